@@ -1,21 +1,72 @@
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use image::GenericImageView;
+use image::{GenericImage, GenericImageView};
 use serde::Deserialize;
+use std::fmt::Write as _;
 use std::fs;
+use std::path::Path;
 
 /// Atari 7800 tool that generates C code for bitmaps described in a YAML file
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// YAML input file
+    /// YAML input file, or image file converted as a whole (quick mode)
     filename: String,
+    /// Graphics mode of the image in quick mode
+    #[arg(long, default_value = "160A")]
+    mode: String,
+    /// Height of the zones (display lists) in quick mode
+    #[arg(long, default_value_t = 16)]
+    dl_height: u8,
+    /// Output basename. Writes the arrays in <OUTPUT>.c and their declarations in <OUTPUT>.h
+    #[arg(short = 'O', long)]
+    output: Option<String>,
+    /// Maps the colors of the image to the Atari 7800 palette in quick mode
+    #[arg(long, value_enum)]
+    quantize: Option<Quantize>,
+    /// Generates the display list list displaying the image full screen in quick mode
+    #[arg(long)]
+    dll: bool,
+    /// Size of the banks (in bytes). Distributes the zones of the bitmaps without bank over consecutive banks
+    #[arg(long, conflicts_with = "compress")]
+    bank_size: Option<usize>,
+    /// First bank used with --bank-size
+    #[arg(long, default_value_t = 0)]
+    first_bank: u8,
+    /// Compresses the graphics data, to be decompressed at runtime in the (uninitialized) zone arrays
+    #[arg(long, value_enum)]
+    compress: Option<Compression>,
+    /// Uses 4-byte display list headers only (the write mode being set once per frame by the caller)
+    #[arg(long)]
+    short_headers: bool,
+    /// Reports the graphics and display list bytes, and the display list entries and DMA cycles of the zones
+    #[arg(long)]
+    stats: bool,
+    /// Writes the bitmaps as displayed by their display lists in <BITMAP>_preview.png (320 pixels wide)
+    #[arg(long)]
+    preview: bool,
+    /// Output format: C code for cc7800, or assembly code for dasm and ca65 (written in <OUTPUT>.asm)
+    #[arg(long, value_enum, default_value_t = Format::C)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum Compression {
+    Rle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Format {
+    C,
+    Asm,
 }
 
 #[derive(Debug, Deserialize)]
 struct AllBitmaps {
     background: Option<(u8, u8, u8)>,
     palettes: Option<Vec<Palette>>,
+    #[serde(default)]
+    reorder_palettes: bool, // Allows regrouping the 160A colors in other palettes than the declared ones
     bitmap_sheets: Vec<BitmapSheet>,
 }
 
@@ -24,24 +75,60 @@ struct BitmapSheet {
     image: String,
     mode: String,
     dl_height: u8,
+    background: Option<(u8, u8, u8)>, // Overrides the global background
     bank: Option<u8>,
     noholeydma: Option<bool>,
+    holeydma: Option<bool>, // Holey DMA data (aligned by cc7800 on 8K or 4K regions)
+    quantize: Option<Quantize>, // Maps the colors of the image to the nearest console colors
     bitmaps: Vec<Bitmap>,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, clap::ValueEnum)]
+enum Quantize {
+    Nearest,
+    Bayer,
+    FloydSteinberg,
+}
+
 #[derive(Debug, Deserialize)]
 struct Palette {
+    name: Option<String>,
     colors: Vec<(u8, u8, u8)>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 struct Bitmap {
     name: String,
+    #[serde(default)]
     top: u32,
+    #[serde(default)]
     left: u32,
+    #[serde(default)]
     width: u32,
+    #[serde(default)]
     height: u32,
     xoffset: Option<u32>,
+    yoffset: Option<u32>, // First line of the bitmap in its first zone (the lines above being empty)
+    background: Option<(u8, u8, u8)>, // Overrides the background of the sheet
+    palettes: Option<Vec<String>>, // Named palettes used by the bitmap (P0, P1... in this order), instead of all
+    #[serde(default)]
+    dll: bool, // Generates a display list list displaying the bitmap full screen
+    dll_top: Option<u32>,          // Blank lines above the bitmap (centered by default)
+    dll_lines: Option<u32>,        // Lines covered by the display list list (243 by default, NTSC)
+    #[serde(default)]
+    double_buffer: bool, // Generates two RAM copies of the display lists, for page flipping
+    #[serde(default)]
+    flicker: bool, // Splits the bitmap in two frames (<name>_a and <name>_b) displayed alternately, mixing their colors
+    clip: Option<Clip>, // Generates the display lists of the bitmap clipped byte by byte, for scroll-ins
+    alias: Option<String>, // Displays the data of a previous bitmap with new display lists (at its own xoffset)
+}
+
+// Sides of the clipped variants of a bitmap
+#[derive(Debug, Deserialize, Clone, Copy)]
+enum Clip {
+    Left,
+    Right,
+    Both,
 }
 
 // Color tables:
@@ -127,14 +214,1246 @@ fn find_color_in_palette(c: &(u8, u8, u8)) -> u8 {
     bestcolor
 }
 
+// Byte range already output, shared by the identical ranges of the following zones (of any bitmap sheet with the
+// same zones height and data placement)
+struct StoredRange {
+    name: String,
+    bytes: Vec<Vec<u8>>, // Lines of the range
+    bank: Option<u8>,
+    holeydma: bool,
+    noholeydma: bool,
+}
+
+// Colors of the pixels of the lines of a zone (None for background)
+type Zone = Vec<Vec<Option<(u8, u8, u8)>>>;
+
+// Colors of each palette, in palette per byte modes (3 colors per palette in 160A, 1 in 320A)
+fn mode_palettes(mode: &str, colors: &[(u8, u8, u8)]) -> Vec<Vec<(u8, u8, u8)>> {
+    let n = if mode == "160A" { 3 } else { 1 };
+    colors.chunks(n).map(|c| c.to_vec()).collect()
+}
+
+// Palettes of the byte columns of a zone, in palette per byte modes (160A and 320A). Each column gets a palette
+// holding all its colors, chosen to change the least from a column to the next (each change of palette starts a new
+// display list entry). The columns whose colors fit in no palette get several palettes, i.e. several layers
+// displayed on top of each other by additional display list entries
+fn assign_palettes(
+    columns: &[Vec<(u8, u8, u8)>],
+    palettes: &[Vec<(u8, u8, u8)>],
+) -> Vec<Vec<usize>> {
+    let fits: Vec<Vec<usize>> = columns
+        .iter()
+        .map(|c| {
+            (0..palettes.len())
+                .filter(|&p| c.iter().all(|color| palettes[p].contains(color)))
+                .collect()
+        })
+        .collect();
+    let mut assigned = vec![Vec::new(); columns.len()];
+    let mut i = 0;
+    while i < columns.len() {
+        if columns[i].is_empty() {
+            i += 1;
+        } else if fits[i].is_empty() {
+            // Split the column, taking each time the palette with most of the remaining colors
+            let mut remaining = columns[i].clone();
+            while let Some(p) = (0..palettes.len())
+                .filter(|&p| remaining.iter().any(|c| palettes[p].contains(c)))
+                .max_by_key(|&p| {
+                    (
+                        remaining.iter().filter(|c| palettes[p].contains(c)).count(),
+                        std::cmp::Reverse(p),
+                    )
+                })
+            {
+                remaining.retain(|c| !palettes[p].contains(c));
+                assigned[i].push(p);
+            }
+            i += 1;
+        } else {
+            // Run of columns that fit in a palette: least number of palette changes up to each column for each
+            // of its possible palettes (with the index of the previous palette)
+            let start = i;
+            while i < columns.len() && !columns[i].is_empty() && !fits[i].is_empty() {
+                i += 1;
+            }
+            let mut costs: Vec<Vec<(usize, usize)>> = vec![vec![(0, 0); fits[start].len()]];
+            for k in start + 1..i {
+                let previous = costs.last().unwrap();
+                let row = fits[k]
+                    .iter()
+                    .map(|p| {
+                        fits[k - 1]
+                            .iter()
+                            .enumerate()
+                            .map(|(j, q)| (previous[j].0 + usize::from(p != q), j))
+                            .min()
+                            .unwrap()
+                    })
+                    .collect();
+                costs.push(row);
+            }
+            let mut j = (0..fits[i - 1].len())
+                .min_by_key(|&j| costs[i - 1 - start][j].0)
+                .unwrap();
+            for k in (start..i).rev() {
+                assigned[k].push(fits[k][j]);
+                j = costs[k - start][j].1;
+            }
+        }
+    }
+    assigned
+}
+
+// Bytes (and palette of each byte column) of a layer of a zone, in palette per byte modes: the pixels drawn with the
+// palette of the layer
+fn layer_bytes(
+    zone: &Zone,
+    assigned: &[Vec<usize>],
+    layer: usize,
+    palettes: &[Vec<(u8, u8, u8)>],
+    pixel_bits: u8,
+) -> (Vec<Vec<u8>>, Vec<u8>) {
+    let pixels_per_byte = (8 / pixel_bits) as usize;
+    let bytes = zone
+        .iter()
+        .map(|line| {
+            line.chunks(pixels_per_byte)
+                .zip(assigned)
+                .map(|(pixels, a)| {
+                    pixels.iter().fold(0u8, |byte, pixel| {
+                        let c = match (pixel, a.get(layer)) {
+                            (Some(color), Some(&p)) => palettes[p]
+                                .iter()
+                                .position(|c| c == color)
+                                .map_or(0, |i| i + 1),
+                            _ => 0,
+                        };
+                        (byte << pixel_bits) | c as u8
+                    })
+                })
+                .collect()
+        })
+        .collect();
+    let layer_palettes = assigned
+        .iter()
+        .map(|a| a.get(layer).map_or(0, |&p| p as u8))
+        .collect();
+    (bytes, layer_palettes)
+}
+
+// Colors of a 160A bitmap regrouped in palettes, so that the colors used together in the same bytes (the most
+// frequent combinations first) share a palette
+fn reorder_palettes(
+    img: &image::DynamicImage,
+    bitmap: &Bitmap,
+    dl_height: u32,
+    background: (u8, u8, u8),
+    colors: &[(u8, u8, u8)],
+) -> Result<Vec<(u8, u8, u8)>> {
+    let mut combinations = std::collections::BTreeMap::<Vec<(u8, u8, u8)>, usize>::new();
+    let mut all_colors = colors.to_vec();
+    let yoffset = bitmap.yoffset.unwrap_or(0);
+    for yy in 0..(yoffset + bitmap.height).div_ceil(dl_height) {
+        for xx in 0..bitmap.width / 8 {
+            let mut column = Vec::new();
+            for line in yy * dl_height..(yy + 1) * dl_height {
+                if line < yoffset || line - yoffset >= bitmap.height {
+                    continue;
+                }
+                for x in 0..8 {
+                    let p = img.get_pixel(bitmap.left + xx * 8 + x, bitmap.top + line - yoffset);
+                    let color = (p[0], p[1], p[2]);
+                    if p[3] != 0 && color != background && !column.contains(&color) {
+                        column.push(color);
+                        if !all_colors.contains(&color) {
+                            all_colors.push(color);
+                        }
+                    }
+                }
+            }
+            if !column.is_empty() {
+                column.sort();
+                *combinations.entry(column).or_default() += 1;
+            }
+        }
+    }
+    let mut palettes = group_palettes(combinations);
+    for color in all_colors {
+        if palettes.iter().any(|p| p.contains(&color)) {
+            continue;
+        }
+        if let Some(p) = palettes.iter_mut().find(|p| p.len() < 3) {
+            p.push(color);
+        } else if palettes.len() < 8 {
+            palettes.push(vec![color]);
+        } else {
+            return Err(anyhow!(
+                "Bitmap {}: Too many colors for the 160A palettes",
+                bitmap.name
+            ));
+        }
+    }
+    // Incomplete palettes are completed with their first color
+    Ok(palettes
+        .iter()
+        .flat_map(|p| (0..3).map(|i| *p.get(i).unwrap_or(&p[0])))
+        .collect())
+}
+
+// 160A palettes (up to 8 of 3 colors) holding the combinations of colors used together, the most frequent ones first
+fn group_palettes(
+    combinations: std::collections::BTreeMap<Vec<(u8, u8, u8)>, usize>,
+) -> Vec<Vec<(u8, u8, u8)>> {
+    let mut combinations: Vec<_> = combinations.into_iter().collect();
+    combinations.sort_by_key(|c| std::cmp::Reverse(c.1));
+    let mut palettes = Vec::<Vec<(u8, u8, u8)>>::new();
+    for (combination, _) in combinations {
+        if combination.len() > 3
+            || palettes
+                .iter()
+                .any(|p| combination.iter().all(|c| p.contains(c)))
+        {
+            continue;
+        }
+        let missing = |p: &Vec<(u8, u8, u8)>| combination.iter().filter(|c| !p.contains(c)).count();
+        if let Some(p) = palettes
+            .iter_mut()
+            .filter(|p| p.len() + missing(p) <= 3)
+            .min_by_key(|p| missing(p))
+        {
+            for c in &combination {
+                if !p.contains(c) {
+                    p.push(*c);
+                }
+            }
+        } else if palettes.len() < 8 {
+            palettes.push(combination);
+        }
+    }
+    palettes
+}
+
+fn color_distance(a: (i32, i32, i32), b: (u8, u8, u8)) -> i32 {
+    (a.0 - b.0 as i32).pow(2) + (a.1 - b.1 as i32).pow(2) + (a.2 - b.2 as i32).pow(2)
+}
+
+fn nearest_color(c: (i32, i32, i32), targets: &[(u8, u8, u8)]) -> (u8, u8, u8) {
+    *targets
+        .iter()
+        .min_by_key(|t| color_distance(c, **t))
+        .unwrap()
+}
+
+// Bitmap area of the image mapped to the console colors. The pixels first go to their nearest console color, the most
+// used ones (after the declared colors) are kept up to the number of colors of the gfx mode, and the area is then
+// mapped again to these colors (or the background), optionally dithered. In palette group modes, the pixels of a
+// byte are mapped to the colors of the group closest to them. In 160A (given the zones height), the kept colors are
+// grouped in palettes of 3 after the colors used together in the byte columns of the zones, and each byte column of a
+// zone is mapped to a single palette. Returns the color table
+#[allow(clippy::too_many_arguments)]
+fn quantize(
+    img: &mut image::DynamicImage,
+    bitmap: &Bitmap,
+    pixel_width: u32,
+    byte_pixels: usize,
+    group_size: usize,
+    zone_palettes: Option<u32>,
+    background: (u8, u8, u8),
+    method: Quantize,
+    declared: &[(u8, u8, u8)],
+    maxmaxcolors: usize,
+) -> Vec<(u8, u8, u8)> {
+    const BAYER: [[i32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    let console: Vec<(u8, u8, u8)> = PALETTE.chunks(3).map(|c| (c[0], c[1], c[2])).collect();
+    let width = (bitmap.width / pixel_width) as usize;
+    let height = bitmap.height as usize;
+    let mut pixels: Vec<Vec<Option<(i32, i32, i32)>>> = (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| {
+                    let p =
+                        img.get_pixel(bitmap.left + x as u32 * pixel_width, bitmap.top + y as u32);
+                    Some((p[0] as i32, p[1] as i32, p[2] as i32))
+                        .filter(|_| p[3] != 0 && (p[0], p[1], p[2]) != background)
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut counts = std::collections::HashMap::<(u8, u8, u8), usize>::new();
+    for c in pixels.iter().flatten().flatten() {
+        *counts.entry(nearest_color(*c, &console)).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts
+        .into_iter()
+        .filter(|(c, _)| !declared.contains(c))
+        .collect();
+    counts.sort_by_key(|(c, n)| (std::cmp::Reverse(*n), *c));
+    let mut colors = declared.to_vec();
+    colors.extend(
+        counts
+            .iter()
+            .map(|(c, _)| *c)
+            .take(maxmaxcolors.saturating_sub(declared.len())),
+    );
+    // Byte columns of the zones (first line and first pixel of each)
+    let yoffset = bitmap.yoffset.unwrap_or(0) as usize;
+    let columns: Vec<(usize, usize)> = zone_palettes.map_or(Vec::new(), |dl_height| {
+        let dl_height = dl_height as usize;
+        (0..(yoffset + height).div_ceil(dl_height))
+            .flat_map(|yy| (0..width.div_ceil(byte_pixels)).map(move |xx| (yy, xx)))
+            .collect()
+    });
+    let column_pixels = |&(yy, xx): &(usize, usize)| {
+        let dl_height = zone_palettes.unwrap_or(1) as usize;
+        (yy * dl_height..(yy + 1) * dl_height)
+            .filter(|line| *line >= yoffset && line - yoffset < height)
+            .flat_map(|line| {
+                (xx * byte_pixels..((xx + 1) * byte_pixels).min(width))
+                    .filter_map(|x| pixels[line - yoffset][x])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    if zone_palettes.is_some() {
+        // The 3 most used colors of each byte column of a zone are used together
+        let mut combinations = std::collections::BTreeMap::<Vec<(u8, u8, u8)>, usize>::new();
+        for column in &columns {
+            let mut counts = std::collections::HashMap::<(u8, u8, u8), usize>::new();
+            for p in column_pixels(column) {
+                *counts.entry(nearest_color(p, &colors)).or_default() += 1;
+            }
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by_key(|(c, n)| (std::cmp::Reverse(*n), *c));
+            let mut combination: Vec<_> = counts.iter().take(3).map(|(c, _)| *c).collect();
+            if !combination.is_empty() {
+                combination.sort();
+                *combinations.entry(combination).or_default() += 1;
+            }
+        }
+        let mut palettes = group_palettes(combinations);
+        // The remaining colors fill the free entries, the others are dropped
+        for color in &colors {
+            if palettes.iter().any(|p| p.contains(color)) {
+                continue;
+            }
+            if let Some(p) = palettes.iter_mut().find(|p| p.len() < 3) {
+                p.push(*color);
+            } else if palettes.len() < 8 {
+                palettes.push(vec![*color]);
+            }
+        }
+        // Incomplete palettes are completed with their first color
+        colors = palettes
+            .iter()
+            .flat_map(|p| (0..3).map(|i| *p.get(i).unwrap_or(&p[0])))
+            .collect();
+    }
+    let group_size = if zone_palettes.is_some() {
+        3
+    } else if group_size == 0 {
+        colors.len().max(1)
+    } else {
+        group_size
+    };
+    let groups: Vec<Vec<(u8, u8, u8)>> = colors
+        .chunks(group_size)
+        .map(|g| [g, &[background]].concat())
+        .collect();
+    let groups = if groups.is_empty() {
+        vec![vec![background]]
+    } else {
+        groups
+    };
+    // Palette of each byte column of the zones, the closest to its pixels
+    let column_groups: Vec<&Vec<(u8, u8, u8)>> = columns
+        .iter()
+        .map(|column| {
+            let pixels = column_pixels(column);
+            groups
+                .iter()
+                .min_by_key(|g| {
+                    pixels
+                        .iter()
+                        .map(|p| color_distance(*p, nearest_color(*p, g)))
+                        .sum::<i32>()
+                })
+                .unwrap()
+        })
+        .collect();
+
+    for y in 0..height {
+        let mut targets = &groups[0];
+        for x in 0..width {
+            if let Some(dl_height) = zone_palettes {
+                let zone = (y + yoffset) / dl_height as usize;
+                targets = column_groups[zone * width.div_ceil(byte_pixels) + x / byte_pixels];
+            } else if x % byte_pixels == 0 {
+                // Closest group for the pixels of the byte
+                targets = groups
+                    .iter()
+                    .min_by_key(|g| {
+                        (x..(x + byte_pixels).min(width))
+                            .filter_map(|i| pixels[y][i])
+                            .map(|p| color_distance(p, nearest_color(p, g)))
+                            .sum::<i32>()
+                    })
+                    .unwrap();
+            }
+            let Some(c) = pixels[y][x] else {
+                continue;
+            };
+            let wanted = match method {
+                Quantize::Bayer => {
+                    let offset = (BAYER[y % 4][x % 4] * 2 - 15) * 2;
+                    (c.0 + offset, c.1 + offset, c.2 + offset)
+                }
+                _ => c,
+            };
+            let t = nearest_color(wanted, targets);
+            if let Quantize::FloydSteinberg = method {
+                let error = (c.0 - t.0 as i32, c.1 - t.1 as i32, c.2 - t.2 as i32);
+                for (dx, dy, w) in [(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)] {
+                    let (nx, ny) = (x as i32 + dx, y + dy);
+                    if nx < 0 || nx as usize >= width || ny >= height {
+                        continue;
+                    }
+                    if let Some(n) = &mut pixels[ny][nx as usize] {
+                        n.0 += error.0 * w / 16;
+                        n.1 += error.1 * w / 16;
+                        n.2 += error.2 * w / 16;
+                    }
+                }
+            }
+            for i in 0..pixel_width {
+                img.put_pixel(
+                    bitmap.left + x as u32 * pixel_width + i,
+                    bitmap.top + y as u32,
+                    image::Rgba([t.0, t.1, t.2, 255]),
+                );
+            }
+        }
+    }
+    colors
+}
+
+// Flicker frames of a bitmap area, as two copies of the image. Each color is displayed alternately as two console
+// colors whose mix is the closest to it (a color of the console being displayed as is), chosen among the most used
+// ones to fit in the color table of each frame. The two colors are swapped on every other pixel, in a checkerboard,
+// to even the flicker. Reports the mixed colors
+fn flicker_frames(
+    img: &image::DynamicImage,
+    bitmap: &Bitmap,
+    pixel_width: u32,
+    background: (u8, u8, u8),
+    maxmaxcolors: usize,
+) -> (image::DynamicImage, image::DynamicImage) {
+    let mut console: Vec<(u8, u8, u8)> = PALETTE.chunks(3).map(|c| (c[0], c[1], c[2])).collect();
+    console.sort();
+    console.dedup();
+    let mut counts = std::collections::HashMap::<(u8, u8, u8), usize>::new();
+    for y in bitmap.top..bitmap.top + bitmap.height {
+        for x in (bitmap.left..bitmap.left + bitmap.width).step_by(pixel_width as usize) {
+            let p = img.get_pixel(x, y);
+            if p[3] != 0 && (p[0], p[1], p[2]) != background {
+                *counts.entry((p[0], p[1], p[2])).or_default() += 1;
+            }
+        }
+    }
+    // Closest mix of two of the colors, favoring the colors close to each other (that flicker less)
+    let best_pair = |c: (u8, u8, u8), colors: &[(u8, u8, u8)]| {
+        let mut best = (background, background);
+        let mut best_distance = i32::MAX;
+        for (i, a) in colors.iter().enumerate() {
+            for b in &colors[i..] {
+                let mix = (
+                    (a.0 as i32 + b.0 as i32) / 2,
+                    (a.1 as i32 + b.1 as i32) / 2,
+                    (a.2 as i32 + b.2 as i32) / 2,
+                );
+                let distance = color_distance(mix, c)
+                    + color_distance((a.0 as i32, a.1 as i32, a.2 as i32), *b) / 16;
+                if distance < best_distance {
+                    best_distance = distance;
+                    best = (*a, *b);
+                }
+            }
+        }
+        best
+    };
+    let mut used = std::collections::HashMap::<(u8, u8, u8), usize>::new();
+    for (c, n) in &counts {
+        let (a, b) = best_pair(*c, &console);
+        *used.entry(a).or_default() += n;
+        *used.entry(b).or_default() += n;
+    }
+    let mut used: Vec<_> = used.into_iter().collect();
+    used.sort_by_key(|(c, n)| (std::cmp::Reverse(*n), *c));
+    // The background is displayed without using the color table
+    let allowed: Vec<(u8, u8, u8)> = used
+        .iter()
+        .map(|(c, _)| *c)
+        .filter(|c| *c != background)
+        .take(maxmaxcolors)
+        .chain([background])
+        .collect();
+    let pairs: std::collections::HashMap<_, _> = counts
+        .keys()
+        .map(|c| (*c, best_pair(*c, &allowed)))
+        .collect();
+
+    let mut mixes = std::collections::HashMap::<((u8, u8, u8), (u8, u8, u8)), usize>::new();
+    for (c, n) in &counts {
+        *mixes.entry(pairs[c]).or_default() += n;
+    }
+    let mut mixes: Vec<_> = mixes.into_iter().collect();
+    mixes.sort_by_key(|(pair, n)| (std::cmp::Reverse(*n), *pair));
+    for ((a, b), n) in mixes {
+        println!(
+            "// Bitmap {}: flicker color ({}, {}, {}) mixing 0x{:02x} and 0x{:02x} ({} pixels)",
+            bitmap.name,
+            (a.0 as u32 + b.0 as u32) / 2,
+            (a.1 as u32 + b.1 as u32) / 2,
+            (a.2 as u32 + b.2 as u32) / 2,
+            find_color_in_palette(&a),
+            find_color_in_palette(&b),
+            n
+        );
+    }
+
+    let mut frames = (img.clone(), img.clone());
+    for y in 0..bitmap.height {
+        for x in 0..bitmap.width / pixel_width {
+            let p = img.get_pixel(bitmap.left + x * pixel_width, bitmap.top + y);
+            let Some(&(a, b)) = pairs.get(&(p[0], p[1], p[2])).filter(|_| p[3] != 0) else {
+                continue;
+            };
+            let (a, b) = if (x + y) % 2 == 0 { (a, b) } else { (b, a) };
+            for i in 0..pixel_width {
+                let xp = bitmap.left + x * pixel_width + i;
+                frames
+                    .0
+                    .put_pixel(xp, bitmap.top + y, image::Rgba([a.0, a.1, a.2, 255]));
+                frames
+                    .1
+                    .put_pixel(xp, bitmap.top + y, image::Rgba([b.0, b.1, b.2, 255]));
+            }
+        }
+    }
+    frames
+}
+
+// Estimated MARIA DMA cycles of a line of a zone, from the header size and width of its display list entries: DMA
+// startup, 8 (4-byte header) or 10 (5-byte header) cycles per entry and 3 per graphics byte, and end of display list
+fn dma_cycles(entries: &[(usize, usize)]) -> usize {
+    7 + entries
+        .iter()
+        .map(|(header, width)| header * 2 + width * 3)
+        .sum::<usize>()
+        + 4
+}
+
+// 320A zone split at the palette (i.e. color) transitions inside bytes: when the pixels of a byte go from a color to
+// another one at an even position (display list entries being positioned by pairs of pixels), the entry of the
+// first color ends with the byte and the run of the second color starts a new entry at the transition. Returns the
+// zone of each shift of the bytes (in pixels, starting with the unshifted zone), and the number of transitions
+fn split_transitions(zone: &Zone) -> (Vec<(u32, Zone)>, usize) {
+    let width = zone[0].len();
+    let mut shifts = [0, 2, 4, 6].map(|_| vec![vec![None; width]; zone.len()]);
+    let mut transitions = 0;
+    // Color of the current run, and its shift
+    let mut run: Option<((u8, u8, u8), usize)> = None;
+    for i in 0..width / 8 {
+        let mut colors: Vec<(u8, u8, u8)> = zone
+            .iter()
+            .flat_map(|line| line[i * 8..i * 8 + 8].iter().flatten().copied())
+            .collect();
+        colors.sort();
+        colors.dedup();
+        let mut assign = |from: usize, to: usize, shift: usize| {
+            for (line, shifted) in zone.iter().zip(shifts[shift / 2].iter_mut()) {
+                shifted[i * 8 + from..i * 8 + to].copy_from_slice(&line[i * 8 + from..i * 8 + to]);
+            }
+        };
+        if let Some((color, shift)) = run.filter(|r| colors == [r.0]) {
+            assign(0, 8, shift);
+            run = Some((color, shift));
+            continue;
+        }
+        // First color on the left of the transition, the one of the current run if possible
+        let split = if colors.len() == 2 {
+            let orders = if run.is_some_and(|r| r.0 == colors[1]) {
+                [(colors[1], colors[0]), (colors[0], colors[1])]
+            } else {
+                [(colors[0], colors[1]), (colors[1], colors[0])]
+            };
+            orders.into_iter().find_map(|(left, right)| {
+                [2, 4, 6]
+                    .into_iter()
+                    .find(|&k| {
+                        zone.iter().all(|line| {
+                            line[i * 8..i * 8 + 8].iter().enumerate().all(|(x, p)| {
+                                p.is_none_or(|p| p == if x < k { left } else { right })
+                            })
+                        })
+                    })
+                    .map(|k| (left, right, k))
+            })
+        } else {
+            None
+        };
+        if let Some((left, right, k)) = split {
+            let shift = run.filter(|r| r.0 == left).map_or(0, |r| r.1);
+            assign(0, k, shift);
+            assign(k, 8, k);
+            run = Some((right, k));
+            transitions += 1;
+        } else {
+            assign(0, 8, 0);
+            run = if colors.len() == 1 {
+                Some((colors[0], 0))
+            } else {
+                None
+            };
+        }
+    }
+    // The bytes of a shifted zone start at its shift
+    let zones = shifts
+        .into_iter()
+        .enumerate()
+        .filter(|(s, z)| *s == 0 || z.iter().flatten().any(|p| p.is_some()))
+        .map(|(s, z)| {
+            let shift = s * 2;
+            let z = z
+                .into_iter()
+                .map(|line| {
+                    line[shift..]
+                        .iter()
+                        .copied()
+                        .chain(std::iter::repeat_n(None, shift))
+                        .collect()
+                })
+                .collect();
+            (shift as u32, z)
+        })
+        .collect();
+    (zones, transitions)
+}
+
+// Display list entry of a byte range, with the 5-byte header (setting the write mode) or the 4-byte one. In assembly,
+// the width and palette byte is computed, and the high byte of the address of a range is the one of its block (a
+// range never crossing a page)
+fn dl_entry(
+    name: &str,
+    width: usize,
+    palette: u8,
+    x: u32,
+    mode_byte: Option<u8>,
+    asm: bool,
+) -> String {
+    let base = name.split(" + ").next().unwrap_or(name);
+    let width_palette = (32 - width) as u8 & 0x1f | palette << 5;
+    match (mode_byte, asm) {
+        (Some(mode), true) => {
+            format!("\t.byte <{name}, ${mode:02x}, >{base}, ${width_palette:02x}, {x}\n")
+        }
+        (None, true) => format!("\t.byte <{name}, ${width_palette:02x}, >{base}, {x}\n"),
+        (Some(mode), false) => format!(
+            "{name} & 0xff, 0x{mode:02x}, {name} >> 8, (-{width} & 0x1f) | ({palette} << 5), {x}, "
+        ),
+        (None, false) => {
+            format!("{name} & 0xff, (-{width} & 0x1f) | ({palette} << 5), {name} >> 8, {x}, ")
+        }
+    }
+}
+
+// Tables of the high and low bytes of the addresses of the display lists of the zones of a bitmap
+fn data_ptrs(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    nb_dls: u32,
+    bank: bool,
+    asm: bool,
+) -> std::fmt::Result {
+    let dls: Vec<String> = (0..nb_dls).map(|y| format!("{name}_{y}_dl")).collect();
+    if asm {
+        for (table, op) in [("high", ">"), ("low", "<")] {
+            let bytes: Vec<String> = dls.iter().map(|dl| format!("{op}{dl}")).collect();
+            write!(out, "{name}_data_ptrs_{table}:\n{}", asm_bytes(&bytes))?;
+        }
+        return writeln!(
+            out,
+            "{name}_data_ptrs:\n\t.word {name}_data_ptrs_high, {name}_data_ptrs_low\n"
+        );
+    }
+    for (table, op) in [("high", ">> 8"), ("low", "& 0xff")] {
+        if bank {
+            write!(out, "BITMAP_TABLE_BANK ")?;
+        }
+        let bytes: Vec<String> = dls.iter().map(|dl| format!("{dl} {op}")).collect();
+        writeln!(
+            out,
+            "const char {name}_data_ptrs_{table}[{nb_dls}] = {{{}}};",
+            bytes.join(", ")
+        )?;
+    }
+    if bank {
+        write!(out, "BITMAP_TABLE_BANK ")?;
+    }
+    writeln!(
+        out,
+        "const char *{name}_data_ptrs[2] = {{{name}_data_ptrs_high, {name}_data_ptrs_low}};\n"
+    )?;
+    for table in ["high", "low"] {
+        writeln!(
+            header,
+            "extern const char {name}_data_ptrs_{table}[{nb_dls}];"
+        )?;
+    }
+    writeln!(header, "extern const char *{name}_data_ptrs[2];")
+}
+
+// Defines of a bitmap (height and number of zones) and tables of the addresses of its display lists
+fn bitmap_defines(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    height: u32,
+    nb_dls: u32,
+    bank: bool,
+    asm: bool,
+) -> std::fmt::Result {
+    let uppercase = name.to_uppercase();
+    writeln!(header, "#define {uppercase}_HEIGHT {height}")?;
+    writeln!(header, "#define {uppercase}_DL_COUNT {nb_dls}")?;
+    data_ptrs(out, header, name, nb_dls, bank, asm)?;
+    if asm {
+        writeln!(out, "{uppercase}_HEIGHT = {height}")?;
+        writeln!(out, "{uppercase}_DL_COUNT = {nb_dls}")?;
+    }
+    Ok(())
+}
+
+// Display list of a zone, out of the entries of a generated one: only the bytes of the bitmap in the kept range,
+// moved by dx
+fn moved_dl(
+    entries: &[Entry],
+    keep: std::ops::Range<usize>,
+    dx: i32,
+    hpos_width: usize,
+    mode_byte: Option<u8>,
+    asm: bool,
+) -> Result<(String, usize)> {
+    let mut dl = String::new();
+    let mut size = 2;
+    for (name, first, last, palette, x) in entries {
+        let start = (*first).max(keep.start);
+        let end = (*last).min(keep.end);
+        if start >= end {
+            continue;
+        }
+        let offset = start - first;
+        let name = match name.split_once(" + ") {
+            Some((name, o)) => format!("{name} + {}", o.parse::<usize>()? + offset),
+            None if offset != 0 => format!("{name} + {offset}"),
+            None => name.clone(),
+        };
+        // Horizontal positions wrap around, off-screen on the left
+        let x = (*x as i32 + (offset * hpos_width) as i32 + dx).rem_euclid(256) as u32;
+        let mode_byte = mode_byte.filter(|_| size == 2);
+        dl.push_str(&dl_entry(&name, end - start, *palette, x, mode_byte, asm));
+        size += if mode_byte.is_some() { 5 } else { 4 };
+    }
+    Ok((dl, size))
+}
+
+// Display list of a zone (out of the banks filled with --bank-size)
+fn dl_array(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    dl: &str,
+    size: usize,
+    bank: bool,
+    asm: bool,
+) -> std::fmt::Result {
+    if asm {
+        return writeln!(out, "{name}:\n{dl}\t.byte 0, 0");
+    }
+    if bank {
+        write!(out, "BITMAP_TABLE_BANK ")?;
+    }
+    writeln!(out, "const unsigned char {name}[{size}] = {{{dl}0, 0}};")?;
+    writeln!(header, "extern const unsigned char {name}[{size}];")
+}
+
+// Display list of a zone being the one of a previous zone
+fn same_dl(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    same: &str,
+    asm: bool,
+) -> std::fmt::Result {
+    if asm {
+        return writeln!(out, "{name} = {same}");
+    }
+    for target in [out, header] {
+        writeln!(target, "#define {name} {same}")?;
+    }
+    Ok(())
+}
+
+// Display list list: blank zones above and below the zones of a bitmap (of up to 16 lines), for the given display
+// lists of the zones
+#[allow(clippy::too_many_arguments)]
+fn dll(
+    out: &mut String,
+    header: &mut String,
+    bitmap: &Bitmap,
+    dl_height: u8,
+    yoffset: u32,
+    holeydma: bool,
+    dlls: &[(String, Vec<String>)],
+    bank: bool,
+    asm: bool,
+) -> Result<()> {
+    let bitmapname = &bitmap.name;
+    let nb_dls = dlls[0].1.len() as u32;
+    let lines = bitmap.dll_lines.unwrap_or(243);
+    // The first zone starts at the first line of the bitmap
+    let height = nb_dls * dl_height as u32 - yoffset;
+    if height > lines {
+        return Err(anyhow!(
+            "Bitmap {}: Too high for a display list list of {} lines",
+            bitmap.name,
+            lines
+        ));
+    }
+    let top = bitmap.dll_top.unwrap_or((lines - height) / 2);
+    if top + height > lines {
+        return Err(anyhow!(
+            "Bitmap {}: {} blank lines above the bitmap leave no room for it in {} lines",
+            bitmap.name,
+            top,
+            lines
+        ));
+    }
+    let blank = |mut n: u32| {
+        let mut entries = Vec::new();
+        while n != 0 {
+            let h = n.min(16);
+            entries.push(if asm {
+                format!(
+                    "${:02x}, >{bitmapname}_blank_dl, <{bitmapname}_blank_dl",
+                    h - 1
+                )
+            } else {
+                format!(
+                    "0x{:02x}, {bitmapname}_blank_dl >> 8, {bitmapname}_blank_dl & 0xff",
+                    h - 1
+                )
+            });
+            n -= h;
+        }
+        entries
+    };
+    // Holey DMA bits (H16 and H8) and offset of the bitmap zones
+    let holey = match (holeydma, dl_height) {
+        (true, 16) => 0x40,
+        (true, 8) => 0x20,
+        _ => 0,
+    };
+    if asm {
+        writeln!(out, "{bitmapname}_blank_dl:\n\t.byte 0, 0")?;
+    } else {
+        if bank {
+            write!(out, "BITMAP_TABLE_BANK ")?;
+        }
+        writeln!(
+            out,
+            "const unsigned char {bitmapname}_blank_dl[2] = {{0, 0}};"
+        )?;
+    }
+    writeln!(
+        header,
+        "extern const unsigned char {bitmapname}_blank_dl[2];"
+    )?;
+    for (i, (dll, dls)) in dlls.iter().enumerate() {
+        let mut entries = blank(top);
+        entries.extend(dls.iter().enumerate().map(|(y, dl)| {
+            let flags = holey | (dl_height as u32 - 1 - if y == 0 { yoffset } else { 0 });
+            if asm {
+                format!("${flags:02x}, >{dl}, <{dl}")
+            } else {
+                format!("0x{flags:02x}, {dl} >> 8, {dl} & 0xff")
+            }
+        }));
+        entries.extend(blank(lines - top - height));
+        if asm {
+            let lines: Vec<String> = entries.iter().map(|e| format!("\t.byte {e}\n")).collect();
+            writeln!(out, "{dll}:\n{}", lines.concat())?;
+            if i == 0 {
+                writeln!(
+                    out,
+                    "{}_DLL_ZONES = {}",
+                    bitmapname.to_uppercase(),
+                    entries.len()
+                )?;
+            }
+            continue;
+        }
+        if bank {
+            write!(out, "BITMAP_TABLE_BANK ")?;
+        }
+        writeln!(
+            out,
+            "const unsigned char {dll}[{}] = {{\n\t{}}};\n",
+            entries.len() * 3,
+            entries.join(",\n\t")
+        )?;
+        if i == 0 {
+            writeln!(
+                header,
+                "#define {}_DLL_ZONES {}",
+                bitmapname.to_uppercase(),
+                entries.len()
+            )?;
+        }
+        writeln!(
+            header,
+            "extern const unsigned char {dll}[{}];",
+            entries.len() * 3
+        )?;
+    }
+    Ok(())
+}
+
+// Table of pointers
+fn pointers(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    values: &[String],
+    bank: bool,
+    asm: bool,
+) -> std::fmt::Result {
+    if asm {
+        writeln!(out, "{name}:")?;
+        for chunk in values.chunks(8) {
+            writeln!(out, "\t.word {}", chunk.join(", "))?;
+        }
+        return Ok(());
+    }
+    if bank {
+        write!(out, "BITMAP_TABLE_BANK ")?;
+    }
+    writeln!(
+        out,
+        "const char *{name}[{}] = {{{}}};",
+        values.len(),
+        values.join(", ")
+    )?;
+    writeln!(header, "extern const char *{name}[{}];", values.len())
+}
+
+// Display list entry of a zone: horizontal position, palette and lines of its bytes
+type Range = (u32, u8, Vec<Vec<u8>>);
+
+// Display list entry of a zone: name, first and last bytes of the bitmap, palette and horizontal position
+type Entry = (String, usize, usize, u8, u32);
+
+// Display lists of a generated bitmap, for its aliases
+#[derive(Clone)]
+struct BitmapDls {
+    entries: Vec<Vec<Entry>>, // Display list entries of the zones
+    same: Vec<Option<u32>>,   // Zones reusing the display list of a previous one
+    xoffset: u32,
+    yoffset: u32,
+    height: u32,
+    dl_height: u8,
+    mode_byte: u8,
+    holeydma: bool,
+}
+
+// Color table indexes (None for transparent) of the 320 mode pixels of a graphics byte, as displayed by MARIA
+fn decode_byte(mode: &str, byte: u8, palette: u8) -> Vec<Option<usize>> {
+    let bit = |n: u8| ((byte >> n) & 1) as usize;
+    let group = (palette / 4) as usize;
+    match mode {
+        "160A" => (0..4)
+            .flat_map(|k| {
+                let c = ((byte >> (6 - 2 * k)) & 3) as usize;
+                let pixel = (c != 0).then(|| palette as usize * 3 + c - 1);
+                [pixel, pixel]
+            })
+            .collect(),
+        "320A" => (0..8)
+            .map(|k| (bit(7 - k) != 0).then_some(palette as usize))
+            .collect(),
+        "160B" => [
+            bit(6) | bit(7) << 1 | bit(2) << 2 | bit(3) << 3,
+            bit(4) | bit(5) << 1 | bit(0) << 2 | bit(1) << 3,
+        ]
+        .into_iter()
+        .flat_map(|c| {
+            // Colors 4, 8 and 12 are transparent
+            let pixel = (c & 3 != 0).then(|| group * 12 + c - c / 4 - 1);
+            [pixel, pixel]
+        })
+        .collect(),
+        "320B" => (0..4)
+            .map(|k| {
+                let c = bit(3 - k) | bit(7 - k) << 1;
+                (c != 0).then(|| group * 3 + c - 1)
+            })
+            .collect(),
+        "320C" => (0..4)
+            .map(|k| {
+                let c = if k < 2 { (byte >> 2) & 3 } else { byte & 3 } as usize;
+                (bit(7 - k) != 0).then_some(group * 4 + c)
+            })
+            .collect(),
+        _ => unreachable!(),
+    }
+}
+
+// Image of the zones of a bitmap, rebuilt from the graphics bytes and display list entries with the console colors
+fn preview(
+    mode: &str,
+    zones: &[Vec<Range>],
+    dl_height: usize,
+    colors: &[(u8, u8, u8)],
+    background: (u8, u8, u8),
+) -> image::RgbImage {
+    let rgb = |c: &(u8, u8, u8)| {
+        let i = find_color_in_palette(c) as usize * 3;
+        image::Rgb([PALETTE[i], PALETTE[i + 1], PALETTE[i + 2]])
+    };
+    let mut img =
+        image::RgbImage::from_pixel(320, (zones.len() * dl_height) as u32, rgb(&background));
+    for (yy, ranges) in zones.iter().enumerate() {
+        for (x, palette, lines) in ranges {
+            for (y, bytes) in lines.iter().enumerate() {
+                let pixels = bytes.iter().flat_map(|b| decode_byte(mode, *b, *palette));
+                for (i, pixel) in pixels.enumerate() {
+                    let xp = x * 2 + i as u32;
+                    if let (Some(c), true) = (pixel, xp < 320) {
+                        img.put_pixel(xp, (yy * dl_height + y) as u32, rgb(&colors[c]));
+                    }
+                }
+            }
+        }
+    }
+    img
+}
+
+// Assembly data lines, 16 bytes per line
+fn asm_bytes(values: &[String]) -> String {
+    values
+        .chunks(16)
+        .map(|c| format!("\t.byte {}\n", c.join(", ")))
+        .collect()
+}
+
+// Graphics data of byte ranges in assembly, the ranges being laid side by side in a block of one page per line of the
+// zones, from the bottom line up (as MARIA reads them, from the address of the range plus the line offset times 256).
+// The block is aligned on an 8K or 4K region with holey DMA (the zone pages and the hole following them)
+fn asm_block(block: &[(String, Vec<Vec<u8>>)], dl_height: usize, holeydma: bool) -> String {
+    let mut out = String::new();
+    let align = match (holeydma, dl_height) {
+        (true, 16) => 8192,
+        (true, _) => 4096,
+        _ => 256,
+    };
+    for page in 0..dl_height {
+        out.push_str(&format!(
+            "\t.align {}\n",
+            if page == 0 { align } else { 256 }
+        ));
+        for (name, lines) in block {
+            if page == 0 {
+                out.push_str(&format!("{name}:\n"));
+            }
+            let bytes: Vec<String> = lines[dl_height - 1 - page]
+                .iter()
+                .map(|b| format!("${:02x}", b))
+                .collect();
+            out.push_str(&asm_bytes(&bytes));
+        }
+    }
+    out
+}
+
+// RLE compression: a byte n < 128 is followed by n + 1 literal bytes, a byte n >= 128 by a byte repeated n - 126
+// times
+fn rle(data: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::new();
+    let mut literals = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(129)
+            .take_while(|&&b| b == data[i])
+            .count();
+        if (run >= 3 || literals.len() == 128) && !literals.is_empty() {
+            packed.push((literals.len() - 1) as u8);
+            packed.append(&mut literals);
+        }
+        if run >= 3 {
+            packed.push((run + 126) as u8);
+            packed.push(data[i]);
+            i += run;
+        } else {
+            literals.push(data[i]);
+            i += 1;
+        }
+    }
+    if !literals.is_empty() {
+        packed.push((literals.len() - 1) as u8);
+        packed.append(&mut literals);
+    }
+    packed
+}
+
+// A whole image converted as one bitmap (quick mode, without YAML file), with its most used color as background and
+// automatically allocated palettes
+fn quick_bitmaps(
+    filename: &str,
+    mode: &str,
+    dl_height: u8,
+    quantize: Option<Quantize>,
+    dll: bool,
+) -> Result<AllBitmaps> {
+    let img = image::open(filename).map_err(|e| anyhow!("Can't open image {}: {}", filename, e))?;
+    let mut counts = std::collections::HashMap::<(u8, u8, u8), usize>::new();
+    for (_, _, p) in img.pixels() {
+        if p[3] != 0 {
+            *counts.entry((p[0], p[1], p[2])).or_default() += 1;
+        }
+    }
+    let background = counts
+        .into_iter()
+        .max_by_key(|(c, n)| (*n, *c))
+        .map(|(c, _)| c);
+    let pixels_per_byte = match mode {
+        "160A" | "320A" | "320D" => 8,
+        _ => 4,
+    };
+    // The last zone is completed with empty lines, but the width must be a whole number of bytes
+    let width = img.width() - img.width() % pixels_per_byte;
+    let height = img.height();
+    if width != img.width() {
+        println!(
+            "// Warning: {}x{} image cropped to {}x{} (multiple of {} pixels per byte)",
+            img.width(),
+            img.height(),
+            width,
+            height,
+            pixels_per_byte
+        );
+    }
+    let mut name: String = Path::new(filename)
+        .file_stem()
+        .map_or("bitmap".into(), |s| s.to_string_lossy())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    Ok(AllBitmaps {
+        background,
+        palettes: None,
+        reorder_palettes: true,
+        bitmap_sheets: vec![BitmapSheet {
+            image: filename.to_string(),
+            mode: mode.to_string(),
+            dl_height,
+            background: None,
+            bank: None,
+            noholeydma: None,
+            holeydma: None,
+            quantize,
+            bitmaps: vec![Bitmap {
+                name,
+                top: 0,
+                left: 0,
+                width,
+                height,
+                xoffset: None,
+                yoffset: None,
+                background: None,
+                palettes: None,
+                dll,
+                dll_top: None,
+                dll_lines: None,
+                double_buffer: false,
+                flicker: false,
+                clip: None,
+                alias: None,
+            }],
+        }],
+    })
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let contents = fs::read_to_string(args.filename).expect("Unable to read input file");
-    let all_bitmaps: AllBitmaps = serde_yaml::from_str(&contents)?;
+    let yaml = Path::new(&args.filename)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("yaml") || e.eq_ignore_ascii_case("yml"));
+    let all_bitmaps: AllBitmaps = if yaml {
+        let contents = fs::read_to_string(&args.filename).expect("Unable to read input file");
+        serde_yaml::from_str(&contents)?
+    } else {
+        quick_bitmaps(
+            &args.filename,
+            &args.mode,
+            args.dl_height,
+            args.quantize,
+            args.dll,
+        )?
+    };
+
+    // The assembly code goes in a file, the standard output being used by the report lines. Banks and runtime
+    // decompression being handled by cc7800, they are only supported in C
+    let asm = args.format == Format::Asm;
+    if asm && args.output.is_none() {
+        return Err(anyhow!("The asm format needs an output basename (-O)"));
+    }
+    if asm && (args.bank_size.is_some() || args.compress.is_some()) {
+        return Err(anyhow!(
+            "The bank-size and compress options aren't supported with the asm format"
+        ));
+    }
 
-    let mut store = Vec::<(String, Vec<Vec<u8>>)>::new();
+    let mut store = Vec::<StoredRange>::new();
+    // Bank being filled (and bytes used in it) with --bank-size
+    let mut current_bank = args.first_bank;
+    let mut bank_used = 0;
+    let mut out = String::new();
+    let mut header = String::new();
+    // Display lists of the bitmaps generated so far, by name, for the aliases
+    let mut generated = std::collections::HashMap::<String, BitmapDls>::new();
 
     for bitmap_sheet in all_bitmaps.bitmap_sheets {
+        if bitmap_sheet.dl_height != 8 && bitmap_sheet.dl_height != 16 {
+            return Err(anyhow!(
+                "Bitmap sheet {}: dl_height {} should be 8 or 16",
+                bitmap_sheet.image,
+                bitmap_sheet.dl_height
+            ));
+        }
+        let holeydma = bitmap_sheet.holeydma == Some(true);
+        if holeydma && bitmap_sheet.noholeydma == Some(true) {
+            return Err(anyhow!(
+                "Bitmap sheet {}: holeydma and noholeydma can't be both set",
+                bitmap_sheet.image
+            ));
+        }
         let byte_width = match bitmap_sheet.mode.as_str() {
             "160A" | "320A" | "320D" => 8,
             _ => 4,
@@ -155,57 +1474,370 @@ fn main() -> Result<()> {
             "160B" => 4,
             _ => 2,
         };
+        // Colors of a palette group (P0-P3 or P4-P7 for 160B and 320C, P0 or P4 for 320B)
+        let group_size = match bitmap_sheet.mode.as_str() {
+            "160B" => 12,
+            "320B" => 3,
+            "320C" => 4,
+            _ => 0,
+        };
+
+        let mut img = image::open(&bitmap_sheet.image)
+            .unwrap_or_else(|_| panic!("Can't open image {}", bitmap_sheet.image));
 
-        let img = image::open(&bitmap_sheet.image)
-            .expect(&format!("Can't open image {}", bitmap_sheet.image));
+        if asm && bitmap_sheet.bank.is_some() {
+            return Err(anyhow!(
+                "Bitmap sheet {}: Banks aren't supported with the asm format",
+                bitmap_sheet.image
+            ));
+        }
+        // Byte ranges waiting to be output together in assembly (up to a page wide)
+        let mut asm_ranges = Vec::<(String, Vec<Vec<u8>>)>::new();
 
         if let Some(b) = bitmap_sheet.bank {
-            println!("#ifndef BITMAP_TABLE_BANK\n#define BITMAP_TABLE_BANK bank{b}\n#endif");
+            writeln!(
+                out,
+                "#ifndef BITMAP_TABLE_BANK\n#define BITMAP_TABLE_BANK bank{b}\n#endif"
+            )?;
         }
 
-        // Generate bitmaps data
+        // Bitmaps to generate, with their own image for the frames of the flicker bitmaps
+        let mut bitmaps = Vec::new();
         for bitmap in &bitmap_sheet.bitmaps {
+            if bitmap.alias.is_some() {
+                if bitmap.flicker || bitmap.clip.is_some() || bitmap.double_buffer {
+                    return Err(anyhow!(
+                        "Bitmap {}: Aliases can't be flickered, clipped or double buffered",
+                        bitmap.name
+                    ));
+                }
+            } else if bitmap.width == 0 {
+                return Err(anyhow!("Bitmap {}: width is missing", bitmap.name));
+            } else if bitmap.height == 0 {
+                return Err(anyhow!("Bitmap {}: height is missing", bitmap.name));
+            }
+            if !bitmap.flicker {
+                bitmaps.push((bitmap.clone(), None));
+                continue;
+            }
+            if bitmap.palettes.is_some() {
+                return Err(anyhow!(
+                    "Bitmap {}: The palettes of flicker frames are allocated automatically",
+                    bitmap.name
+                ));
+            }
+            let background = bitmap
+                .background
+                .or(bitmap_sheet.background)
+                .or(all_bitmaps.background)
+                .unwrap_or((0, 0, 0));
+            // 320C pixels go by pairs of the same color
+            let width = if bitmap_sheet.mode == "320C" {
+                2
+            } else {
+                pixel_width
+            };
+            let (a, b) = flicker_frames(&img, bitmap, width, background, maxmaxcolors);
+            for (suffix, frame) in [("a", a), ("b", b)] {
+                let name = format!("{}_{}", bitmap.name, suffix);
+                bitmaps.push((
+                    Bitmap {
+                        name,
+                        flicker: false,
+                        ..bitmap.clone()
+                    },
+                    Some(frame),
+                ));
+            }
+        }
+
+        // Generate bitmaps data
+        for (bitmap, frame) in &mut bitmaps {
+            let bitmap = &*bitmap;
+            let flicker_frame = frame.is_some();
+            if bitmap.clip.is_some() && (args.bank_size.is_some() || bitmap.double_buffer) {
+                return Err(anyhow!(
+                    "Bitmap {}: Clipped variants aren't supported with bank-size or double_buffer",
+                    bitmap.name
+                ));
+            }
+            if asm && bitmap.double_buffer {
+                return Err(anyhow!(
+                    "Bitmap {}: Double buffering isn't supported with the asm format",
+                    bitmap.name
+                ));
+            }
+            if let Some(alias) = &bitmap.alias {
+                // Display lists (and display list list) of the zones of the aliased bitmap, moved to the xoffset of
+                // the alias, its data and palettes being shared
+                if args.bank_size.is_some() {
+                    return Err(anyhow!(
+                        "Bitmap {}: Aliases aren't supported with bank-size",
+                        bitmap.name
+                    ));
+                }
+                let source = generated
+                    .get(alias)
+                    .ok_or_else(|| anyhow!("Bitmap {}: Unknown alias {}", bitmap.name, alias))?
+                    .clone();
+                let bitmapname = &bitmap.name;
+                let xoffset = bitmap.xoffset.unwrap_or(source.xoffset);
+                let dx = xoffset as i32 - source.xoffset as i32;
+                let nb_dls = source.entries.len() as u32;
+                for (y, entries) in source.entries.iter().enumerate() {
+                    if let Some(same) = source.same[y] {
+                        same_dl(
+                            &mut out,
+                            &mut header,
+                            &format!("{bitmapname}_{y}_dl"),
+                            &format!("{bitmapname}_{same}_dl"),
+                            asm,
+                        )?;
+                        continue;
+                    }
+                    let (dl, size) = moved_dl(
+                        entries,
+                        0..usize::MAX,
+                        dx,
+                        0,
+                        (!args.short_headers).then_some(source.mode_byte),
+                        asm,
+                    )?;
+                    dl_array(
+                        &mut out,
+                        &mut header,
+                        &format!("{bitmapname}_{y}_dl"),
+                        &dl,
+                        size,
+                        bitmap_sheet.bank.is_some(),
+                        asm,
+                    )?;
+                }
+                writeln!(out)?;
+                bitmap_defines(
+                    &mut out,
+                    &mut header,
+                    bitmapname,
+                    source.height,
+                    nb_dls,
+                    bitmap_sheet.bank.is_some(),
+                    asm,
+                )?;
+                if bitmap.dll {
+                    let dls = (0..nb_dls)
+                        .map(|y| format!("{bitmapname}_{y}_dl"))
+                        .collect();
+                    dll(
+                        &mut out,
+                        &mut header,
+                        bitmap,
+                        source.dl_height,
+                        source.yoffset,
+                        source.holeydma,
+                        &[(format!("{bitmapname}_dll"), dls)],
+                        bitmap_sheet.bank.is_some(),
+                        asm,
+                    )?;
+                }
+                if asm {
+                    writeln!(out, "{bitmapname}_set_palette = {alias}_set_palette\n")?;
+                } else if args.output.is_some() {
+                    writeln!(
+                        header,
+                        "#define {bitmapname}_set_palette {alias}_set_palette\n"
+                    )?;
+                } else {
+                    writeln!(
+                        out,
+                        "#define {bitmapname}_set_palette {alias}_set_palette\n"
+                    )?;
+                }
+                let entries = source
+                    .entries
+                    .iter()
+                    .map(|zone| {
+                        zone.iter()
+                            .map(|(name, first, last, palette, x)| {
+                                let x = (*x as i32 + dx).rem_euclid(256) as u32;
+                                (name.clone(), *first, *last, *palette, x)
+                            })
+                            .collect()
+                    })
+                    .collect();
+                generated.insert(
+                    bitmapname.clone(),
+                    BitmapDls {
+                        entries,
+                        xoffset,
+                        ..source
+                    },
+                );
+                continue;
+            }
+            let img = frame.as_mut().unwrap_or(&mut img);
             let mut colors = [(0u8, 0u8, 0u8); 24];
             let mut maxcolors = 0;
-            if let Some(palettes) = &all_bitmaps.palettes {
-                for p in palettes {
+            if bitmap.palettes.is_some() && all_bitmaps.palettes.is_none() {
+                return Err(anyhow!(
+                    "Bitmap {}: Palettes selected without palettes declaration",
+                    bitmap.name
+                ));
+            }
+            if let Some(palettes) = all_bitmaps.palettes.as_ref().filter(|_| !flicker_frame) {
+                let selected = if let Some(names) = &bitmap.palettes {
+                    names
+                        .iter()
+                        .map(|name| {
+                            palettes
+                                .iter()
+                                .find(|p| p.name.as_ref() == Some(name))
+                                .ok_or_else(|| {
+                                    anyhow!("Bitmap {}: Unknown palette {}", bitmap.name, name)
+                                })
+                        })
+                        .collect::<Result<Vec<_>>>()?
+                } else {
+                    palettes.iter().collect()
+                };
+                for p in selected {
                     for c in &p.colors {
                         colors[maxcolors] = *c;
                         maxcolors += 1;
                     }
                 }
             }
-            let background = all_bitmaps.background.unwrap_or((0, 0, 0));
+            let background = bitmap
+                .background
+                .or(bitmap_sheet.background)
+                .or(all_bitmaps.background)
+                .unwrap_or((0, 0, 0));
+            // 320C pixel pairs with two different colors
+            let mut violations = 0;
+            // In 160A and 320A modes, the palettes of the bytes are assigned once the colors of a whole zone are known
+            let per_byte = matches!(bitmap_sheet.mode.as_str(), "160A" | "320A");
+            if let Some(method) = bitmap_sheet.quantize {
+                // 320C pixels go by pairs of the same color
+                let width = if bitmap_sheet.mode == "320C" {
+                    2
+                } else {
+                    pixel_width
+                };
+                let quantized = quantize(
+                    img,
+                    bitmap,
+                    width,
+                    (8 / pixel_bits as u32 * pixel_width / width) as usize,
+                    group_size,
+                    Some(bitmap_sheet.dl_height as u32).filter(|_| bitmap_sheet.mode == "160A"),
+                    background,
+                    method,
+                    &colors[0..maxcolors],
+                    maxmaxcolors,
+                );
+                colors[0..quantized.len()].copy_from_slice(&quantized);
+                maxcolors = quantized.len();
+            }
+            // The colors of flicker frames are always regrouped in palettes (quantized 160A colors already are)
+            if (all_bitmaps.reorder_palettes || flicker_frame)
+                && bitmap_sheet.quantize.is_none()
+                && bitmap_sheet.mode == "160A"
+            {
+                let reordered = reorder_palettes(
+                    img,
+                    bitmap,
+                    bitmap_sheet.dl_height as u32,
+                    background,
+                    &colors[0..maxcolors],
+                )?;
+                colors[0..reordered.len()].copy_from_slice(&reordered);
+                maxcolors = reordered.len();
+            }
 
-            for yy in 0..bitmap.height / bitmap_sheet.dl_height as u32 {
+            let yoffset = bitmap.yoffset.unwrap_or(0);
+            if yoffset >= bitmap_sheet.dl_height as u32 {
+                return Err(anyhow!(
+                    "Bitmap {}: yoffset {} should be less than the zones height",
+                    bitmap.name,
+                    yoffset
+                ));
+            }
+            // Zones of the bitmap, the last one being completed with empty lines if needed
+            let nb_dls = (yoffset + bitmap.height).div_ceil(bitmap_sheet.dl_height as u32);
+            let mut zone_banks = Vec::new();
+            let mut zone_dl_sizes = Vec::new();
+            let mut zone_stats = Vec::<Vec<(usize, usize)>>::new(); // Display list entries of the zones
+            let mut zone_ranges = Vec::<Vec<Range>>::new();
+            // Display list entries of the zones (name, first and last bytes, palette and position), and zone whose
+            // display list is reused
+            let mut zone_entries = Vec::<Vec<Entry>>::new();
+            let mut zone_same = Vec::<Option<u32>>::new();
+            let mut gfx_bytes = 0;
+            let mut dl_bytes = 0;
+            let mut zone_layers = Vec::<(Vec<(Vec<Vec<u8>>, Vec<u8>, u32)>, u32)>::new(); // Output zones
+            let mut zone_streams = Vec::<(Vec<u8>, usize)>::new(); // Compressed data and original size
+            for yy in 0..nb_dls {
                 let mut fullbytes = Vec::<Vec<u8>>::new();
                 let mut palettes = vec![0u8; (bitmap.width / byte_width) as usize];
+                let mut zone = Zone::new();
                 for y in 0..bitmap_sheet.dl_height as u32 {
+                    let mut line = Vec::new();
                     let mut bytes = Vec::<u8>::new();
                     let mut current_byte: u8 = 0;
                     let mut current_bits: u8 = 0;
                     let mut palette: Option<u8> = None;
+                    let mut group: Option<usize> = None;
+                    // The lines of the zones above or below the bitmap are empty
+                    let zone_line = yy * bitmap_sheet.dl_height as u32 + y;
+                    let inside = zone_line >= yoffset && zone_line - yoffset < bitmap.height;
+                    let yp = bitmap.top + zone_line.saturating_sub(yoffset);
+                    let pixel = |x: u32| {
+                        if inside {
+                            img.get_pixel(x, yp)
+                        } else {
+                            image::Rgba([0, 0, 0, 0])
+                        }
+                    };
                     for x in 0..bitmap.width / pixel_width {
                         let xp = bitmap.left + x * pixel_width;
-                        let yp = bitmap.top + yy * bitmap_sheet.dl_height as u32 + y;
-                        let color = img.get_pixel(xp, yp);
+                        let color = pixel(xp);
                         let mut cx = 0u8;
+                        line.push(
+                            Some((color[0], color[1], color[2]))
+                                .filter(|c| color[3] != 0 && *c != background),
+                        );
+
+                        if group_size != 0 && current_bits == 0 {
+                            // Select the palette group holding the colors of all the pixels of the byte,
+                            // keeping the group of the previous byte when possible
+                            let pixels: Vec<_> = (0..8 / pixel_bits as u32)
+                                .map(|i| pixel(xp + i * pixel_width))
+                                .collect();
+                            let fits = |g: usize| {
+                                pixels.iter().all(|p| {
+                                    p[3] == 0
+                                        || (p[0], p[1], p[2]) == background
+                                        || colors[g * group_size
+                                            ..(g * group_size + group_size).min(maxcolors)]
+                                            .contains(&(p[0], p[1], p[2]))
+                                })
+                            };
+                            let previous = group.unwrap_or(0);
+                            group = [previous, 1 - previous]
+                                .into_iter()
+                                .find(|&g| g * group_size < maxcolors && fits(g))
+                                .or(Some(previous));
+                        }
 
                         if color[3] != 0
                             && (color[0] != background.0
                                 || color[1] != background.1
                                 || color[2] != background.2)
                         {
-                            let mut found: Option<u8> = None;
-                            for c in 0..maxcolors {
-                                if color[0] == colors[c].0
-                                    && color[1] == colors[c].1
-                                    && color[2] == colors[c].2
-                                {
-                                    found = Some(c as u8);
-                                    break;
-                                }
-                            }
+                            let first_color = group.map_or(0, |g| g * group_size);
+                            let found = (first_color..maxcolors)
+                                .chain(0..first_color)
+                                .find(|&c| (color[0], color[1], color[2]) == colors[c])
+                                .map(|c| c as u8);
 
                             let c = if let Some(c) = found {
                                 c
@@ -216,7 +1848,10 @@ fn main() -> Result<()> {
                                     colors[maxcolors].1 = color[1];
                                     colors[maxcolors].2 = color[2];
                                     maxcolors += 1;
-                                    println!("// Added new color {:?} to the palette at {x},{y}", color);
+                                    println!(
+                                        "// Added new color {:?} to the palette at {x},{y}",
+                                        color
+                                    );
                                     (maxcolors - 1) as u8
                                 } else {
                                     return Err(anyhow!(
@@ -227,19 +1862,13 @@ fn main() -> Result<()> {
                             };
 
                             match bitmap_sheet.mode.as_str() {
-                                "320A" => {
+                                "160A" | "320A" => {
+                                    // The palettes are assigned for the whole zone
                                     cx = 1;
-                                    if let Some(p) = palette {
-                                        if c as u8 != p {
-                                            return Err(anyhow!("Bitmap {}: Two pixels use a different palette in the same byte (x = {}, y = {}, color1 = {:?}, color2 = {:?})", bitmap.name, xp, yp, c, p));
-                                        }
-                                    } else {
-                                        palette = Some(c as u8);
-                                    }
                                 }
                                 "160B" => {
                                     cx = (c % 12) + 1; // 0 is background
-                                    let px = c / 12;
+                                    let px = (c / 12) * 4; // The group is selected by P2
                                     if let Some(p) = palette {
                                         if px != p {
                                             return Err(anyhow!("Bitmap {}: Two pixels use a different palette in the same byte (x = {}, y = {})", bitmap.name, xp, yp));
@@ -248,6 +1877,17 @@ fn main() -> Result<()> {
                                         palette = Some(px as u8);
                                     }
                                 }
+                                "320B" => {
+                                    cx = (c % 3) + 1; // 0 is background
+                                    let px = (c / 3) * 4; // The group is selected by P2
+                                    if let Some(p) = palette {
+                                        if px != p {
+                                            return Err(anyhow!("Bitmap {}: Two pixels use colors of different palettes (P0 and P4) in the same byte (x = {}, y = {}, color = {:?})", bitmap.name, xp, yp, color));
+                                        }
+                                    } else {
+                                        palette = Some(px);
+                                    }
+                                }
                                 "320C" => {
                                     cx = (c % 4) + 1; // 0 is background
                                     let px = (c / 4) * 4;
@@ -273,7 +1913,7 @@ fn main() -> Result<()> {
                             if bitmap_sheet.mode == "320C" {
                                 // Check next pixel, should be background or same color
                                 if x & 1 == 0 {
-                                    let colorr = img.get_pixel(xp + 1, yp);
+                                    let colorr = pixel(xp + 1);
                                     if !(colorr[3] == 0
                                         || (colorr[0] == background.0
                                             && colorr[1] == background.1
@@ -281,8 +1921,8 @@ fn main() -> Result<()> {
                                     {
                                         // This is not background
                                         if colorr != color {
-                                            println!("// Bitmap {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", bitmap.name, x, y, color, colorr);
-                                            //return Err(anyhow!("Bitmap {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", bitmap.name, x, y, color, colorr));
+                                            println!("// Bitmap {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", bitmap.name, xp, yp, color, colorr);
+                                            violations += 1;
                                         }
                                     }
                                 }
@@ -382,227 +2022,752 @@ fn main() -> Result<()> {
                         };
                     }
 
-                    fullbytes.push(bytes)
+                    fullbytes.push(bytes);
+                    zone.push(line);
                 }
 
-                // Whoaw. We do have our pixels vector. Let's output it
-
-                // Let's find ranges of bytes that are not all 0s on all lines (for memory
-                // compression)
-                let mut first = 0;
-                let end = fullbytes[0].len();
-                let mut range_counter = 0;
-                let mut dl = String::new();
-                let mut nb_bytes = 0;
-                let mut palette;
-                loop {
-                    if first == end {
-                        break;
-                    }
-                    let mut empty = true;
-                    for v in &fullbytes {
-                        if v[first] != 0 {
-                            empty = false;
-                            break;
+                let mut layers = vec![(fullbytes, palettes, 0)];
+                if per_byte {
+                    let mode_palettes = mode_palettes(&bitmap_sheet.mode, &colors[0..maxcolors]);
+                    let pixels_per_byte = (8 / pixel_bits) as usize;
+                    let zones = if bitmap_sheet.mode == "320A" {
+                        let (zones, transitions) = split_transitions(&zone);
+                        if transitions != 0 {
+                            println!("// Bitmap {}: {} palette transition(s) inside bytes of zone {} (new display list entries started at the transitions)", bitmap.name, transitions, yy);
                         }
-                    }
-                    if empty {
-                        first += 1;
-                        if first == end {
-                            break;
+                        zones
+                    } else {
+                        vec![(0, zone)]
+                    };
+                    layers.clear();
+                    for (shift, zone) in zones {
+                        let columns: Vec<Vec<(u8, u8, u8)>> = (0..zone[0].len() / pixels_per_byte)
+                            .map(|i| {
+                                let mut column: Vec<_> = zone
+                                    .iter()
+                                    .flat_map(|line| {
+                                        line[i * pixels_per_byte..(i + 1) * pixels_per_byte]
+                                            .iter()
+                                            .flatten()
+                                            .copied()
+                                    })
+                                    .collect();
+                                column.sort();
+                                column.dedup();
+                                column
+                            })
+                            .collect();
+                        let assigned = assign_palettes(&columns, &mode_palettes);
+                        let split = assigned.iter().filter(|a| a.len() > 1).count();
+                        if split != 0 {
+                            println!("// Bitmap {}: {} byte(s) of zone {} use the colors of several palettes (displayed by additional display list entries)", bitmap.name, split, yy);
                         }
+                        let nb_layers = assigned.iter().map(|a| a.len()).max().unwrap_or(0);
+                        layers.extend((0..nb_layers.max(1)).map(|layer| {
+                            let (bytes, palettes) =
+                                layer_bytes(&zone, &assigned, layer, &mode_palettes, pixel_bits);
+                            (bytes, palettes, shift)
+                        }));
+                    }
+                }
+
+                // A zone identical to a previous one of the bitmap (e.g. sky or floor) uses its display list
+                if let Some(&(_, same)) = zone_layers.iter().find(|(l, _)| *l == layers) {
+                    println!(
+                        "// Bitmap {}: zone {} is the same as zone {} (display list reused)",
+                        bitmap.name, yy, same
+                    );
+                    same_dl(
+                        &mut out,
+                        &mut header,
+                        &format!("{}_{}_dl", bitmap.name, yy),
+                        &format!("{}_{}_dl", bitmap.name, same),
+                        asm,
+                    )?;
+                    zone_banks.push(zone_banks[same as usize]);
+                    zone_dl_sizes.push(zone_dl_sizes[same as usize]);
+                    zone_stats.push(zone_stats[same as usize].clone());
+                    zone_ranges.push(zone_ranges[same as usize].clone());
+                    zone_entries.push(zone_entries[same as usize].clone());
+                    zone_same.push(Some(same));
+                    zone_streams.push((Vec::new(), 0));
+                    continue;
+                }
+                zone_layers.push((layers.clone(), yy));
+
+                // Whoaw. We do have our pixels vector. Let's output it
+
+                // With --bank-size, the data and display list of the zone go in the current bank, or in the
+                // next one when it's full
+                let auto_bank = args.bank_size.filter(|_| bitmap_sheet.bank.is_none());
+                let store_len = store.len();
+                let (
+                    zone_out,
+                    zone_header,
+                    dl,
+                    nb_bytes,
+                    bank,
+                    entries,
+                    zone_bytes,
+                    ranges,
+                    clip_entries,
+                ) = loop {
+                    let bank = if auto_bank.is_some() {
+                        Some(current_bank)
                     } else {
-                        // Ok, we have found a first char that is not empty
-                        // Let's find an end (or a char that has different palette)
-                        palette = palettes[first];
-                        let mut last = first + 1;
-                        if last != end {
-                            loop {
-                                let mut empty = true;
-                                for v in &fullbytes {
-                                    if v[last] != 0 {
-                                        empty = false;
-                                        break;
-                                    }
+                        bitmap_sheet.bank
+                    };
+                    let mut out = String::new();
+                    let mut header = String::new();
+                    let mut zone_bytes = 0;
+                    // Compressed data of the zone, in a single array
+                    let mut packed = vec![Vec::<u8>::new(); bitmap_sheet.dl_height as usize];
+                    let mut range_counter = 0;
+                    let mut dl = String::new();
+                    let mut nb_bytes = 0;
+                    let mut entries = Vec::new(); // Header size and width of the entries
+                    let mut ranges = Vec::new();
+                    let mut clip_entries = Vec::new();
+                    for (fullbytes, palettes, shift) in layers.clone() {
+                        // Let's find ranges of bytes that are not all 0s on all lines (for memory
+                        // compression)
+                        let mut first = 0;
+                        let end = fullbytes[0].len();
+                        let mut palette;
+                        loop {
+                            if first == end {
+                                break;
+                            }
+                            let mut empty = true;
+                            for v in &fullbytes {
+                                if v[first] != 0 {
+                                    empty = false;
+                                    break;
                                 }
-                                if !empty {
-                                    // Is it the same palette ?
-                                    if palettes[last] != palette {
-                                        break;
-                                    }
-                                    // Is it bigger than 31 bytes
-                                    if range_counter != 0 {
-                                        if last - first == 31 {
+                            }
+                            if empty {
+                                first += 1;
+                                if first == end {
+                                    break;
+                                }
+                            } else {
+                                // Ok, we have found a first char that is not empty
+                                // Let's find an end (or a char that has different palette)
+                                palette = palettes[first];
+                                let mut last = first + 1;
+                                if last != end {
+                                    loop {
+                                        let mut empty = true;
+                                        for v in &fullbytes {
+                                            if v[last] != 0 {
+                                                empty = false;
+                                                break;
+                                            }
+                                        }
+                                        if !empty {
+                                            // Is it the same palette ?
+                                            if palettes[last] != palette {
+                                                break;
+                                            }
+                                            // Is it bigger than 31 bytes
+                                            if range_counter != 0 || args.short_headers {
+                                                if last - first == 31 {
+                                                    break;
+                                                }
+                                            } else if last - first == 32 {
+                                                break;
+                                            }
+                                            last += 1;
+                                            if last == end {
+                                                break;
+                                            }
+                                        } else {
                                             break;
                                         }
-                                    } else if last - first == 32 {
-                                        break;
-                                    }
-                                    last += 1;
-                                    if last == end {
-                                        break;
                                     }
-                                } else {
-                                    break;
                                 }
-                            }
-                        }
 
-                        // OK. Now we have our series of bytes.
-                        // Reconstruct this array of bytes to be ready to compare with store
-                        let mut bytespart = Vec::<Vec<u8>>::new();
-                        for i in &fullbytes {
-                            bytespart.push(i[first..last].to_vec());
-                        }
+                                // OK. Now we have our series of bytes.
+                                // Reconstruct this array of bytes to be ready to compare with store
+                                let mut bytespart = Vec::<Vec<u8>>::new();
+                                for i in &fullbytes {
+                                    bytespart.push(i[first..last].to_vec());
+                                }
 
-                        // Let's look for them in the store
-                        let mut found = None;
-                        let mut name = String::new();
-                        for r in &store {
-                            if r.1[0].len() >= bytespart[0].len() {
-                                let f = r.1[0]
-                                    .windows(bytespart[0].len())
-                                    .position(|w| w == bytespart[0]);
-                                if let Some(offset) = f {
-                                    // Check each line
-                                    let mut ok = true;
-                                    for j in 1..bitmap_sheet.dl_height as usize {
-                                        if r.1[j][offset..offset + bytespart[j].len()]
-                                            != bytespart[j]
-                                        {
-                                            ok = false;
-                                            break;
+                                // Let's look for them in the store
+                                let mut found = None;
+                                let mut name = String::new();
+                                for r in &store {
+                                    if args.compress.is_none()
+                                        && r.bank == bank
+                                        && r.holeydma == holeydma
+                                        && r.noholeydma == (bitmap_sheet.noholeydma == Some(true))
+                                        && r.bytes.len() == bytespart.len()
+                                        && r.bytes[0].len() >= bytespart[0].len()
+                                    {
+                                        let f = r.bytes[0]
+                                            .windows(bytespart[0].len())
+                                            .position(|w| w == bytespart[0]);
+                                        if let Some(offset) = f {
+                                            // Check each line
+                                            let ok = bytespart.iter().enumerate().skip(1).all(
+                                                |(j, b)| r.bytes[j][offset..offset + b.len()] == *b,
+                                            );
+                                            if ok {
+                                                found = Some(offset);
+                                                name = r.name.clone();
+                                                break;
+                                            }
                                         }
                                     }
-                                    if ok {
-                                        found = Some(offset);
-                                        name = r.0.clone();
-                                        break;
-                                    }
                                 }
-                            }
-                        }
-                        if let Some(offset) = found {
-                            name = format!("{name} + {offset}");
-                        } else {
-                            // We haven't found it in the store, so Let's output them
-                            name = format!("{}_{}_{}", bitmap.name, yy, range_counter);
-                            if let Some(b) = bitmap_sheet.bank {
-                                print!("bank{} ", b);
-                            }
-                            if let Some(no) = bitmap_sheet.noholeydma {
-                                if no {
-                                    print!("noholeydma ");
-                                }
-                            }
-                            print!(
-                                "reversed scattered({},{}) char {}[{}] = {{\n\t",
-                                bitmap_sheet.dl_height,
-                                last - first,
-                                name,
-                                (last - first) * bitmap_sheet.dl_height as usize
-                            );
-                            let mut c = 0;
-                            for bytes in &fullbytes {
-                                for i in first..last {
-                                    print!("0x{:02x}", bytes[i]);
-                                    if c == (last - first) * bitmap_sheet.dl_height as usize - 1 {
-                                        println!("}};");
-                                    } else if (c + 1) % 16 != 0 {
-                                        print!(", ");
-                                    } else {
-                                        print!(",\n\t");
+                                if let Some(offset) = found {
+                                    name = format!("{name} + {offset}");
+                                } else if args.compress.is_some() {
+                                    name = format!("{}_{} + {}", bitmap.name, yy, packed[0].len());
+                                    for (row, bytes) in packed.iter_mut().zip(&bytespart) {
+                                        row.extend(bytes);
+                                    }
+                                } else if asm {
+                                    name = format!("{}_{}_{}", bitmap.name, yy, range_counter);
+                                    let width: usize =
+                                        asm_ranges.iter().map(|r| r.1[0].len()).sum();
+                                    if width + bytespart[0].len() > 256 {
+                                        out.push_str(&asm_block(
+                                            &asm_ranges,
+                                            bitmap_sheet.dl_height as usize,
+                                            holeydma,
+                                        ));
+                                        asm_ranges.clear();
                                     }
-                                    c += 1;
+                                    asm_ranges.push((name.clone(), bytespart.clone()));
+                                    zone_bytes += (last - first) * bitmap_sheet.dl_height as usize;
+                                    store.push(StoredRange {
+                                        name: name.clone(),
+                                        bytes: bytespart,
+                                        bank,
+                                        holeydma,
+                                        noholeydma: bitmap_sheet.noholeydma == Some(true),
+                                    });
+                                } else {
+                                    // We haven't found it in the store, so Let's output them
+                                    name = format!("{}_{}_{}", bitmap.name, yy, range_counter);
+                                    if let Some(b) = bank {
+                                        write!(out, "bank{} ", b)?;
+                                    }
+                                    if let Some(no) = bitmap_sheet.noholeydma {
+                                        if no {
+                                            write!(out, "noholeydma ")?;
+                                        }
+                                    }
+                                    if holeydma {
+                                        write!(out, "holeydma ")?;
+                                    }
+                                    write!(
+                                        out,
+                                        "reversed scattered({},{}) char {}[{}] = {{\n\t",
+                                        bitmap_sheet.dl_height,
+                                        last - first,
+                                        name,
+                                        (last - first) * bitmap_sheet.dl_height as usize
+                                    )?;
+                                    writeln!(
+                                        header,
+                                        "extern char {}[{}];",
+                                        name,
+                                        (last - first) * bitmap_sheet.dl_height as usize
+                                    )?;
+                                    let mut c = 0;
+                                    for bytes in &fullbytes {
+                                        for byte in &bytes[first..last] {
+                                            write!(out, "0x{:02x}", byte)?;
+                                            if c == (last - first) * bitmap_sheet.dl_height as usize
+                                                - 1
+                                            {
+                                                writeln!(out, "}};")?;
+                                            } else if (c + 1) % 16 != 0 {
+                                                write!(out, ", ")?;
+                                            } else {
+                                                write!(out, ",\n\t")?;
+                                            }
+                                            c += 1;
+                                        }
+                                    }
+                                    zone_bytes += (last - first) * bitmap_sheet.dl_height as usize;
+                                    // Put them in store
+                                    store.push(StoredRange {
+                                        name: name.clone(),
+                                        bytes: bytespart,
+                                        bank,
+                                        holeydma,
+                                        noholeydma: bitmap_sheet.noholeydma == Some(true),
+                                    });
                                 }
-                            }
-                            // Put them in store
-                            store.push((name.clone(), bytespart));
-                        }
 
-                        let byte_width = match bitmap_sheet.mode.as_str() {
-                            "160A" | "320A" | "320D" => 4,
-                            _ => 2,
-                        };
-                        let x = bitmap.xoffset.unwrap_or(0) + first as u32 * byte_width;
-                        if range_counter == 0 {
-                            let mode_byte = match bitmap_sheet.mode.as_str() {
-                                "320A" | "160A" => 0x40,
-                                _ => 0xc0,
-                            };
-                            dl.push_str(
-                                format!(
-                                    "{} & 0xff, 0x{:02x}, {} >> 8, (-{} & 0x1f) | ({} << 5), {}, ",
-                                    name,
-                                    mode_byte,
-                                    name,
-                                    last - first,
+                                let byte_width = match bitmap_sheet.mode.as_str() {
+                                    "160A" | "320A" | "320D" => 4,
+                                    _ => 2,
+                                };
+                                let x = bitmap.xoffset.unwrap_or(0)
+                                    + first as u32 * byte_width
+                                    + shift / 2;
+                                clip_entries.push((name.clone(), first, last, palette, x));
+                                ranges.push((
+                                    x,
                                     palette,
-                                    x
-                                )
-                                .as_str(),
-                            );
-                            nb_bytes += 5;
-                        } else {
-                            dl.push_str(
-                                format!(
-                                    "{} & 0xff, (-{} & 0x1f) | ({} << 5), {} >> 8, {}, ",
-                                    name,
+                                    fullbytes.iter().map(|b| b[first..last].to_vec()).collect(),
+                                ));
+                                let mode_byte = (range_counter == 0 && !args.short_headers)
+                                    .then_some(match bitmap_sheet.mode.as_str() {
+                                        "320A" | "160A" => 0x40,
+                                        _ => 0xc0,
+                                    });
+                                dl.push_str(&dl_entry(
+                                    &name,
                                     last - first,
                                     palette,
-                                    name,
-                                    x
-                                )
-                                .as_str(),
-                            );
-                            nb_bytes += 4;
-                        }
+                                    x,
+                                    mode_byte,
+                                    asm,
+                                ));
+                                let header_size = if mode_byte.is_some() { 5 } else { 4 };
+                                nb_bytes += header_size;
+                                entries.push((header_size, last - first));
 
-                        range_counter += 1;
-                        first = last;
+                                range_counter += 1;
+                                first = last;
+                            }
+                        }
+                    }
+                    if let Some(bank_size) = auto_bank {
+                        let size = zone_bytes + nb_bytes + 2;
+                        if bank_used + size > bank_size {
+                            if bank_used == 0 {
+                                return Err(anyhow!(
+                                "Bitmap {}: Zone {} ({} bytes) doesn't fit in a bank of {} bytes",
+                                bitmap.name,
+                                yy,
+                                size,
+                                bank_size
+                            ));
+                            }
+                            // Starts again in the next bank, without the data stored for this attempt
+                            store.truncate(store_len);
+                            current_bank += 1;
+                            bank_used = 0;
+                            continue;
+                        }
+                        bank_used += size;
                     }
+                    if args.compress.is_some() && !packed[0].is_empty() {
+                        // Zone array filled at runtime by the decompression of its part of the stream
+                        let name = format!("{}_{}", bitmap.name, yy);
+                        let size = packed.len() * packed[0].len();
+                        if let Some(b) = bank {
+                            write!(out, "bank{} ", b)?;
+                        }
+                        if bitmap_sheet.noholeydma == Some(true) {
+                            write!(out, "noholeydma ")?;
+                        }
+                        if holeydma {
+                            write!(out, "holeydma ")?;
+                        }
+                        writeln!(
+                            out,
+                            "reversed scattered({},{}) char {}[{}];",
+                            bitmap_sheet.dl_height,
+                            packed[0].len(),
+                            name,
+                            size
+                        )?;
+                        writeln!(header, "extern char {}[{}];", name, size)?;
+                        let data = packed.concat();
+                        zone_streams.push((rle(&data), data.len()));
+                    } else {
+                        zone_streams.push((Vec::new(), 0));
+                    }
+                    break (
+                        out,
+                        header,
+                        dl,
+                        nb_bytes,
+                        bank,
+                        entries,
+                        zone_bytes,
+                        ranges,
+                        clip_entries,
+                    );
+                };
+                out.push_str(&zone_out);
+                header.push_str(&zone_header);
+                zone_dl_sizes.push(nb_bytes + 2);
+                zone_stats.push(entries);
+                zone_ranges.push(ranges);
+                zone_entries.push(clip_entries);
+                zone_same.push(None);
+                gfx_bytes += zone_bytes;
+                dl_bytes += nb_bytes + 2;
+                zone_banks.push(bank);
+                if asm {
+                    writeln!(out, "{}_{}_dl:\n{}\t.byte 0, 0", bitmap.name, yy, dl)?;
+                    continue;
                 }
-                if bitmap_sheet.bank.is_some() {
-                    print!("BITMAP_TABLE_BANK ");
+                if auto_bank.is_some() {
+                    write!(out, "bank{} ", bank.unwrap())?;
+                } else if bitmap_sheet.bank.is_some() {
+                    write!(out, "BITMAP_TABLE_BANK ")?;
                 }
-                println!(
+                writeln!(
+                    out,
                     "const unsigned char {}_{}_dl[{}] = {{{}0, 0}};",
                     bitmap.name,
                     yy,
                     nb_bytes + 2,
                     dl
+                )?;
+                writeln!(
+                    header,
+                    "extern const unsigned char {}_{}_dl[{}];",
+                    bitmap.name,
+                    yy,
+                    nb_bytes + 2
+                )?;
+            }
+            if args.compress.is_some() {
+                let bitmapname = &bitmap.name;
+                let stream: Vec<u8> = zone_streams.iter().flat_map(|z| z.0.clone()).collect();
+                let mut offsets = Vec::new();
+                let mut offset = 0;
+                for z in &zone_streams {
+                    offsets.push(offset);
+                    offset += z.0.len();
+                }
+                let sizes: Vec<usize> = zone_streams.iter().map(|z| z.1).collect();
+                // The offsets and sizes tables are 16 bits wide
+                if offsets.iter().chain(&sizes).any(|&v| v > 0xffff) {
+                    return Err(anyhow!(
+                        "Bitmap {}: compressed zones offsets or sizes above 65535",
+                        bitmap.name
+                    ));
+                }
+                gfx_bytes += stream.len();
+                println!(
+                    "// Bitmap {}: {} bytes compressed to {}",
+                    bitmapname,
+                    sizes.iter().sum::<usize>(),
+                    stream.len()
                 );
+                if bitmap_sheet.bank.is_some() {
+                    write!(out, "BITMAP_TABLE_BANK ")?;
+                }
+                let bytes: Vec<String> = stream.iter().map(|b| format!("0x{:02x}", b)).collect();
+                writeln!(
+                    out,
+                    "const unsigned char {bitmapname}_rle[{}] = {{\n\t{}}};",
+                    stream.len().max(1),
+                    if bytes.is_empty() {
+                        "0".to_string()
+                    } else {
+                        bytes
+                            .chunks(16)
+                            .map(|c| c.join(", "))
+                            .collect::<Vec<_>>()
+                            .join(",\n\t")
+                    }
+                )?;
+                writeln!(
+                    header,
+                    "extern const unsigned char {bitmapname}_rle[{}];",
+                    stream.len().max(1)
+                )?;
+                // Offset in the stream and original size of each zone
+                for (table, values) in [("rle_offsets", &offsets), ("rle_sizes", &sizes)] {
+                    for (part, shift) in [("high", 8), ("low", 0)] {
+                        let v: Vec<String> = values
+                            .iter()
+                            .map(|v| format!("0x{:02x}", (v >> shift) & 0xff))
+                            .collect();
+                        if bitmap_sheet.bank.is_some() {
+                            write!(out, "BITMAP_TABLE_BANK ")?;
+                        }
+                        writeln!(
+                            out,
+                            "const unsigned char {bitmapname}_{table}_{part}[{}] = {{{}}};",
+                            v.len(),
+                            v.join(", ")
+                        )?;
+                        writeln!(
+                            header,
+                            "extern const unsigned char {bitmapname}_{table}_{part}[{}];",
+                            v.len()
+                        )?;
+                    }
+                }
             }
-            println!();
-            let nb_dls = bitmap.height / bitmap_sheet.dl_height as u32;
-            let bitmapname = &bitmap.name;
-            if bitmap_sheet.bank.is_some() {
-                print!("BITMAP_TABLE_BANK ");
+            if violations != 0 {
+                return Err(anyhow!(
+                    "Bitmap {}: {} pairs of pixels with two different colors (320C pixels go by pairs of the same color or background)",
+                    bitmap.name,
+                    violations
+                ));
             }
-            print!("const char {bitmapname}_data_ptrs_high[{}] = {{", nb_dls);
-            for y in 0..nb_dls - 1 {
-                print!("{bitmapname}_{y}_dl >> 8, ");
+            writeln!(out)?;
+            let bitmapname = &bitmap.name;
+            bitmap_defines(
+                &mut out,
+                &mut header,
+                bitmapname,
+                bitmap.height,
+                nb_dls,
+                bitmap_sheet.bank.is_some(),
+                asm,
+            )?;
+            if args.bank_size.is_some() && bitmap_sheet.bank.is_none() {
+                // Bank of each zone, to be selected (by a DLI) before MARIA displays it
+                let banks: Vec<String> = zone_banks
+                    .iter()
+                    .map(|b| b.unwrap_or(0).to_string())
+                    .collect();
+                writeln!(
+                    out,
+                    "const char {bitmapname}_banks[{nb_dls}] = {{{}}};\n",
+                    banks.join(", ")
+                )?;
+                writeln!(header, "extern const char {bitmapname}_banks[{nb_dls}];")?;
             }
-            println!("{bitmapname}_{}_dl >> 8}};", nb_dls - 1);
-            if bitmap_sheet.bank.is_some() {
-                print!("BITMAP_TABLE_BANK ");
+
+            generated.insert(
+                bitmapname.clone(),
+                BitmapDls {
+                    entries: zone_entries.clone(),
+                    same: zone_same.clone(),
+                    xoffset: bitmap.xoffset.unwrap_or(0),
+                    yoffset,
+                    height: bitmap.height,
+                    dl_height: bitmap_sheet.dl_height,
+                    mode_byte: match bitmap_sheet.mode.as_str() {
+                        "320A" | "160A" => 0x40,
+                        _ => 0xc0,
+                    },
+                    holeydma,
+                },
+            );
+
+            // Clipped variants (for scroll-ins): the bitmap moved by a number of bytes to the left or to the right
+            // and clipped at its own edges, the display lists of the variants pointing into its graphics data
+            let mut variants = Vec::<(&str, Vec<String>)>::new(); // Side and names, from the whole bitmap on
+            if let Some(clip) = bitmap.clip {
+                let sides: &[&str] = match clip {
+                    Clip::Left => &["left"],
+                    Clip::Right => &["right"],
+                    Clip::Both => &["left", "right"],
+                };
+                let hpos_width = match bitmap_sheet.mode.as_str() {
+                    "160A" | "320A" | "320D" => 4,
+                    _ => 2,
+                };
+                let mode_byte = match bitmap_sheet.mode.as_str() {
+                    "320A" | "160A" => 0x40,
+                    _ => 0xc0,
+                };
+                let columns = (bitmap.width / byte_width) as usize;
+                for side in sides {
+                    let mut names = vec![bitmapname.to_string()];
+                    for k in 1..columns {
+                        let variant = format!("{bitmapname}_{side}{k}");
+                        let (keep, dx) = if *side == "left" {
+                            (k..columns, -((k * hpos_width) as i32))
+                        } else {
+                            (0..columns - k, (k * hpos_width) as i32)
+                        };
+                        for (y, entries) in zone_entries.iter().enumerate() {
+                            if let Some(same) = zone_same[y] {
+                                same_dl(
+                                    &mut out,
+                                    &mut header,
+                                    &format!("{variant}_{y}_dl"),
+                                    &format!("{variant}_{same}_dl"),
+                                    asm,
+                                )?;
+                                continue;
+                            }
+                            let (dl, size) = moved_dl(
+                                entries,
+                                keep.clone(),
+                                dx,
+                                hpos_width,
+                                (!args.short_headers).then_some(mode_byte),
+                                asm,
+                            )?;
+                            dl_array(
+                                &mut out,
+                                &mut header,
+                                &format!("{variant}_{y}_dl"),
+                                &dl,
+                                size,
+                                bitmap_sheet.bank.is_some(),
+                                asm,
+                            )?;
+                        }
+                        data_ptrs(
+                            &mut out,
+                            &mut header,
+                            &variant,
+                            nb_dls,
+                            bitmap_sheet.bank.is_some(),
+                            asm,
+                        )?;
+                        names.push(variant);
+                    }
+                    // Display lists tables of the variants, from the whole bitmap to the most clipped one
+                    for table in ["high", "low"] {
+                        let ptrs: Vec<String> = names
+                            .iter()
+                            .map(|n| format!("{n}_data_ptrs_{table}"))
+                            .collect();
+                        pointers(
+                            &mut out,
+                            &mut header,
+                            &format!("{bitmapname}_{side}_data_ptrs_{table}"),
+                            &ptrs,
+                            bitmap_sheet.bank.is_some(),
+                            asm,
+                        )?;
+                    }
+                    println!(
+                        "// Bitmap {}: {} variants clipped on the {}",
+                        bitmap.name,
+                        columns - 1,
+                        side
+                    );
+                    variants.push((side, names));
+                }
             }
-            print!("const char {bitmapname}_data_ptrs_low[{}] = {{", nb_dls);
-            for y in 0..nb_dls - 1 {
-                print!("{bitmapname}_{y}_dl & 0xff, ");
+
+            // Display lists of the bitmap (templates), or their A and B RAM copies when double buffered
+            let suffixes: &[&str] = if bitmap.double_buffer {
+                &["_a", "_b"]
+            } else {
+                &[""]
+            };
+            if bitmap.double_buffer {
+                if let Some(size) = zone_dl_sizes.iter().find(|&&size| size > 255) {
+                    return Err(anyhow!(
+                        "Bitmap {}: Display list of {} bytes too large for double buffering",
+                        bitmap.name,
+                        size
+                    ));
+                }
+                let mut pairs = Vec::new();
+                for (y, size) in zone_dl_sizes.iter().enumerate() {
+                    for suffix in suffixes {
+                        writeln!(out, "unsigned char {bitmapname}_{y}_dl{suffix}[{size}];")?;
+                        writeln!(
+                            header,
+                            "extern unsigned char {bitmapname}_{y}_dl{suffix}[{size}];"
+                        )?;
+                        pairs.push(format!("{bitmapname}_{y}_dl{suffix}"));
+                    }
+                }
+                if bitmap_sheet.bank.is_some() {
+                    write!(out, "BITMAP_TABLE_BANK ")?;
+                }
+                writeln!(
+                    out,
+                    "const char *{bitmapname}_dl_pairs[{}] = {{{}}};\n",
+                    pairs.len(),
+                    pairs.join(", ")
+                )?;
+                writeln!(
+                    header,
+                    "extern const char *{bitmapname}_dl_pairs[{}];",
+                    pairs.len()
+                )?;
+                // Initialization of the copies (in the header file when writing files)
+                let target = if args.output.is_some() {
+                    &mut header
+                } else {
+                    &mut out
+                };
+                writeln!(target, "inline void {bitmapname}_init_dl_buffers() {{")?;
+                writeln!(target, "\tchar i;")?;
+                for (y, size) in zone_dl_sizes.iter().enumerate() {
+                    writeln!(target, "\tfor (i = 0; i != {size}; i++) {{")?;
+                    for suffix in suffixes {
+                        writeln!(
+                            target,
+                            "\t\t{bitmapname}_{y}_dl{suffix}[i] = {bitmapname}_{y}_dl[i];"
+                        )?;
+                    }
+                    writeln!(target, "\t}}")?;
+                }
+                writeln!(target, "}}\n")?;
             }
-            println!("{bitmapname}_{}_dl & 0xff}};", nb_dls - 1);
-            if bitmap_sheet.bank.is_some() {
-                print!("BITMAP_TABLE_BANK ");
+
+            if bitmap.dll {
+                // Display list lists of the bitmap (or of its copies) and of its clipped variants
+                let mut dlls: Vec<(String, Vec<String>)> = suffixes
+                    .iter()
+                    .map(|suffix| {
+                        (
+                            format!("{bitmapname}_dll{suffix}"),
+                            (0..nb_dls)
+                                .map(|y| format!("{bitmapname}_{y}_dl{suffix}"))
+                                .collect(),
+                        )
+                    })
+                    .collect();
+                for (_, names) in &variants {
+                    dlls.extend(names.iter().skip(1).map(|variant| {
+                        (
+                            format!("{variant}_dll"),
+                            (0..nb_dls).map(|y| format!("{variant}_{y}_dl")).collect(),
+                        )
+                    }));
+                }
+                dll(
+                    &mut out,
+                    &mut header,
+                    bitmap,
+                    bitmap_sheet.dl_height,
+                    yoffset,
+                    holeydma,
+                    &dlls,
+                    bitmap_sheet.bank.is_some(),
+                    asm,
+                )?;
+                for (side, names) in &variants {
+                    let dlls: Vec<String> = names.iter().map(|n| format!("{n}_dll")).collect();
+                    pointers(
+                        &mut out,
+                        &mut header,
+                        &format!("{bitmapname}_{side}_dlls"),
+                        &dlls,
+                        bitmap_sheet.bank.is_some(),
+                        asm,
+                    )?;
+                }
             }
-            println!("const char *{bitmapname}_data_ptrs[2] = {{{bitmapname}_data_ptrs_high, {bitmapname}_data_ptrs_low}};\n");
 
-            // Output palettes
-            println!("inline void {bitmapname}_set_palette() {{");
+            // Output palettes (in the header file when writing files, as an inline function), or as a subroutine in
+            // assembly
+            let target = if asm {
+                &mut out
+            } else if args.output.is_some() {
+                &mut header
+            } else {
+                &mut out
+            };
             let color = find_color_in_palette(&background);
-            println!("\t*BACKGRND = multisprite_color(0x{:02x});", color);
-            for i in 0..maxcolors {
-                let color = find_color_in_palette(&colors[i]);
+            if asm {
+                writeln!(target, "\n{bitmapname}_set_palette:")?;
+                writeln!(target, "\tlda #${:02x}\n\tsta BACKGRND", color)?;
+            } else {
+                writeln!(target, "inline void {bitmapname}_set_palette() {{")?;
+                writeln!(target, "\t*BACKGRND = multisprite_color(0x{:02x});", color)?;
+            }
+            for (i, c) in colors.iter().take(maxcolors).enumerate() {
+                let color = find_color_in_palette(c);
                 let palette;
                 let index_in_palette;
                 match bitmap_sheet.mode.as_str() {
@@ -614,15 +2779,103 @@ fn main() -> Result<()> {
                         palette = i / 3;
                         index_in_palette = 1 + i % 3;
                     }
+                    "320B" => {
+                        palette = (i / 3) * 4;
+                        index_in_palette = 1 + i % 3;
+                    }
+                    "160A" => {
+                        palette = i / 3;
+                        index_in_palette = 1 + i % 3;
+                    }
                     _ => unimplemented!(),
                 }
+                if asm {
+                    writeln!(
+                        target,
+                        "\tlda #${:02x}\n\tsta P{palette}C{index_in_palette}",
+                        color
+                    )?;
+                } else {
+                    writeln!(
+                        target,
+                        "\t*P{palette}C{index_in_palette} = multisprite_color(0x{:02x});",
+                        color
+                    )?;
+                }
+            }
+            writeln!(target, "{}", if asm { "\trts\n" } else { "}" })?;
+
+            if args.stats {
+                println!(
+                    "// Bitmap {}: {} graphics bytes, {} display list bytes",
+                    bitmap.name, gfx_bytes, dl_bytes
+                );
                 println!(
-                    "\t*P{palette}C{index_in_palette} = multisprite_color(0x{:02x});",
-                    color
+                    "// Bitmap {}: display list entries per zone: {}",
+                    bitmap.name,
+                    zone_stats
+                        .iter()
+                        .map(|e| e.len().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
                 );
+                if let Some((worst, entries)) = zone_stats
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(i, e)| (dma_cycles(e), std::cmp::Reverse(*i)))
+                {
+                    println!(
+                        "// Bitmap {}: worst zone {} ({} entries, {} bytes): {} DMA cycles per line (out of 454)",
+                        bitmap.name,
+                        worst,
+                        entries.len(),
+                        entries.iter().map(|e| e.1).sum::<usize>(),
+                        dma_cycles(entries)
+                    );
+                }
             }
-            println!("}}");
+
+            if args.preview {
+                let filename = format!("{}_preview.png", bitmap.name);
+                preview(
+                    &bitmap_sheet.mode,
+                    &zone_ranges,
+                    bitmap_sheet.dl_height as usize,
+                    &colors,
+                    background,
+                )
+                .save(&filename)?;
+                println!("// Bitmap {}: preview written in {}", bitmap.name, filename);
+            }
+        }
+        if !asm_ranges.is_empty() {
+            out.push_str(&asm_block(
+                &asm_ranges,
+                bitmap_sheet.dl_height as usize,
+                bitmap_sheet.holeydma == Some(true),
+            ));
+        }
+    }
+
+    if let Some(basename) = &args.output {
+        if asm {
+            fs::write(format!("{basename}.asm"), out)?;
+            return Ok(());
         }
+        let guard = Path::new(basename)
+            .file_name()
+            .map_or("BITMAPS".into(), |f| f.to_string_lossy().to_uppercase())
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>()
+            + "_H";
+        fs::write(format!("{basename}.c"), out)?;
+        fs::write(
+            format!("{basename}.h"),
+            format!("#ifndef {guard}\n#define {guard}\n\n{header}\n#endif\n"),
+        )?;
+    } else {
+        print!("{out}");
     }
 
     Ok(())
@@ -1,15 +1,144 @@
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use image::GenericImageView;
+use asefile::{AnimationDirection, AsepriteFile};
+use clap::{Parser, ValueEnum};
+use image::codecs::{gif::GifDecoder, png::PngDecoder};
+use image::{
+    imageops, imageops::FilterType, AnimationDecoder, DynamicImage, Frame, GenericImage,
+    GenericImageView, GrayImage, Luma, Rgba, RgbaImage,
+};
 use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
 use std::fs;
+use std::io::BufReader;
+use std::path::Path;
 
 /// Atari 7800 tool that generates C code for sprites described in a YAML file
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// YAML input file
-    filename: String,
+    /// YAML input files (the sprites of several files are output together, sharing identical graphics)
+    #[arg(required = true)]
+    filenames: Vec<String>,
+    /// Output basename. Writes the arrays in <OUTPUT>.c and their declarations in <OUTPUT>.h
+    #[arg(short = 'O', long)]
+    output: Option<String>,
+    /// Output the palettes as Atari 7800 color values (palette_<name> arrays)
+    #[arg(short = 'P', long)]
+    palettes: bool,
+    /// Output the sprites metadata tables (sprites_width, sprites_height, sprites_mode and sprites_palette)
+    #[arg(short = 'M', long)]
+    metadata: bool,
+    /// Output the sprites ids (SPRITE_<NAME>, in the metadata tables order) as an enum instead of defines
+    #[arg(short = 'E', long)]
+    enum_ids: bool,
+    /// Output the bounding box of each sprite (name_bbox arrays)
+    #[arg(short = 'B', long)]
+    bbox: bool,
+    /// Output a sprite_def descriptors table (gfx, width, mode, palette and holey DMA height), for multisprite.h
+    #[arg(short = 'S', long)]
+    sprite_defs: bool,
+    /// Report the holey DMA regions used in each bank
+    #[arg(short = 'H', long)]
+    holey_report: bool,
+    /// Write a PNG preview of each generated sprite (decoded back from its bytes) in this directory
+    #[arg(long)]
+    preview: Option<String>,
+    /// Report the graphics bytes used in each bank (holey DMA and plain scattered data)
+    #[arg(short = 'R', long)]
+    bank_report: bool,
+    /// Report the MARIA DMA cycles used by each sprite per scanline
+    #[arg(short = 'D', long)]
+    dma_report: bool,
+    /// Check that the listed sprites (comma separated names) use the same colors. Can be repeated
+    #[arg(short = 'C', long, value_name = "SPRITES")]
+    check_shared_palette: Vec<String>,
+    /// Output the graphics as standard row-major C arrays (not reversed nor scattered), for other toolchains
+    #[arg(long)]
+    plain: bool,
+    /// Collision maps format: bitmaps, or spans of colliding offsets per row (smaller for large sprites)
+    #[arg(long, value_enum, default_value_t = CollisionFormat::Bitmap)]
+    collision_format: CollisionFormat,
+    /// Output a collide_<sprite1>_<sprite2>(dx, dy) C function for each collision map
+    #[arg(long)]
+    collision_helpers: bool,
+    /// Issue an error for the sprites too wide for a display list entry, instead of splitting them in columns
+    #[arg(long)]
+    no_split: bool,
+    /// Output format: C code for cc7800, or assembly (dasm/ca65)
+    #[arg(short = 'f', long, value_enum, default_value_t = Format::C)]
+    format: Format,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Format {
+    C,
+    Asm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum CollisionFormat {
+    Bitmap,
+    Spans,
+}
+
+// Lookup routine of the collision maps in spans format
+const COLLISION_SPANS_TEST: &str = "
+// Returns 1 if the offset (x, y) is in one of the spans of row y of a collision map
+char collision_spans_test(const char *spans, const char *rows, char x, char y)
+{
+    char i, end = rows[y + 1];
+    for (i = rows[y]; i != end; i += 2) {
+        if (x < spans[i]) return 0;
+        if (x - spans[i] < spans[i + 1]) return 1;
+    }
+    return 0;
+}
+";
+
+// Graphics data of a MARIA zone high sprite (or part of a sprite), for the assembly output
+#[derive(Debug)]
+struct ScatteredArray {
+    name: String,
+    bank: Option<u8>,
+    height: u8,
+    holeydma: bool,
+    rows: Vec<Vec<u8>>, // From top to bottom
+}
+
+// Maximum width (in bytes) of a display list entry, as its width field is 5 bits (2's complement, 0 for 32 bytes)
+const MAX_DL_WIDTH: usize = 32;
+
+// Graphics data layout of the sprites
+enum Layout<'a> {
+    Scattered(bool), // cc7800 reversed scattered arrays (with or without a header file)
+    Plain,           // Standard row-major C arrays
+    Asm(&'a mut Vec<ScatteredArray>), // Assembly pages, laid out at the end
+}
+
+impl Layout<'_> {
+    fn reborrow(&mut self) -> Layout<'_> {
+        match self {
+            Layout::Scattered(header) => Layout::Scattered(*header),
+            Layout::Plain => Layout::Plain,
+            Layout::Asm(scattered) => Layout::Asm(scattered),
+        }
+    }
+
+    fn new(
+        asm: bool,
+        plain: bool,
+        header: bool,
+        scattered: &mut Vec<ScatteredArray>,
+    ) -> Layout<'_> {
+        if asm {
+            Layout::Asm(scattered)
+        } else if plain {
+            Layout::Plain
+        } else {
+            Layout::Scattered(header)
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -17,18 +146,40 @@ struct AllSprites {
     #[serde(default)]
     palettes: Option<Vec<Palette>>,
     sprite_sheets: Vec<SpriteSheet>,
+    order: Option<Vec<String>>, // Output order of the generated code (bank<n>, nobank or sprite sheet image)
 }
 
 #[derive(Debug, Deserialize)]
 struct SpriteSheet {
-    image: String,
+    #[serde(default)]
+    image: String, // Empty when all the sprites have their own image
+    images: Option<String>, // Per sprite images, as a path with * and ? wildcards in the file name
     #[serde(default = "default_mode")]
     mode: String,
     default_height: Option<u8>,
     holeydma: Option<u8>,
     bank: Option<u8>,
+    #[serde(default)]
     sprites: Vec<Sprite>,
     collisions: Option<Vec<Collision>>,
+    tile_collisions: Option<Vec<TileCollision>>,
+    animations: Option<Vec<Animation>>,
+    atlas: Option<String>, // TexturePacker or Aseprite JSON atlas giving the sprites rectangles
+    tolerance: Option<u32>, // Default color tolerance of the sprites
+    alpha_threshold: Option<u8>, // Default alpha threshold of the sprites
+    background: Option<(u8, u8, u8)>, // Default background color of the sprites
+    #[serde(skip)]
+    indexed_palette: Option<Vec<(u8, u8, u8)>>, // Palette of an indexed PNG image
+    #[serde(skip)]
+    indices: Option<GrayImage>, // Palette indices of the pixels of an indexed PNG image
+    dither: Option<Dither>, // Default color reduction of the sprites
+    mirror: Option<Mirror>, // Default mirrored copies of the sprites
+    color_map: Option<Vec<ColorMapping>>, // Colors slots of the sprites without palette
+    markers: Option<String>, // Image with the sprites rectangles outlined in the marker color
+    marker_color: Option<(u8, u8, u8)>, // Outlines color of the markers image (magenta by default)
+    defaults: Option<serde_yaml::Mapping>, // Attributes inherited by the sprites that don't specify them
+    #[serde(skip)]
+    file: usize,         // Index of the YAML file of the sprite sheet
 }
 
 #[derive(Debug, Deserialize)]
@@ -37,16 +188,21 @@ struct Palette {
     colors: Vec<(u8, u8, u8)>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 struct Sprite {
     name: String,
+    #[serde(default)]
+    image: Option<String>, // Own image of the sprite, instead of the sprite sheet image
+    #[serde(default)]
     top: u32,
+    #[serde(default)]
     left: u32,
-    width: u32,
-    #[serde(default = "default_sprite_size")]
-    height: u32,
+    #[serde(default)]
+    width: u32, // Defaults to the width of the own image of the sprite
+    #[serde(default)]
+    height: u32, // Defaults to 16, or to the height of the own image of the sprite
     #[serde(default = "default_holeydma")]
-    holeydma: bool,
+    holeydma: HoleyDma,
     #[serde(default)]
     palette: Option<String>,
     #[serde(default)]
@@ -54,9 +210,103 @@ struct Sprite {
     #[serde(default)]
     alias: Option<String>,
     #[serde(default)]
-    background: Option<String>,
+    background: Option<Background>,
     bank: Option<u8>,
     fake: Option<bool>,
+    #[serde(default)]
+    mirror: Option<Mirror>,
+    frames: Option<u32>,         // Number of frames of an animation strip (grid)
+    durations: Option<Vec<u8>>,  // Duration of each frame of the strip (makes it an animation)
+    step_x: Option<u32>,         // Horizontal distance between frames (defaults to width)
+    step_y: Option<u32>,         // Vertical distance between frames rows (defaults to height)
+    columns: Option<u32>,        // Number of frames per row (defaults to frames)
+    naming: Option<String>,      // Frames names pattern (defaults to {name}_{index})
+    tolerance: Option<u32>,      // Maximum distance to snap a color to the nearest palette color
+    palette_number: Option<u8>,  // Palette of the sprite in the metadata tables
+    alpha_threshold: Option<u8>, // Pixels with a lower alpha are background
+    #[serde(default)]
+    scale: Option<Scale>,
+    #[serde(default)]
+    rotate: Option<Vec<u32>>, // Rotated copies (90, 180 and/or 270 degrees clockwise)
+    #[serde(default)]
+    dither: Option<Dither>, // Reduces the sprite to the colors of its gfx mode
+    #[serde(default)]
+    mask: bool, // Generates a companion transparency mask, for software compositing
+    variant: Option<u8>, // 320D sub-mode (P1 and P0 bits of the palette number)
+    #[serde(default)]
+    overlay: bool, // Splits a 160A sprite of up to 6 colors into two overlay sprites
+    #[serde(default)]
+    padded: bool, // Keeps a short sprite at its own height, with a <name>_padded full zone variant
+    #[serde(skip)]
+    generated: bool, // Added by an atlas, markers, images or an Aseprite or animated image, not declared
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum Background {
+    Color((u8, u8, u8)),
+    Any(serde::de::IgnoredAny), // Unknown colors are considered as background
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+enum HoleyDma {
+    Enabled(bool),
+    Height(u8), // Holey DMA zone height (8 or 16), or 0 for plain scattered data
+}
+
+#[derive(Debug, Deserialize, Clone)]
+enum Dither {
+    Bayer,
+    FloydSteinberg,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+enum Scale {
+    #[serde(rename = "2x1")]
+    DoubleWidth,
+    #[serde(rename = "1x2")]
+    DoubleHeight,
+    #[serde(rename = "2x2")]
+    Double,
+}
+
+impl Scale {
+    fn factors(&self) -> (u32, u32) {
+        match self {
+            Scale::DoubleWidth => (2, 1),
+            Scale::DoubleHeight => (1, 2),
+            Scale::Double => (2, 2),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+enum Mirror {
+    Horizontal,
+    Vertical,
+    Both,
+}
+
+// Generated sprite description, for the metadata and descriptors tables
+#[derive(Debug, Clone)]
+struct SpriteInfo {
+    name: String,
+    gfx: String,  // Graphics data array
+    width: usize, // In bytes
+    height: u32,  // In pixels
+    write_mode: u8,
+    palette: u8,
+    holeydma: u8, // Holey DMA zone height (0 if none)
+    bank: Option<u8>,
+    size: usize, // Bytes of the scattered arrays (including the padding of the small sprites)
+}
+
+#[derive(Debug, Deserialize)]
+struct Animation {
+    name: String,
+    frames: Vec<String>,
+    durations: Option<Vec<u8>>, // Duration of each frame (in frames)
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -65,11 +315,30 @@ struct Collision {
     sprite2: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct ColorMapping {
+    color: (u8, u8, u8),
+    slot: u8, // 1 for C1, 2 for C2...
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct TileCollision {
+    sprite: String,
+    #[serde(default = "default_tile_size")]
+    tile_width: u32, // In pixels of the sprite gfx mode
+    #[serde(default = "default_tile_size")]
+    tile_height: u32,
+}
+
+fn default_tile_size() -> u32 {
+    8
+}
+
 fn default_sprite_size() -> u32 {
     16
 }
-fn default_holeydma() -> bool {
-    true
+fn default_holeydma() -> HoleyDma {
+    HoleyDma::Enabled(true)
 }
 fn default_mode() -> String {
     "160A".to_string()
@@ -91,52 +360,2521 @@ fn default_mode() -> String {
 // |      | P2 = X, P1 = 1, P0 = 0 => PXC1, PXC2, PXC3 with BG on the right
 // |      | P2 = X, P1 = 1, P0 = 1 => PXC1, PXC3
 
+impl Sprite {
+    fn new(name: String, left: u32, top: u32, width: u32, height: u32) -> Sprite {
+        Sprite {
+            name,
+            image: None,
+            top,
+            left,
+            width,
+            height,
+            holeydma: default_holeydma(),
+            palette: None,
+            mode: None,
+            alias: None,
+            background: None,
+            bank: None,
+            fake: None,
+            mirror: None,
+            frames: None,
+            durations: None,
+            step_x: None,
+            step_y: None,
+            columns: None,
+            naming: None,
+            tolerance: None,
+            palette_number: None,
+            alpha_threshold: None,
+            scale: None,
+            rotate: None,
+            dither: None,
+            mask: false,
+            variant: None,
+            overlay: false,
+            padded: false,
+            generated: true,
+        }
+    }
+}
+
+// Make a C identifier out of a file or tag name
+fn c_identifier(name: &str) -> String {
+    let mut v: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !v.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        v.insert(0, '_');
+    }
+    v
+}
+
+// Frames of an animation tag, in playing order
+fn tag_frames(from: u32, to: u32, reverse: bool, pingpong: bool) -> Vec<u32> {
+    let mut frames = (from..=to).collect::<Vec<_>>();
+    if reverse {
+        frames.reverse();
+    } else if pingpong && frames.len() > 2 {
+        let back = frames[1..frames.len() - 1].to_vec();
+        frames.extend(back.iter().rev());
+    }
+    frames
+}
+
+// Aseprite durations are in milliseconds. Convert them to 60Hz frames
+fn ms_to_frames(ms: u32) -> u8 {
+    ((ms * 60 + 500) / 1000).clamp(1, 255) as u8
+}
+
+// Take the sprites rectangles from a JSON atlas (TexturePacker or Aseprite export, hash or array
+// format). Sprites are matched by name (the frame file name without extension), and the frames
+// that are not declared in the YAML file are added. Aseprite tags are converted to animations
+fn load_atlas(sprite_sheet: &mut SpriteSheet, atlas: &str) -> Result<()> {
+    let contents =
+        fs::read_to_string(atlas).map_err(|e| anyhow!("Unable to read atlas {}: {}", atlas, e))?;
+    let json: serde_json::Value = serde_json::from_str(&contents)?;
+    let frames: Vec<(&str, &serde_json::Value)> = match &json["frames"] {
+        serde_json::Value::Object(m) => m.iter().map(|(k, v)| (k.as_str(), v)).collect(),
+        serde_json::Value::Array(a) => a
+            .iter()
+            .map(|v| (v["filename"].as_str().unwrap_or_default(), v))
+            .collect(),
+        _ => return Err(anyhow!("Atlas {}: no frames", atlas)),
+    };
+    let mut names = Vec::new();
+    for (filename, frame) in &frames {
+        let name = c_identifier(
+            &Path::new(filename)
+                .file_stem()
+                .map_or("".into(), |s| s.to_string_lossy()),
+        );
+        if frame["rotated"].as_bool() == Some(true) {
+            return Err(anyhow!(
+                "Atlas {}: rotated frame {} is not supported",
+                atlas,
+                filename
+            ));
+        }
+        let rect = |k: &str| -> Result<u32> {
+            frame["frame"][k]
+                .as_u64()
+                .map(|v| v as u32)
+                .ok_or_else(|| anyhow!("Atlas {}: bad frame {}", atlas, filename))
+        };
+        let (x, y, w, h) = (rect("x")?, rect("y")?, rect("w")?, rect("h")?);
+        if let Some(sprite) = sprite_sheet.sprites.iter_mut().find(|s| s.name == name) {
+            sprite.left = x;
+            sprite.top = y;
+            sprite.width = w;
+            sprite.height = h;
+        } else {
+            sprite_sheet
+                .sprites
+                .push(Sprite::new(name.clone(), x, y, w, h));
+        }
+        names.push(name);
+    }
+    for tag in json["meta"]["frameTags"].as_array().into_iter().flatten() {
+        let index = |k: &str| tag[k].as_u64().map_or(0, |v| v as u32);
+        let (from, to) = (index("from"), index("to"));
+        if to as usize >= names.len() || from > to {
+            return Err(anyhow!("Atlas {}: bad frame tag {}", atlas, tag["name"]));
+        }
+        let direction = tag["direction"].as_str().unwrap_or("forward");
+        let tframes = tag_frames(from, to, direction == "reverse", direction == "pingpong");
+        sprite_sheet
+            .animations
+            .get_or_insert_with(Vec::new)
+            .push(Animation {
+                name: c_identifier(tag["name"].as_str().unwrap_or_default()),
+                frames: tframes.iter().map(|i| names[*i as usize].clone()).collect(),
+                durations: Some(
+                    tframes
+                        .iter()
+                        .map(|i| {
+                            ms_to_frames(
+                                frames[*i as usize].1["duration"].as_u64().unwrap_or(100) as u32
+                            )
+                        })
+                        .collect(),
+                ),
+            });
+    }
+    Ok(())
+}
+
+// Sprites rectangles outlined in the marker color. A label (marker pixels one line above the top outline, n in
+// binary with the least significant bit on the left) selects the n-th declared sprite. The other rectangles go to
+// the declared sprites without label in reading order, then are added as <image>_<i> sprites
+fn load_markers(sprite_sheet: &mut SpriteSheet, markers: &str) -> Result<()> {
+    let img = image::open(markers)
+        .map_err(|e| anyhow!("Unable to open markers image {}: {}", markers, e))?
+        .to_rgb8();
+    let color = sprite_sheet.marker_color.unwrap_or((255, 0, 255));
+    let marker = |x: u32, y: u32| {
+        x < img.width() && y < img.height() && {
+            let p = img.get_pixel(x, y);
+            (p[0], p[1], p[2]) == color
+        }
+    };
+    let mut labeled = Vec::new();
+    let mut unlabeled = Vec::new();
+    for y in 0..img.height() {
+        for x in 0..img.width() {
+            // Top left corner of a rectangle (the marker pixel above it can be the lowest bit of its label)
+            if !marker(x, y)
+                || (x > 0 && marker(x - 1, y))
+                || !marker(x + 1, y)
+                || !marker(x, y + 1)
+            {
+                continue;
+            }
+            let mut right = x + 1;
+            while marker(right + 1, y) {
+                right += 1;
+            }
+            let mut bottom = y + 1;
+            while marker(x, bottom + 1) {
+                bottom += 1;
+            }
+            let closed =
+                (x..=right).all(|i| marker(i, bottom)) && (y..=bottom).all(|j| marker(right, j));
+            if !closed || right - x < 2 || bottom - y < 2 {
+                continue;
+            }
+            let rect = (x + 1, y + 1, right - x - 1, bottom - y - 1);
+            let label = if y >= 1 {
+                (0..8)
+                    .filter(|&i| marker(x + i, y - 1))
+                    .fold(0, |n, i| n | (1 << i))
+            } else {
+                0
+            };
+            if label == 0 {
+                unlabeled.push(rect);
+            } else {
+                labeled.push((label as usize, rect));
+            }
+        }
+    }
+    let mut free: Vec<bool> = vec![true; sprite_sheet.sprites.len()];
+    let set = |sprite: &mut Sprite, (x, y, w, h): (u32, u32, u32, u32)| {
+        sprite.left = x;
+        sprite.top = y;
+        sprite.width = w;
+        sprite.height = h;
+    };
+    for (label, rect) in labeled {
+        let Some(sprite) = sprite_sheet.sprites.get_mut(label - 1) else {
+            return Err(anyhow!(
+                "Markers {}: label {} of the rectangle at {},{} matches no sprite",
+                markers,
+                label,
+                rect.0 - 1,
+                rect.1 - 1
+            ));
+        };
+        if !free[label - 1] {
+            return Err(anyhow!(
+                "Markers {}: label {} of the rectangle at {},{} is already used",
+                markers,
+                label,
+                rect.0 - 1,
+                rect.1 - 1
+            ));
+        }
+        set(sprite, rect);
+        free[label - 1] = false;
+    }
+    let stem = c_identifier(
+        &Path::new(markers)
+            .file_stem()
+            .map_or("".into(), |s| s.to_string_lossy()),
+    );
+    let mut slots = (0..free.len()).filter(|&i| free[i]);
+    let mut added = 0;
+    for rect in unlabeled {
+        if let Some(s) = slots.next() {
+            set(&mut sprite_sheet.sprites[s], rect);
+        } else {
+            let mut sprite = Sprite::new(format!("{}_{}", stem, added), 0, 0, 0, 0);
+            added += 1;
+            set(&mut sprite, rect);
+            sprite_sheet.sprites.push(sprite);
+        }
+    }
+    Ok(())
+}
+
+// Files matching a path with * and ? wildcards in its file name, in alphabetical order
+fn glob_files(pattern: &str) -> Result<Vec<String>> {
+    fn matches(p: &[u8], s: &[u8]) -> bool {
+        match (p.first(), s.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => matches(&p[1..], s) || (!s.is_empty() && matches(p, &s[1..])),
+            (Some(b'?'), Some(_)) => matches(&p[1..], &s[1..]),
+            (Some(a), Some(b)) => a == b && matches(&p[1..], &s[1..]),
+            _ => false,
+        }
+    }
+    let path = Path::new(pattern);
+    let dir = path
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let filter = path
+        .file_name()
+        .map_or(String::new(), |f| f.to_string_lossy().to_string());
+    let mut files = Vec::new();
+    for entry in
+        fs::read_dir(dir).map_err(|e| anyhow!("Can't read directory {}: {}", dir.display(), e))?
+    {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if matches(filter.as_bytes(), name.as_bytes()) {
+            files.push(dir.join(name).to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Add a sprite for each image matching the images path of a sprite sheet, named after the file name without
+// extension. The declared sprites of the same name get the image
+fn load_images(sprite_sheet: &mut SpriteSheet, pattern: &str) -> Result<()> {
+    let files = glob_files(pattern)?;
+    if files.is_empty() {
+        return Err(anyhow!("No image matching {}", pattern));
+    }
+    for file in files {
+        let name = c_identifier(
+            &Path::new(&file)
+                .file_stem()
+                .map_or("".into(), |s| s.to_string_lossy()),
+        );
+        if let Some(sprite) = sprite_sheet.sprites.iter_mut().find(|s| s.name == name) {
+            sprite.image = Some(file);
+        } else {
+            let (w, h) = image::image_dimensions(&file)
+                .map_err(|e| anyhow!("Can't open image {}: {}", file, e))?;
+            let mut sprite = Sprite::new(name, 0, 0, w, h);
+            sprite.image = Some(file);
+            sprite_sheet.sprites.push(sprite);
+        }
+    }
+    Ok(())
+}
+
+// Append the own images of the sprites to the right of the sprite sheet image, so that they are converted
+// as parts of the sheet. Each image is added once, whatever the number of sprites (or frames) taken from it
+fn merge_sprite_images(sprite_sheet: &mut SpriteSheet, img: DynamicImage) -> Result<DynamicImage> {
+    let mut images = Vec::<(String, RgbaImage, u32)>::new();
+    let mut width = img.width();
+    for sprite in &mut sprite_sheet.sprites {
+        if let Some(file) = &sprite.image {
+            let x = if let Some((_, _, x)) = images.iter().find(|(f, _, _)| f == file) {
+                *x
+            } else {
+                let image = image::open(file)
+                    .map_err(|e| anyhow!("Can't open image {}: {}", file, e))?
+                    .to_rgba8();
+                let x = width;
+                width += image.width();
+                images.push((file.clone(), image, x));
+                x
+            };
+            let image = &images.iter().find(|(f, _, _)| f == file).unwrap().1;
+            if sprite.width == 0 {
+                sprite.width = image.width().saturating_sub(sprite.left);
+            }
+            if sprite.height == 0 {
+                sprite.height = image.height().saturating_sub(sprite.top);
+            }
+            if sprite.left + sprite.width > image.width()
+                || sprite.top + sprite.height > image.height()
+            {
+                return Err(anyhow!(
+                    "Sprite {}: out of image {} ({}x{})",
+                    sprite.name,
+                    file,
+                    image.width(),
+                    image.height()
+                ));
+            }
+            sprite.left += x;
+        } else if sprite.alias.is_none() {
+            if sprite_sheet.image.is_empty() {
+                return Err(anyhow!(
+                    "Sprite {}: no image (neither in the sprite nor in the sprite sheet)",
+                    sprite.name
+                ));
+            }
+            if sprite.width == 0 {
+                return Err(anyhow!("Sprite {}: width is missing", sprite.name));
+            }
+        }
+    }
+    if images.is_empty() {
+        return Ok(img);
+    }
+    let height = images
+        .iter()
+        .map(|(_, i, _)| i.height())
+        .max()
+        .unwrap_or(0)
+        .max(img.height());
+    let mut merged = RgbaImage::new(width, height);
+    imageops::replace(&mut merged, &img.to_rgba8(), 0, 0);
+    for (_, image, x) in &images {
+        imageops::replace(&mut merged, image, *x as i64, 0);
+    }
+    Ok(DynamicImage::ImageRgba8(merged))
+}
+
+// Load an Aseprite file as a strip of its frames. When no sprite is declared, each frame becomes
+// a <file>_<n> sprite, its tags become animations and its embedded palette the <file> palette
+fn load_aseprite(
+    sprite_sheet: &mut SpriteSheet,
+    palettes: &mut Vec<Palette>,
+) -> Result<DynamicImage> {
+    let path = Path::new(&sprite_sheet.image);
+    let ase = AsepriteFile::read_file(path)
+        .map_err(|e| anyhow!("Can't open Aseprite file {}: {}", sprite_sheet.image, e))?;
+    let (w, h) = (ase.width() as u32, ase.height() as u32);
+    let mut strip = RgbaImage::new(w * ase.num_frames(), h);
+    for i in 0..ase.num_frames() {
+        let frame = RgbaImage::from_raw(w, h, ase.frame(i).image().into_raw()).unwrap();
+        imageops::replace(&mut strip, &frame, (i * w) as i64, 0);
+    }
+    if sprite_sheet.sprites.is_empty() {
+        let prefix = c_identifier(
+            &path
+                .file_stem()
+                .map_or("sprite".into(), |s| s.to_string_lossy()),
+        );
+        // The colors of the sprites are the palette entries following the transparent color
+        let palette = ase
+            .palette()
+            .filter(|p| p.num_colors() > 1)
+            .map(|p| Palette {
+                name: prefix.clone(),
+                colors: (1..p.num_colors().min(13))
+                    .filter_map(|c| p.color(c))
+                    .map(|c| (c.red(), c.green(), c.blue()))
+                    .collect(),
+            });
+        for i in 0..ase.num_frames() {
+            let mut sprite = Sprite::new(format!("{prefix}_{i}"), i * w, 0, w, h);
+            sprite.palette = palette.as_ref().map(|p| p.name.clone());
+            sprite_sheet.sprites.push(sprite);
+        }
+        let animations = sprite_sheet.animations.get_or_insert_with(Vec::new);
+        for t in 0..ase.num_tags() {
+            let tag = ase.tag(t);
+            let frames = tag_frames(
+                tag.from_frame(),
+                tag.to_frame(),
+                tag.animation_direction() == AnimationDirection::Reverse,
+                tag.animation_direction() == AnimationDirection::PingPong,
+            );
+            animations.push(Animation {
+                name: c_identifier(tag.name()),
+                frames: frames.iter().map(|i| format!("{prefix}_{i}")).collect(),
+                durations: Some(
+                    frames
+                        .iter()
+                        .map(|i| ms_to_frames(ase.frame(*i).duration()))
+                        .collect(),
+                ),
+            });
+        }
+        palettes.extend(palette);
+    }
+    Ok(DynamicImage::ImageRgba8(strip))
+}
+
+// Load an animated GIF or APNG image as a strip of its frames (None if it is not animated). When no sprite
+// is declared, each frame becomes a <file>_<n> sprite, and the frames make the <file> animation
+fn load_animation(sprite_sheet: &mut SpriteSheet) -> Result<Option<DynamicImage>> {
+    let path = Path::new(&sprite_sheet.image);
+    let extension = path
+        .extension()
+        .map_or(String::new(), |e| e.to_string_lossy().to_lowercase());
+    let open = || -> Result<BufReader<fs::File>> {
+        fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| anyhow!("Can't open image {}: {}", sprite_sheet.image, e))
+    };
+    let frames: Vec<Frame> = match extension.as_str() {
+        "gif" => GifDecoder::new(open()?)?.into_frames().collect_frames()?,
+        "png" => {
+            let decoder = PngDecoder::new(open()?)?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.apng()?.into_frames().collect_frames()?
+        }
+        _ => return Ok(None),
+    };
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    let (w, h) = frames[0].buffer().dimensions();
+    let mut strip = RgbaImage::new(w * frames.len() as u32, h);
+    for (i, frame) in frames.iter().enumerate() {
+        imageops::replace(&mut strip, frame.buffer(), (i as u32 * w) as i64, 0);
+    }
+    if sprite_sheet.sprites.is_empty() {
+        let prefix = c_identifier(
+            &path
+                .file_stem()
+                .map_or("sprite".into(), |s| s.to_string_lossy()),
+        );
+        for i in 0..frames.len() as u32 {
+            sprite_sheet
+                .sprites
+                .push(Sprite::new(format!("{prefix}_{i}"), i * w, 0, w, h));
+        }
+        sprite_sheet
+            .animations
+            .get_or_insert_with(Vec::new)
+            .push(Animation {
+                name: prefix.clone(),
+                frames: (0..frames.len()).map(|i| format!("{prefix}_{i}")).collect(),
+                durations: Some(
+                    frames
+                        .iter()
+                        .map(|f| {
+                            let (numer, denom) = f.delay().numer_denom_ms();
+                            ms_to_frames(numer / denom.max(1))
+                        })
+                        .collect(),
+                ),
+            });
+    }
+    Ok(Some(DynamicImage::ImageRgba8(strip)))
+}
+
+// Name of the frame i of an animation strip, from a pattern with {name}, {index}, {row} and {col} placeholders
+fn frame_name(pattern: &str, name: &str, i: u32, columns: u32) -> String {
+    pattern
+        .replace("{name}", name)
+        .replace("{index}", &i.to_string())
+        .replace("{row}", &(i / columns).to_string())
+        .replace("{col}", &(i % columns).to_string())
+}
+
+// Expand the animation strips (sprites with frames) into name_0, name_1... sprites. The strips with
+// durations are also added to the animations
+fn expand_frames(
+    sprites: Vec<Sprite>,
+    animations: &mut Option<Vec<Animation>>,
+) -> Result<Vec<Sprite>> {
+    let mut expanded = Vec::with_capacity(sprites.len());
+    for sprite in sprites {
+        if let Some(frames) = sprite.frames {
+            let columns = sprite.columns.unwrap_or(frames);
+            if columns == 0 {
+                return Err(anyhow!("Sprite {}: columns should not be 0", sprite.name));
+            }
+            if sprite.width == 0 {
+                // The width of an own image is only known later, and would be the width of the whole strip
+                return Err(anyhow!(
+                    "Sprite {}: width of the frames is missing",
+                    sprite.name
+                ));
+            }
+            if sprite.height == 0 && frames > columns {
+                return Err(anyhow!(
+                    "Sprite {}: height of the frames is missing",
+                    sprite.name
+                ));
+            }
+            let pattern = sprite.naming.as_deref().unwrap_or("{name}_{index}");
+            let names = (0..frames)
+                .map(|i| frame_name(pattern, &sprite.name, i, columns))
+                .collect::<Vec<_>>();
+            if !["{index}", "{row}", "{col}"]
+                .iter()
+                .any(|p| pattern.contains(p))
+                && frames > 1
+            {
+                return Err(anyhow!(
+                    "Sprite {}: naming pattern {} should contain {{index}}, {{row}} or {{col}}",
+                    sprite.name,
+                    pattern
+                ));
+            }
+            if let Some(durations) = &sprite.durations {
+                if durations.len() != frames as usize {
+                    return Err(anyhow!(
+                        "Sprite {}: {} durations for {} frames",
+                        sprite.name,
+                        durations.len(),
+                        frames
+                    ));
+                }
+                animations.get_or_insert_with(Vec::new).push(Animation {
+                    name: sprite.name.clone(),
+                    frames: names.clone(),
+                    durations: Some(durations.clone()),
+                });
+            }
+            let step_x = sprite.step_x.unwrap_or(sprite.width);
+            let step_y = sprite.step_y.unwrap_or(sprite.height);
+            for (i, name) in (0..frames).zip(names) {
+                let mut frame = sprite.clone();
+                frame.name = name;
+                frame.left = sprite.left + (i % columns) * step_x;
+                frame.top = sprite.top + (i / columns) * step_y;
+                frame.frames = None;
+                frame.durations = None;
+                frame.naming = None;
+                expanded.push(frame);
+            }
+        } else if sprite.durations.is_some() {
+            return Err(anyhow!(
+                "Sprite {}: durations are only allowed with frames",
+                sprite.name
+            ));
+        } else {
+            expanded.push(sprite);
+        }
+    }
+    Ok(expanded)
+}
+
+// Overlay sprite, with its image and its colors
+type Layer = (Sprite, DynamicImage, [(u8, u8, u8); 12]);
+
+// Split a 160A sprite of up to 6 colors into two overlay sprites of 3 colors (<name>_layer0 and <name>_layer1),
+// to be displayed at the same position with different palettes (or alternately, with 30Hz flicker). The colors
+// are taken from the sprite palette (C1-C3 for the first layer, C4-C6 for the second one), or else in order of
+// appearance
+fn overlay_layers(
+    img: &DynamicImage,
+    sprite: &Sprite,
+    palettes: &Option<Vec<Palette>>,
+) -> Result<Vec<Layer>> {
+    let background = background_color(sprite);
+    let excerpt = img
+        .crop_imm(sprite.left, sprite.top, sprite.width, sprite.height)
+        .to_rgba8();
+    let mut colors = Vec::<(u8, u8, u8)>::new();
+    if let Some(pname) = &sprite.palette {
+        let p = palettes
+            .iter()
+            .flatten()
+            .find(|p| &p.name == pname)
+            .ok_or_else(|| anyhow!("Unknown palette {}", pname))?;
+        colors = p.colors.clone();
+    }
+    for color in excerpt.pixels() {
+        let rgb = (color[0], color[1], color[2]);
+        if !is_background(color, background) && !colors.contains(&rgb) {
+            colors.push(rgb);
+        }
+    }
+    if colors.len() > 6 {
+        return Err(anyhow!(
+            "Sprite {}: {} colors, more than the 6 colors of two overlay sprites",
+            sprite.name,
+            colors.len()
+        ));
+    }
+    let mut layers = Vec::new();
+    for l in 0..2 {
+        let layer_colors: Vec<(u8, u8, u8)> = colors.iter().skip(l * 3).take(3).cloned().collect();
+        let mut pixels = excerpt.clone();
+        for color in pixels.pixels_mut() {
+            if !layer_colors.contains(&(color[0], color[1], color[2])) {
+                *color = Rgba([background.0, background.1, background.2, 0]);
+            }
+        }
+        let mut layer = sprite.clone();
+        layer.name = format!("{}_layer{}", sprite.name, l);
+        layer.left = 0;
+        layer.top = 0;
+        layer.palette = None;
+        let mut slots = [(0u8, 0u8, 0u8); 12];
+        for (i, c) in layer_colors.iter().enumerate() {
+            slots[i] = *c;
+        }
+        layers.push((layer, DynamicImage::ImageRgba8(pixels), slots));
+    }
+    Ok(layers)
+}
+
+// Palette bit (P1 for the even pixels, P0 for the odd ones) completing the data bit of a 320D pixel
+fn variant_bit(variant: u8, x: u32) -> u8 {
+    if x & 1 == 0 {
+        (variant >> 1) & 1
+    } else {
+        variant & 1
+    }
+}
+
+// 320D variant of the horizontally mirrored copy of a sprite: when it is an even number of pixels wide,
+// its even pixels are the odd ones of the sprite, so that the P1 and P0 bits are swapped
+fn mirrored_variant(variant: Option<u8>, columns: u32) -> Option<u8> {
+    variant.map(|v| {
+        if columns.is_multiple_of(2) {
+            ((v & 1) << 1) | (v >> 1)
+        } else {
+            v
+        }
+    })
+}
+
+// Convert the pixels of a sprite to graphics bytes, optionally mirrored. The colors that are not
+// found in the palette are affected to the free entries of colors, unless the palette indices of
+// an indexed PNG image are given
+#[allow(clippy::too_many_arguments)]
+fn sprite_bytes(
+    img: &DynamicImage,
+    indices: Option<&GrayImage>,
+    sprite: &Sprite,
+    mode: &str,
+    colors: &mut [(u8, u8, u8); 12],
+    maxcolors: usize,
+    hflip: bool,
+    vflip: bool,
+    comment: &str,
+) -> Result<Vec<u8>> {
+    let pixel_width = match mode {
+        "320A" | "320B" | "320C" | "320D" => 1,
+        _ => 2,
+    };
+    let pixel_bits = match mode {
+        "320A" | "320D" => 1,
+        "160B" => 4,
+        _ => 2,
+    };
+    // A last single column makes a whole pixel
+    let columns = sprite.width.div_ceil(pixel_width);
+    // Position in the image of the pixel (x, y) of the (mirrored) sprite
+    let sx = |x: u32| sprite.left + if hflip { columns - 1 - x } else { x } * pixel_width;
+    let sy = |y: u32| sprite.top + if vflip { sprite.height - 1 - y } else { y };
+    let pixel = |x: u32, y: u32| img.get_pixel(sx(x), sy(y));
+    let size = (columns, sprite.height);
+    // The pixels parity is the one of the emitted (mirrored) sprite
+    let variant = if hflip {
+        mirrored_variant(sprite.variant, columns)
+    } else {
+        sprite.variant
+    };
+    let background = background_color(sprite);
+    // In 320C mode, the next pixel should be background or of the same color
+    let pair_conflict = |x: u32, y: u32, color: Rgba<u8>| {
+        if x & 1 == 0 && x + 1 < size.0 {
+            let colorr = pixel(x + 1, y);
+            if !is_background(&colorr, background) && colorr != color {
+                return Some(colorr);
+            }
+        }
+        None
+    };
+
+    let mut remapped = Vec::<((u8, u8, u8), (u8, u8, u8))>::new();
+    let mut bytes = Vec::<u8>::new();
+    let mut current_byte: u8 = 0;
+    let mut current_bits: u8 = 0;
+    for y in 0..sprite.height {
+        for x in 0..columns {
+            let color = pixel(x, y);
+            let mut cx: Option<u8> = None;
+            let mut conflict = None; // 320C next pixel of another color
+            let mut fatal = false;
+            if let Some(indices) = indices {
+                // The palette index is the color number, index 0 being the background
+                let index = indices.get_pixel(sx(x), sy(y))[0];
+                if index as usize > maxcolors {
+                    print_excerpt(
+                        &pixel,
+                        size,
+                        &colors[0..maxcolors],
+                        background,
+                        &[(x, y)],
+                        comment,
+                    );
+                    return Err(anyhow!(
+                        "Sprite {}: palette index {} at {},{} is beyond the {} colors of {} mode",
+                        sprite.name,
+                        index,
+                        sx(x),
+                        sy(y),
+                        maxcolors,
+                        mode
+                    ));
+                }
+                cx = Some(index);
+                if mode == "320C" && index != 0 && x & 1 == 0 && x + 1 < size.0 {
+                    let next = indices.get_pixel(sx(x + 1), sy(y))[0];
+                    if next != 0 && next != index {
+                        conflict = Some(pixel(x + 1, y));
+                    }
+                }
+            }
+            // In case of defined palette, priority is to find the color in the palette, so that black is not considered as a background color
+            else if (color[3] != 0 && sprite.palette.is_some())
+                || (sprite.palette.is_none() && (color[0], color[1], color[2]) != background)
+            {
+                // Not transparent
+                for (c, pc) in colors.iter().enumerate().take(maxcolors) {
+                    if color[0] == pc.0 && color[1] == pc.1 && color[2] == pc.2 {
+                        // Ok. this is a pixel of color c
+                        cx = Some((c + 1) as u8);
+                        // 320C mode contraint check
+                        if mode == "320C" {
+                            conflict = pair_conflict(x, y, color);
+                        }
+                        break;
+                    }
+                }
+            }
+            if cx.is_none() {
+                if is_background(&color, background) {
+                    cx = Some(0); // Background color (either black or transparent)
+                } else if let Some(tolerance) = sprite.tolerance {
+                    // Snap the color to the nearest palette color, if close enough
+                    let rgb = (color[0], color[1], color[2]);
+                    let nearest = colors
+                        .iter()
+                        .enumerate()
+                        .take(maxcolors)
+                        .filter(|(_, pc)| pc.0 != 0 || pc.1 != 0 || pc.2 != 0)
+                        .map(|(c, pc)| (c, pc, color_distance(&rgb, pc)))
+                        .min_by_key(|(_, _, d)| *d);
+                    if let Some((c, pc, d)) = nearest {
+                        if d <= tolerance {
+                            cx = Some((c + 1) as u8);
+                            if !remapped.iter().any(|(from, _)| *from == rgb) {
+                                remapped.push((rgb, *pc));
+                            }
+                        }
+                    }
+                }
+                if cx.is_none() {
+                    // Let's find a unaffected color
+                    for (c, pc) in colors.iter_mut().enumerate().take(maxcolors) {
+                        // In 320D variants, C2 is only available to the pixels of palette bit 0, C1 and C3 to the others
+                        let allowed = variant.is_none_or(|v| (c == 1) == (variant_bit(v, x) == 0));
+                        if allowed && pc.0 == 0 && pc.1 == 0 && pc.2 == 0 {
+                            *pc = (color[0], color[1], color[2]);
+                            cx = Some((c + 1) as u8);
+                            //println!("color {c} affected to {:?}", color);
+                            if mode == "320C" {
+                                conflict = pair_conflict(x, y, color);
+                                fatal = true;
+                            }
+                            break;
+                        }
+                    }
+                    if cx.is_none() {
+                        if let Some(Background::Any(_)) = sprite.background {
+                            // If a background is specified
+                            cx = Some(0); // This unknown color is affected to background
+                        } else {
+                            println!(
+                                "{} Unexpected color {:?} found at {},{}",
+                                comment,
+                                color,
+                                sx(x),
+                                sy(y)
+                            );
+                            print_excerpt(
+                                &pixel,
+                                size,
+                                &colors[0..maxcolors],
+                                background,
+                                &[(x, y)],
+                                comment,
+                            );
+                            let rgb = (color[0], color[1], color[2]);
+                            if let Some((pc, d)) = colors
+                                .iter()
+                                .take(maxcolors)
+                                .map(|pc| (pc, color_distance(&rgb, pc)))
+                                .min_by_key(|(_, d)| *d)
+                            {
+                                println!("{} Nearest sprite color is {:?} (it can be snapped to it with tolerance: {})", comment, pc, d);
+                            }
+                            return Err(anyhow!(
+                                "Sprite {} has more than {} colors",
+                                sprite.name,
+                                maxcolors
+                            ));
+                        }
+                    }
+                }
+            }
+            if let Some(colorr) = conflict {
+                // Check next pixel, should be background or same color
+                let message = format!("Sprite {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", sprite.name, x, y, color, colorr);
+                if !fatal {
+                    println!("{} Warning: {}", comment, message);
+                }
+                print_excerpt(
+                    &pixel,
+                    size,
+                    &colors[0..maxcolors],
+                    background,
+                    &[(x, y), (x + 1, y)],
+                    comment,
+                );
+                let rgb = (colorr[0], colorr[1], colorr[2]);
+                let same = (color[0], color[1], color[2]);
+                if color_distance(&rgb, &same) <= color_distance(&rgb, &background) {
+                    println!(
+                        "{} Suggestion: use color {:?} for pixel ({}, {})",
+                        comment,
+                        same,
+                        x + 1,
+                        y
+                    );
+                } else {
+                    println!(
+                        "{} Suggestion: use the background for pixel ({}, {})",
+                        comment,
+                        x + 1,
+                        y
+                    );
+                }
+                if fatal {
+                    return Err(anyhow!(message));
+                }
+            }
+            if let (true, Some(v)) = (mode == "320D", variant) {
+                // The pixel color is made of its data bit and of a palette bit: background or C2 for
+                // palette bit 0, C1 or C3 for palette bit 1
+                let p = variant_bit(v, x);
+                let c = cx.unwrap();
+                if c & 1 != p {
+                    print_excerpt(
+                        &pixel,
+                        size,
+                        &colors[0..maxcolors],
+                        background,
+                        &[(x, y)],
+                        comment,
+                    );
+                    return Err(anyhow!(
+                        "Sprite {}: color {:?} at ({}, {}) is not allowed in 320D variant {} (this pixel can only be {})",
+                        sprite.name,
+                        color,
+                        x,
+                        y,
+                        v,
+                        if p == 0 { "background or C2" } else { "C1 or C3" }
+                    ));
+                }
+                cx = Some(c >> 1);
+            }
+            match mode {
+                "160A" | "320A" | "320D" => {
+                    current_byte |= cx.unwrap();
+                    current_bits += pixel_bits;
+                    if current_bits == 8 {
+                        bytes.push(current_byte);
+                        current_byte = 0;
+                        current_bits = 0;
+                    } else {
+                        current_byte <<= pixel_bits;
+                    };
+                }
+                "160B" => {
+                    let c = match cx.unwrap() {
+                        0 => 0,
+                        1 => 1,
+                        2 => 2,
+                        3 => 3,
+                        4 => 5,
+                        5 => 6,
+                        6 => 7,
+                        7 => 9,
+                        8 => 10,
+                        9 => 11,
+                        10 => 13,
+                        11 => 14,
+                        12 => 15,
+                        _ => 0,
+                    };
+                    current_byte |= (if c & 1 != 0 { 16 } else { 0 })
+                        | (if c & 2 != 0 { 32 } else { 0 })
+                        | (if c & 4 != 0 { 1 } else { 0 })
+                        | (if c & 8 != 0 { 2 } else { 0 });
+                    current_bits += 1;
+                    if current_bits == 2 {
+                        bytes.push(current_byte);
+                        current_byte = 0;
+                        current_bits = 0;
+                    } else {
+                        current_byte <<= 2;
+                    };
+                }
+                "320B" => {
+                    let c = cx.unwrap();
+                    current_byte |=
+                        (if c & 1 != 0 { 1 } else { 0 }) | (if c & 2 != 0 { 16 } else { 0 });
+                    current_bits += 1;
+                    if current_bits == 4 {
+                        bytes.push(current_byte);
+                        current_byte = 0;
+                        current_bits = 0;
+                    } else {
+                        current_byte <<= 1;
+                    };
+                }
+                "320C" => {
+                    let c = cx.unwrap();
+                    //println!("Color: {}", c);
+                    if c != 0 {
+                        current_byte |= 1 << (7 - current_bits);
+                        if current_bits < 2 {
+                            current_byte |= (c - 1) << 2;
+                        } else {
+                            current_byte |= c - 1;
+                        }
+                    }
+                    current_bits += 1;
+                    if current_bits == 4 {
+                        bytes.push(current_byte);
+                        current_byte = 0;
+                        current_bits = 0;
+                    }
+                }
+                _ => unreachable!(),
+            };
+        }
+        // If the bytes are not complete, fill with void
+        while current_bits != 0 {
+            match mode {
+                "160A" | "320A" | "320D" => {
+                    current_bits += pixel_bits;
+                    if current_bits == 8 {
+                        bytes.push(current_byte);
+                        current_bits = 0;
+                    } else {
+                        current_byte <<= pixel_bits;
+                    };
+                }
+                "160B" => {
+                    current_bits += 1;
+                    if current_bits == 2 {
+                        bytes.push(current_byte);
+                        current_bits = 0;
+                    } else {
+                        current_byte <<= 2;
+                    };
+                }
+                "320B" => {
+                    current_bits += 1;
+                    if current_bits == 4 {
+                        bytes.push(current_byte);
+                        current_bits = 0;
+                    } else {
+                        current_byte <<= 1;
+                    };
+                }
+                "320C" => {
+                    bytes.push(current_byte);
+                    current_bits = 0;
+                }
+                _ => unreachable!(),
+            };
+        }
+    }
+    if !hflip && !vflip {
+        for (from, to) in remapped {
+            println!(
+                "{} Sprite {}: color {:?} remapped to {:?}",
+                comment, sprite.name, from, to
+            );
+        }
+    }
+    Ok(bytes)
+}
+
+// Warn about the sprites widths that don't make whole pixels or bytes in their gfx mode
+fn check_width(sprite: &Sprite, mode: &str, comment: &str) {
+    let (pixel_width, pixels_per_byte) = match mode {
+        "320A" | "320D" => (1, 8),
+        "320B" | "320C" => (1, 4),
+        "160B" => (2, 2),
+        _ => (2, 4),
+    };
+    if !sprite.width.is_multiple_of(pixel_width) {
+        println!(
+            "{} Warning: Sprite {}: width {} is not a multiple of {} (the {} mode pixel width). The last column makes a whole pixel",
+            comment, sprite.name, sprite.width, pixel_width, mode
+        );
+    }
+    let pixels = sprite.width.div_ceil(pixel_width);
+    if !pixels.is_multiple_of(pixels_per_byte) {
+        println!(
+            "{} Warning: Sprite {}: {} pixels wide, which is not a multiple of {} (pixels per byte in {} mode). The last byte is padded with background",
+            comment, sprite.name, pixels, pixels_per_byte, mode
+        );
+    }
+}
+
+// Print the area of a sprite around the first marked pixel, to locate the gfx mode constraints violations
+fn print_excerpt(
+    pixel: &dyn Fn(u32, u32) -> Rgba<u8>,
+    size: (u32, u32),
+    colors: &[(u8, u8, u8)],
+    background: (u8, u8, u8),
+    marks: &[(u32, u32)],
+    comment: &str,
+) {
+    let (mx, my) = marks[0];
+    let xs = mx.saturating_sub(6)..(mx + 8).min(size.0);
+    let ys = my.saturating_sub(2)..(my + 3).min(size.1);
+    println!("{} (. is background, 1-9 and a-c are the sprite colors, ? is another color, [ ] marks the faulty pixels)", comment);
+    let mut line = format!("{}       ", comment);
+    for x in xs.clone() {
+        line.push_str(&format!("{:^3}", x % 100));
+    }
+    println!("{}", line.trim_end());
+    for y in ys {
+        let mut line = format!("{} y={:<3} ", comment, y);
+        for x in xs.clone() {
+            let color = pixel(x, y);
+            let rgb = (color[0], color[1], color[2]);
+            let c = if is_background(&color, background) {
+                '.'
+            } else if let Some(i) = colors.iter().position(|c| *c == rgb) {
+                char::from(b"123456789abc"[i])
+            } else {
+                '?'
+            };
+            if marks.contains(&(x, y)) {
+                line.push_str(&format!("[{}]", c));
+            } else {
+                line.push_str(&format!(" {} ", c));
+            }
+        }
+        println!("{}", line.trim_end());
+    }
+}
+
+// Decode the bytes of a sprite back to an image, as displayed by MARIA (160 pixels are 2 pixels wide)
+fn decode_sprite(
+    bytes: &[u8],
+    mode: &str,
+    variant: Option<u8>,
+    height: u32,
+    colors: &[(u8, u8, u8); 12],
+) -> RgbaImage {
+    let pixels_per_byte = match mode {
+        "320A" | "320D" => 8,
+        "160B" => 2,
+        _ => 4,
+    };
+    let pixel_width = match mode {
+        "320A" | "320B" | "320C" | "320D" => 1,
+        _ => 2,
+    };
+    let width = (bytes.len() as u32 / height) * pixels_per_byte;
+    let mut img = RgbaImage::new(width * pixel_width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let b = bytes[(y * width / pixels_per_byte + x / pixels_per_byte) as usize];
+            let i = x % pixels_per_byte;
+            let cx = match mode {
+                "160A" => (b >> (6 - 2 * i)) & 3,
+                "320D" if variant.is_some() => {
+                    (((b >> (7 - i)) & 1) << 1) | variant_bit(variant.unwrap(), x)
+                }
+                "320A" | "320D" => (b >> (7 - i)) & 1,
+                "160B" => {
+                    let v = b >> (2 * (1 - i));
+                    let c = ((v >> 4) & 1)
+                        | (((v >> 5) & 1) << 1)
+                        | ((v & 1) << 2)
+                        | (((v >> 1) & 1) << 3);
+                    // Colors 4, 8 and 12 are background
+                    if c & 3 == 0 {
+                        0
+                    } else {
+                        (c >> 2) * 3 + (c & 3)
+                    }
+                }
+                "320B" => ((b >> (3 - i)) & 1) | (((b >> (7 - i)) & 1) << 1),
+                "320C" => {
+                    if (b >> (7 - i)) & 1 != 0 {
+                        (if i < 2 { (b >> 2) & 3 } else { b & 3 }) + 1
+                    } else {
+                        0
+                    }
+                }
+                _ => unreachable!(),
+            };
+            if cx != 0 {
+                let c = colors[cx as usize - 1];
+                for px in 0..pixel_width {
+                    img.put_pixel(x * pixel_width + px, y, Rgba([c.0, c.1, c.2, 255]));
+                }
+            }
+        }
+    }
+    img
+}
+
+fn write_preview(
+    dir: &str,
+    name: &str,
+    bytes: &[u8],
+    mode: &str,
+    variant: Option<u8>,
+    height: u32,
+    colors: &[(u8, u8, u8); 12],
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    decode_sprite(bytes, mode, variant, height, colors)
+        .save(Path::new(dir).join(format!("{name}.png")))?;
+    Ok(())
+}
+
+fn pixel_width(mode: &str) -> u32 {
+    match mode {
+        "320A" | "320B" | "320C" | "320D" => 1,
+        _ => 2,
+    }
+}
+
+// Representative colors of a sprite (median cut quantization)
+fn main_colors(img: &DynamicImage, sprite: &Sprite, mode: &str, n: usize) -> Vec<(u8, u8, u8)> {
+    let pw = pixel_width(mode);
+    let background = background_color(sprite);
+    let mut pixels = Vec::<[u8; 3]>::new();
+    for y in 0..sprite.height {
+        for x in 0..sprite.width / pw {
+            let color = img.get_pixel(sprite.left + x * pw, sprite.top + y);
+            if !is_background(&color, background) {
+                pixels.push([color[0], color[1], color[2]]);
+            }
+        }
+    }
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+    // Widest channel of a box of colors, and its range
+    let widest = |b: &[[u8; 3]]| {
+        (0..3)
+            .map(|i| {
+                let min = b.iter().map(|c| c[i]).min().unwrap_or(0);
+                let max = b.iter().map(|c| c[i]).max().unwrap_or(0);
+                (i, max - min)
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
+    };
+    let mut boxes = vec![pixels];
+    while boxes.len() < n {
+        let (bi, (channel, range)) = boxes
+            .iter()
+            .enumerate()
+            .map(|(bi, b)| (bi, widest(b)))
+            .max_by_key(|(_, (_, range))| *range)
+            .unwrap();
+        if range == 0 {
+            break; // Less colors than n
+        }
+        let mut b = boxes.swap_remove(bi);
+        b.sort_by_key(|c| c[channel]);
+        let other = b.split_off(b.len() / 2);
+        boxes.push(b);
+        boxes.push(other);
+    }
+    boxes
+        .iter()
+        .map(|b| {
+            let sum = |i: usize| b.iter().map(|c| c[i] as usize).sum::<usize>() / b.len();
+            (sum(0) as u8, sum(1) as u8, sum(2) as u8)
+        })
+        .collect()
+}
+
+// Reduce the colors of a sprite to the target colors, with ordered (Bayer) or error diffusion (Floyd-Steinberg)
+// dithering. Background pixels are left untouched.
+fn dither_sprite(
+    img: &mut DynamicImage,
+    sprite: &Sprite,
+    mode: &str,
+    targets: &[(u8, u8, u8)],
+    method: &Dither,
+) {
+    const BAYER: [[i32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    let pw = pixel_width(mode);
+    let background = background_color(sprite);
+    let (w, h) = ((sprite.width / pw) as usize, sprite.height as usize);
+    let pos = |x: usize, y: usize| (sprite.left + x as u32 * pw, sprite.top + y as u32);
+    let mut opaque = vec![false; w * h];
+    let mut pixels = vec![[0i32; 3]; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let (px, py) = pos(x, y);
+            let color = img.get_pixel(px, py);
+            opaque[x + y * w] = !is_background(&color, background);
+            pixels[x + y * w] = [color[0] as i32, color[1] as i32, color[2] as i32];
+        }
+    }
+    let nearest = |c: [i32; 3]| {
+        let c = (
+            c[0].clamp(0, 255) as u8,
+            c[1].clamp(0, 255) as u8,
+            c[2].clamp(0, 255) as u8,
+        );
+        *targets
+            .iter()
+            .min_by_key(|t| color_distance(&c, t))
+            .unwrap_or(&c)
+    };
+    for y in 0..h {
+        for x in 0..w {
+            if !opaque[x + y * w] {
+                continue;
+            }
+            let old = pixels[x + y * w];
+            let new = match method {
+                Dither::Bayer => {
+                    let offset = (BAYER[y % 4][x % 4] * 2 - 15) * 2; // -30..30
+                    nearest([old[0] + offset, old[1] + offset, old[2] + offset])
+                }
+                Dither::FloydSteinberg => {
+                    let new = nearest(old);
+                    let err = [
+                        old[0] - new.0 as i32,
+                        old[1] - new.1 as i32,
+                        old[2] - new.2 as i32,
+                    ];
+                    let neighbours = [(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)];
+                    for (dx, dy, weight) in neighbours {
+                        let (nx, ny) = (x as i32 + dx, y + dy as usize);
+                        if nx >= 0 && (nx as usize) < w && ny < h && opaque[nx as usize + ny * w] {
+                            let p = &mut pixels[nx as usize + ny * w];
+                            for i in 0..3 {
+                                p[i] += err[i] * weight / 16;
+                            }
+                        }
+                    }
+                    new
+                }
+            };
+            let (px, py) = pos(x, y);
+            for i in 0..pw {
+                img.put_pixel(px + i, py, Rgba([new.0, new.1, new.2, 255]));
+            }
+        }
+    }
+}
+
+type Indexed = (Vec<(u8, u8, u8)>, GrayImage);
+
+// Palette and pixels palette indices of a PNG image, if it is a (non animated) indexed one
+fn png_indices(filename: &str) -> Result<Option<Indexed>> {
+    let mut decoder = png::Decoder::new(fs::File::open(filename)?);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed || info.animation_control.is_some() {
+        return Ok(None);
+    }
+    let Some(palette) = info
+        .palette
+        .as_ref()
+        .map(|p| p.chunks(3).map(|c| (c[0], c[1], c[2])).collect())
+    else {
+        return Ok(None);
+    };
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buf)?;
+    // Indices of less than 8 bits are packed, leftmost pixel in the high bits
+    let bits = frame.bit_depth as usize;
+    let mut indices = GrayImage::new(frame.width, frame.height);
+    for (y, line) in buf
+        .chunks(frame.line_size)
+        .take(frame.height as usize)
+        .enumerate()
+    {
+        for x in 0..frame.width as usize {
+            let shift = 8 - bits - (x * bits) % 8;
+            let index = (line[x * bits / 8] >> shift) & ((1u16 << bits) - 1) as u8;
+            indices.put_pixel(x as u32, y as u32, Luma([index]));
+        }
+    }
+    Ok(Some((palette, indices)))
+}
+
+// Make the semi-transparent pixels (anti-aliased edges) of a sprite with an alpha below the threshold transparent
+fn clear_translucent_pixels(img: &mut DynamicImage, sprite: &Sprite, threshold: u8) {
+    let background = background_color(sprite);
+    for y in sprite.top..sprite.top + sprite.height {
+        for x in sprite.left..sprite.left + sprite.width {
+            let color = img.get_pixel(x, y);
+            if color[3] < threshold {
+                img.put_pixel(x, y, Rgba([background.0, background.1, background.2, 0]));
+            }
+        }
+    }
+}
+
+// Background color of a sprite (black by default)
+fn background_color(sprite: &Sprite) -> (u8, u8, u8) {
+    match sprite.background {
+        Some(Background::Color(c)) => c,
+        _ => (0, 0, 0),
+    }
+}
+
+// Transparent pixels and pixels of the background color are considered as background
+fn is_background(color: &Rgba<u8>, background: (u8, u8, u8)) -> bool {
+    color[3] == 0 || (color[0], color[1], color[2]) == background
+}
+
+// Distance between two colors (sum of the absolute differences of their components)
+fn color_distance(a: &(u8, u8, u8), b: &(u8, u8, u8)) -> u32 {
+    a.0.abs_diff(b.0) as u32 + a.1.abs_diff(b.1) as u32 + a.2.abs_diff(b.2) as u32
+}
+
+// Atari 7800 Palette
+static PALETTE: [u8; 768] = [
+    0x00, 0x00, 0x00, 0x11, 0x11, 0x11, 0x22, 0x22, 0x22, 0x33, 0x33, 0x33, 0x44, 0x44, 0x44, 0x55,
+    0x55, 0x55, 0x66, 0x66, 0x66, 0x77, 0x77, 0x77, 0x88, 0x88, 0x88, 0x99, 0x99, 0x99, 0xaa, 0xaa,
+    0xaa, 0xbb, 0xbb, 0xbb, 0xcc, 0xcc, 0xcc, 0xdd, 0xdd, 0xdd, 0xee, 0xee, 0xee, 0xff, 0xff, 0xff,
+    0x16, 0x0a, 0x00, 0x27, 0x1b, 0x00, 0x38, 0x2c, 0x00, 0x49, 0x3d, 0x00, 0x5a, 0x4e, 0x00, 0x6b,
+    0x5f, 0x00, 0x7c, 0x70, 0x00, 0x8d, 0x81, 0x05, 0x9e, 0x92, 0x16, 0xaf, 0xa3, 0x27, 0xc0, 0xb4,
+    0x38, 0xd1, 0xc5, 0x49, 0xe2, 0xd6, 0x5a, 0xf3, 0xe7, 0x6b, 0xff, 0xf8, 0x7c, 0xff, 0xff, 0x8d,
+    0x2f, 0x00, 0x00, 0x40, 0x08, 0x00, 0x51, 0x19, 0x00, 0x62, 0x2a, 0x00, 0x73, 0x3b, 0x00, 0x84,
+    0x4c, 0x00, 0x95, 0x5d, 0x00, 0xa6, 0x6e, 0x11, 0xb7, 0x7f, 0x22, 0xc8, 0x90, 0x33, 0xd9, 0xa1,
+    0x44, 0xea, 0xb2, 0x55, 0xfb, 0xc3, 0x66, 0xff, 0xd4, 0x77, 0xff, 0xe5, 0x88, 0xff, 0xf6, 0x99,
+    0x3d, 0x00, 0x00, 0x4e, 0x00, 0x00, 0x5f, 0x09, 0x00, 0x70, 0x1a, 0x00, 0x81, 0x2b, 0x00, 0x92,
+    0x3c, 0x11, 0xa3, 0x4d, 0x22, 0xb4, 0x5e, 0x33, 0xc5, 0x6f, 0x44, 0xd6, 0x80, 0x55, 0xe7, 0x91,
+    0x66, 0xf8, 0xa2, 0x77, 0xff, 0xb3, 0x88, 0xff, 0xc4, 0x99, 0xff, 0xd5, 0xaa, 0xff, 0xe6, 0xbb,
+    0x3f, 0x00, 0x00, 0x50, 0x00, 0x00, 0x61, 0x00, 0x0f, 0x72, 0x0f, 0x20, 0x83, 0x20, 0x31, 0x94,
+    0x31, 0x42, 0xa5, 0x42, 0x53, 0xb6, 0x53, 0x64, 0xc7, 0x64, 0x75, 0xd8, 0x75, 0x86, 0xe9, 0x86,
+    0x97, 0xfa, 0x97, 0xa8, 0xff, 0xa8, 0xb9, 0xff, 0xb9, 0xca, 0xff, 0xca, 0xdb, 0xff, 0xdb, 0xec,
+    0x33, 0x00, 0x21, 0x44, 0x00, 0x32, 0x55, 0x00, 0x43, 0x66, 0x0c, 0x54, 0x77, 0x1d, 0x65, 0x88,
+    0x2e, 0x76, 0x99, 0x3f, 0x87, 0xaa, 0x50, 0x98, 0xbb, 0x61, 0xa9, 0xcc, 0x72, 0xba, 0xdd, 0x83,
+    0xcb, 0xee, 0x94, 0xdc, 0xff, 0xa5, 0xed, 0xff, 0xb6, 0xfe, 0xff, 0xc7, 0xff, 0xff, 0xd8, 0xff,
+    0x1c, 0x00, 0x4f, 0x2d, 0x00, 0x60, 0x3e, 0x00, 0x71, 0x4f, 0x11, 0x82, 0x60, 0x22, 0x93, 0x71,
+    0x33, 0xa4, 0x82, 0x44, 0xb5, 0x93, 0x55, 0xc6, 0xa4, 0x66, 0xd7, 0xb5, 0x77, 0xe8, 0xc6, 0x88,
+    0xf9, 0xd7, 0x99, 0xff, 0xe8, 0xaa, 0xff, 0xf9, 0xbb, 0xff, 0xff, 0xcc, 0xff, 0xff, 0xdd, 0xff,
+    0x00, 0x00, 0x6b, 0x11, 0x00, 0x7c, 0x22, 0x0c, 0x8d, 0x33, 0x1d, 0x9e, 0x44, 0x2e, 0xaf, 0x55,
+    0x3f, 0xc0, 0x66, 0x50, 0xd1, 0x77, 0x61, 0xe2, 0x88, 0x72, 0xf3, 0x99, 0x83, 0xff, 0xaa, 0x94,
+    0xff, 0xbb, 0xa5, 0xff, 0xcc, 0xb6, 0xff, 0xdd, 0xc7, 0xff, 0xee, 0xd8, 0xff, 0xff, 0xe9, 0xff,
+    0x00, 0x00, 0x71, 0x00, 0x0c, 0x82, 0x05, 0x1d, 0x93, 0x16, 0x2e, 0xa4, 0x27, 0x3f, 0xb5, 0x38,
+    0x50, 0xc6, 0x49, 0x61, 0xd7, 0x5a, 0x72, 0xe8, 0x6b, 0x83, 0xf9, 0x7c, 0x94, 0xff, 0x8d, 0xa5,
+    0xff, 0x9e, 0xb6, 0xff, 0xaf, 0xc7, 0xff, 0xc0, 0xd8, 0xff, 0xd1, 0xe9, 0xff, 0xe2, 0xfa, 0xff,
+    0x00, 0x0d, 0x5f, 0x00, 0x1e, 0x70, 0x00, 0x2f, 0x81, 0x00, 0x40, 0x92, 0x10, 0x51, 0xa3, 0x21,
+    0x62, 0xb4, 0x32, 0x73, 0xc5, 0x43, 0x84, 0xd6, 0x54, 0x95, 0xe7, 0x65, 0xa6, 0xf8, 0x76, 0xb7,
+    0xff, 0x87, 0xc8, 0xff, 0x98, 0xd9, 0xff, 0xa9, 0xea, 0xff, 0xba, 0xfb, 0xff, 0xcb, 0xff, 0xff,
+    0x00, 0x1d, 0x38, 0x00, 0x2e, 0x49, 0x00, 0x3f, 0x5a, 0x00, 0x50, 0x6b, 0x05, 0x61, 0x7c, 0x16,
+    0x72, 0x8d, 0x27, 0x83, 0x9e, 0x38, 0x94, 0xaf, 0x49, 0xa5, 0xc0, 0x5a, 0xb6, 0xd1, 0x6b, 0xc7,
+    0xe2, 0x7c, 0xd8, 0xf3, 0x8d, 0xe9, 0xff, 0x9e, 0xfa, 0xff, 0xaf, 0xff, 0xff, 0xc0, 0xff, 0xff,
+    0x00, 0x26, 0x05, 0x00, 0x37, 0x16, 0x00, 0x48, 0x27, 0x00, 0x59, 0x38, 0x07, 0x6a, 0x49, 0x18,
+    0x7b, 0x5a, 0x29, 0x8c, 0x6b, 0x3a, 0x9d, 0x7c, 0x4b, 0xae, 0x8d, 0x5c, 0xbf, 0x9e, 0x6d, 0xd0,
+    0xaf, 0x7e, 0xe1, 0xc0, 0x8f, 0xf2, 0xd1, 0xa0, 0xff, 0xe2, 0xb1, 0xff, 0xf3, 0xc2, 0xff, 0xff,
+    0x00, 0x27, 0x00, 0x00, 0x38, 0x00, 0x00, 0x49, 0x00, 0x05, 0x5a, 0x05, 0x16, 0x6b, 0x16, 0x27,
+    0x7c, 0x27, 0x38, 0x8d, 0x38, 0x49, 0x9e, 0x49, 0x5a, 0xaf, 0x5a, 0x6b, 0xc0, 0x6b, 0x7c, 0xd1,
+    0x7c, 0x8d, 0xe2, 0x8d, 0x9e, 0xf3, 0x9e, 0xaf, 0xff, 0xaf, 0xc0, 0xff, 0xc0, 0xd1, 0xff, 0xd1,
+    0x00, 0x20, 0x00, 0x00, 0x31, 0x00, 0x0d, 0x42, 0x00, 0x1e, 0x53, 0x00, 0x2f, 0x64, 0x00, 0x40,
+    0x75, 0x00, 0x51, 0x86, 0x0e, 0x62, 0x97, 0x1f, 0x73, 0xa8, 0x30, 0x84, 0xb9, 0x41, 0x95, 0xca,
+    0x52, 0xa6, 0xdb, 0x63, 0xb7, 0xec, 0x74, 0xc8, 0xfd, 0x85, 0xd9, 0xff, 0x96, 0xea, 0xff, 0xa7,
+    0x08, 0x12, 0x00, 0x19, 0x23, 0x00, 0x2a, 0x34, 0x00, 0x3b, 0x45, 0x00, 0x4c, 0x56, 0x00, 0x5d,
+    0x67, 0x00, 0x6e, 0x78, 0x00, 0x7f, 0x89, 0x08, 0x90, 0x9a, 0x19, 0xa1, 0xab, 0x2a, 0xb2, 0xbc,
+    0x3b, 0xc3, 0xcd, 0x4c, 0xd4, 0xde, 0x5d, 0xe5, 0xef, 0x6e, 0xf6, 0xff, 0x7f, 0xff, 0xff, 0x90,
+    0x24, 0x00, 0x00, 0x35, 0x11, 0x00, 0x46, 0x22, 0x00, 0x57, 0x33, 0x00, 0x68, 0x44, 0x00, 0x79,
+    0x55, 0x00, 0x8a, 0x66, 0x00, 0x9b, 0x77, 0x09, 0xac, 0x88, 0x1a, 0xbd, 0x99, 0x2b, 0xce, 0xaa,
+    0x3c, 0xdf, 0xbb, 0x4d, 0xf0, 0xcc, 0x5e, 0xff, 0xdd, 0x6f, 0xff, 0xee, 0x80, 0xff, 0xff, 0x91,
+];
+
+fn find_color_in_palette(c: &(u8, u8, u8)) -> u8 {
+    let mut maxdist = 256 * 256 * 256;
+    let mut bestcolor = 0;
+    for color in 0..256 {
+        let dist = (PALETTE[color * 3] as i32 - c.0 as i32).abs()
+            + (PALETTE[color * 3 + 1] as i32 - c.1 as i32).abs()
+            + (PALETTE[color * 3 + 2] as i32 - c.2 as i32).abs();
+        if dist < maxdist {
+            maxdist = dist;
+            bestcolor = color as u8;
+        }
+    }
+    bestcolor
+}
+
+// Palette number of a sprite: explicitly given, or the position of its palette in the palettes list
+fn palette_number(sprite: &Sprite, palettes: &Option<Vec<Palette>>) -> u8 {
+    sprite.palette_number.unwrap_or_else(|| {
+        sprite
+            .palette
+            .as_ref()
+            .and_then(|pname| palettes.iter().flatten().position(|p| &p.name == pname))
+            .map_or(0, |i| i as u8)
+    })
+}
+
+// Palette group (0 for P0-P3, 1 for P4-P7) of a 160B sprite, given the colors of the 3 colors palettes list
+fn palette_group(img: &DynamicImage, sprite: &Sprite, palettes: &[Palette]) -> Result<u8> {
+    let background = background_color(sprite);
+    let tolerance = sprite.tolerance.unwrap_or(0);
+    let in_group = |g: usize, c: &(u8, u8, u8)| {
+        palettes
+            .iter()
+            .skip(g * 4)
+            .take(4)
+            .flat_map(|p| p.colors.iter())
+            .any(|pc| color_distance(c, pc) <= tolerance)
+    };
+    let mut only = [None, None];
+    for y in 0..sprite.height {
+        for x in 0..sprite.width / 2 {
+            let color = img.get_pixel(sprite.left + x * 2, sprite.top + y);
+            if !is_background(&color, background) {
+                let rgb = (color[0], color[1], color[2]);
+                match (in_group(0, &rgb), in_group(1, &rgb)) {
+                    (true, false) => only[0] = only[0].or(Some(rgb)),
+                    (false, true) => only[1] = only[1].or(Some(rgb)),
+                    _ => (),
+                }
+            }
+        }
+    }
+    match only {
+        [Some(c0), Some(c1)] => Err(anyhow!(
+            "Sprite {}: colors straddle both 160B palette groups ({:?} is only in P0-P3, {:?} only in P4-P7)",
+            sprite.name,
+            c0,
+            c1
+        )),
+        [_, Some(_)] => Ok(1),
+        _ => Ok(0),
+    }
+}
+
+// Tight bounding box (x, y, width, height in pixels of the gfx mode) of the non transparent pixels of a sprite
+fn bounding_box(img: &DynamicImage, sprite: &Sprite, pixel_width: u32) -> [u32; 4] {
+    let background = background_color(sprite);
+    let (mut xmin, mut ymin, mut xmax, mut ymax) = (u32::MAX, u32::MAX, 0, 0);
+    for y in 0..sprite.height {
+        for x in 0..sprite.width / pixel_width {
+            let color = img.get_pixel(sprite.left + x * pixel_width, sprite.top + y);
+            if !is_background(&color, background) {
+                xmin = xmin.min(x);
+                ymin = ymin.min(y);
+                xmax = xmax.max(x + 1);
+                ymax = ymax.max(y + 1);
+            }
+        }
+    }
+    if xmin == u32::MAX {
+        [0, 0, 0, 0] // Empty sprite
+    } else {
+        [xmin, ymin, xmax - xmin, ymax - ymin]
+    }
+}
+
+fn print_bbox(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    bbox: &[u32; 4],
+    asm: bool,
+) -> Result<()> {
+    if asm {
+        let values: Vec<String> = bbox.iter().map(|v| v.to_string()).collect();
+        return print_asm_table(out, &format!("{}_bbox", name), ".byte", &values);
+    }
+    writeln!(
+        out,
+        "const char {}_bbox[4] = {{{}, {}, {}, {}}};",
+        name, bbox[0], bbox[1], bbox[2], bbox[3]
+    )?;
+    writeln!(header, "extern const char {}_bbox[4];", name)?;
+    Ok(())
+}
+
+// Non transparent pixels of a sprite (or of its hflip or vflip mirrored copy), for collisions computation
+fn collision_map(img: &DynamicImage, sprite: &Sprite, pixel_width: u32, flip: &str) -> Vec<bool> {
+    let w = (sprite.width / pixel_width) as usize;
+    let h = sprite.height as usize;
+    let background = background_color(sprite);
+    let mut map = vec![false; w * h];
+    for y in 0..h {
+        for x in 0..w {
+            let color = img.get_pixel(sprite.left + x as u32 * pixel_width, sprite.top + y as u32);
+            if !is_background(&color, background) {
+                let (mx, my) = match flip {
+                    "hflip" => (w - 1 - x, y),
+                    "vflip" => (x, h - 1 - y),
+                    _ => (x, y),
+                };
+                map[mx + my * w] = true;
+            }
+        }
+    }
+    map
+}
+
+// Collision map as spans (start and length pairs) of colliding x offsets, row after row, with the index of the
+// first span of each row (and the end of the last one) in the spans table
+fn print_collision_spans(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    cmap: &[bool],
+    width: usize,
+    asm: bool,
+) -> Result<()> {
+    let mut spans = Vec::<usize>::new();
+    let mut rows = vec![0];
+    for row in cmap.chunks(width) {
+        let mut x = 0;
+        while x < width {
+            if row[x] {
+                let start = x;
+                while x < width && row[x] {
+                    x += 1;
+                }
+                spans.extend([start, x - start]);
+            } else {
+                x += 1;
+            }
+        }
+        rows.push(spans.len());
+    }
+    if spans.len() > 255 {
+        return Err(anyhow!(
+            "Collision computation: {} has too many spans ({} bytes) for a char index. Use the bitmap format",
+            name,
+            spans.len()
+        ));
+    }
+    let bitmap = (width / 8 + 1).next_power_of_two().min(8) * (rows.len() - 1);
+    let (prefix, comment) = if asm { ("$", ";") } else { ("0x", "//") };
+    let values =
+        |v: &[usize]| -> Vec<String> { v.iter().map(|b| format!("{}{:02x}", prefix, b)).collect() };
+    writeln!(
+        out,
+        "\n{} {}: {} bytes of spans and {} bytes of rows index (instead of {} bytes as a bitmap)",
+        comment,
+        name,
+        spans.len(),
+        rows.len(),
+        bitmap
+    )?;
+    if asm {
+        print_asm_table(out, &format!("{}_spans", name), ".byte", &values(&spans))?;
+        print_asm_table(out, &format!("{}_rows", name), ".byte", &values(&rows))?;
+        return Ok(());
+    }
+    // Empty arrays are not valid C
+    let spans = if spans.is_empty() { vec![0] } else { spans };
+    for (table, v) in [("spans", &spans), ("rows", &rows)] {
+        writeln!(
+            out,
+            "const char {}_{}[{}] = {{{}}};",
+            name,
+            table,
+            v.len(),
+            values(v).join(", ")
+        )?;
+        writeln!(header, "extern const char {}_{}[{}];", name, table, v.len())?;
+    }
+    Ok(())
+}
+
+// C function testing the collision map of two sprites, given the position of the second one relative to the
+// first one (dx = x2 - x1 and dy = y2 - y1, in pixels of the gfx mode). The negative offsets wrap around, out
+// of the map. The map is a bitmap of stride bytes per row, or spans when no stride is given
+fn print_collision_helper(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    size1: (usize, usize),
+    size: (usize, usize),
+    stride: Option<usize>,
+) -> Result<()> {
+    let function = name.replacen("collision_", "collide_", 1);
+    writeln!(
+        out,
+        "\n// Returns non zero if the sprites of {} overlap at (dx, dy) = (x2 - x1, y2 - y1)",
+        name
+    )?;
+    writeln!(out, "char {}(char dx, char dy)\n{{", function)?;
+    writeln!(
+        out,
+        "    char x = {} - dx, y = {} - dy;",
+        size1.0 - 1,
+        size1.1 - 1
+    )?;
+    writeln!(out, "    if (x >= {} || y >= {}) return 0;", size.0, size.1)?;
+    match stride {
+        Some(w) => writeln!(
+            out,
+            "    return {}[y * {} + (x >> 3)] & (0x80 >> (x & 7));",
+            name, w
+        )?,
+        None => writeln!(
+            out,
+            "    return collision_spans_test({0}_spans, {0}_rows, x, y);",
+            name
+        )?,
+    }
+    writeln!(out, "}}")?;
+    writeln!(header, "extern char {}(char dx, char dy);", function)?;
+    Ok(())
+}
+
+// Height of the scattered arrays (i.e. of the holey DMA zones)
+fn default_height(sprite: &Sprite, sprite_sheet: &SpriteSheet) -> u8 {
+    if let HoleyDma::Height(h @ 1..) = sprite.holeydma {
+        h
+    } else if let Some(h @ 1..) = sprite_sheet.holeydma {
+        h
+    } else if let Some(h) = sprite_sheet.default_height {
+        h
+    } else if sprite.height == 8 {
+        8
+    } else {
+        16
+    }
+}
+
+// Height of a sprite padded to a multiple of the zones height
+fn zones_height(sprite: &Sprite, sprite_sheet: &SpriteSheet) -> u32 {
+    let h = default_height(sprite, sprite_sheet) as u32;
+    sprite.height.div_ceil(h) * h
+}
+
+// Whether the scattered arrays of a sprite are holey DMA ones. A 0 holeydma on the sprite sheet disables holey
+// DMA for the sprites that don't give their own zone height
+fn holey_dma(sprite: &Sprite, sprite_sheet: &SpriteSheet) -> bool {
+    let enabled = match sprite.holeydma {
+        HoleyDma::Enabled(b) => b && sprite_sheet.holeydma != Some(0),
+        HoleyDma::Height(h) => h != 0,
+    };
+    enabled && matches!(default_height(sprite, sprite_sheet), 8 | 16)
+}
+
+// Transparency mask of a sprite: all the bits of its non background pixels are set, so that it can be
+// drawn into a bitmap buffer with dst = (dst & ~mask) | data
+fn print_mask(
+    out: &mut String,
+    header: &mut String,
+    name: &str,
+    bytes: &[u8],
+    mode: &str,
+    asm: bool,
+) -> Result<()> {
+    let mask: Vec<u8> = bytes
+        .iter()
+        .map(|&b| {
+            let mut m = 0;
+            match mode {
+                "160A" => {
+                    for i in 0..4 {
+                        if (b >> (6 - 2 * i)) & 3 != 0 {
+                            m |= 3 << (6 - 2 * i);
+                        }
+                    }
+                }
+                "160B" => {
+                    for pixel in [0xcc, 0x33] {
+                        if b & pixel != 0 {
+                            m |= pixel;
+                        }
+                    }
+                }
+                "320B" => {
+                    for i in 0..4 {
+                        if b & (0x88 >> i) != 0 {
+                            m |= 0x88 >> i;
+                        }
+                    }
+                }
+                "320C" => {
+                    for i in 0..4 {
+                        if b & (0x80 >> i) != 0 {
+                            m |= (0x80 >> i) | if i < 2 { 0x0c } else { 0x03 };
+                        }
+                    }
+                }
+                _ => m = b, // 320A and 320D: 1 bit per pixel
+            }
+            m
+        })
+        .collect();
+    if asm {
+        let values: Vec<String> = mask.iter().map(|m| format!("${:02x}", m)).collect();
+        return print_asm_table(out, &format!("{}_mask", name), ".byte", &values);
+    }
+    write!(out, "const char {}_mask[{}] = {{\n\t", name, mask.len())?;
+    print_bytes(out, &mask)?;
+    writeln!(header, "extern const char {}_mask[{}];", name, mask.len())?;
+    Ok(())
+}
+
+// Values of a C array, 16 per line, and its closing brace
+fn print_bytes(out: &mut String, bytes: &[u8]) -> Result<()> {
+    for (i, b) in bytes.iter().enumerate() {
+        write!(out, "0x{:02x}", b)?;
+        if i + 1 == bytes.len() {
+            writeln!(out, "\n}};")?;
+        } else if (i + 1) % 16 != 0 {
+            write!(out, ", ")?;
+        } else {
+            write!(out, ",\n\t")?;
+        }
+    }
+    Ok(())
+}
+
+// Assembly (dasm/ca65) table, 16 values per line
+fn print_asm_table(out: &mut String, name: &str, directive: &str, values: &[String]) -> Result<()> {
+    writeln!(out, "\n{}:", name)?;
+    for line in values.chunks(16) {
+        writeln!(out, "\t{} {}", directive, line.join(", "))?;
+    }
+    Ok(())
+}
+
+// Assembly output of the sprites graphics of a bank, in blocks of pages (one per zone line). As MARIA reads the
+// graphics upwards, the 1st page holds the bottom line of the sprites, and their labels.
+fn print_asm_scattered(
+    out: &mut String,
+    scattered: &[ScatteredArray],
+    bank: Option<u8>,
+) -> Result<()> {
+    let mut groups = BTreeMap::<(u8, bool), Vec<&ScatteredArray>>::new();
+    for a in scattered.iter().filter(|a| a.bank == bank) {
+        groups.entry((a.height, a.holeydma)).or_default().push(a);
+    }
+    for ((height, holeydma), arrays) in groups {
+        // Pack the arrays in blocks of 256 bytes wide pages
+        let mut blocks = Vec::<Vec<&ScatteredArray>>::new();
+        let mut width = 256;
+        for a in arrays {
+            let w = a.rows[0].len();
+            if w > 256 {
+                return Err(anyhow!("Sprite {}: more than 256 bytes wide", a.name));
+            }
+            if width + w > 256 {
+                blocks.push(Vec::new());
+                width = 0;
+            }
+            width += w;
+            blocks.last_mut().unwrap().push(a);
+        }
+        for block in blocks {
+            if holeydma {
+                // The block must not overlap the holes (4K blocks with A12 set, or 2K blocks with A11 set)
+                writeln!(out, "\n; {} lines holey DMA zones graphics", height)?;
+                writeln!(out, "\t.align {}", 512 * height as usize)?;
+            } else {
+                writeln!(out, "\n; {} lines zones graphics", height)?;
+                writeln!(out, "\t.align 256")?;
+            }
+            for page in 0..height as usize {
+                writeln!(out, "; Line {}", height as usize - 1 - page)?;
+                for a in &block {
+                    if page == 0 {
+                        writeln!(out, "{}:", a.name)?;
+                    }
+                    let row = &a.rows[height as usize - 1 - page];
+                    let values: Vec<String> = row.iter().map(|b| format!("${:02x}", b)).collect();
+                    writeln!(out, "\t.byte {}", values.join(", "))?;
+                }
+                writeln!(out, "\t.align 256")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Height (in pixels) and width (in bytes) of a sprite, for the header file
+fn print_defines(header: &mut String, name: &str, height: u32, width: usize) -> Result<()> {
+    writeln!(header, "#define {}_HEIGHT {}", name.to_uppercase(), height)?;
+    writeln!(header, "#define {}_WIDTH {}", name.to_uppercase(), width)?;
+    Ok(())
+}
+
+// Output the graphics data of a sprite (in several arrays if it is higher than the holey DMA zones)
+fn print_sprite(
+    name: &str,
+    bytes: &[u8],
+    sprite: &Sprite,
+    sprite_sheet: &SpriteSheet,
+    out: &mut String,
+    header: &mut String,
+    mut layout: Layout,
+) -> Result<()> {
+    let bank = if sprite.bank.is_some() {
+        sprite.bank
+    } else if sprite_sheet.bank.is_some() {
+        sprite_sheet.bank
+    } else {
+        None
+    };
+    let default_height = default_height(sprite, sprite_sheet);
+    let holeydma = holey_dma(sprite, sprite_sheet);
+    let width = bytes.len() / sprite.height as usize;
+    let padded = sprite.padded && sprite.height < default_height as u32;
+    if width > MAX_DL_WIDTH && !matches!(layout, Layout::Plain) {
+        if padded {
+            return Err(anyhow!(
+                "Sprite {}: padded sprites can't be split into columns",
+                sprite.name
+            ));
+        }
+        return print_columns(name, bytes, sprite, sprite_sheet, out, header, layout);
+    }
+    if let Layout::Plain = layout {
+        // Standard C array, for the toolchains that arrange the graphics data themselves
+        writeln!(
+            out,
+            "// {}: {} byte(s) per line, {} lines from top to bottom (row-major, not reversed nor scattered)",
+            name, width, sprite.height
+        )?;
+        write!(
+            out,
+            "const unsigned char {}[{}] = {{\n\t",
+            name,
+            bytes.len()
+        )?;
+        print_bytes(out, bytes)?;
+        writeln!(
+            header,
+            "extern const unsigned char {}[{}];",
+            name,
+            bytes.len()
+        )?;
+        print_defines(header, name, sprite.height, width)?;
+        return Ok(());
+    }
+    let padded_name = format!("{}_padded", name);
+    let mut name = name;
+    if padded {
+        // Short sprite (for zones of its own height), followed by its blank padded full zone variant
+        if let Layout::Asm(scattered) = &mut layout {
+            scattered.push(ScatteredArray {
+                name: name.to_string(),
+                bank,
+                height: sprite.height as u8,
+                holeydma: false,
+                rows: bytes.chunks(width).map(|r| r.to_vec()).collect(),
+            });
+        } else {
+            if sprite.height != 8 {
+                return Err(anyhow!(
+                    "Sprite {}: padded sprites should be 8 pixels high, as cc7800 only supports 8 and 16 lines scattered data",
+                    sprite.name
+                ));
+            }
+            print_defines(header, name, sprite.height, width)?;
+            if let Some(b) = bank {
+                write!(out, "bank{} ", b)?;
+            }
+            write!(
+                out,
+                "reversed scattered({},{}) char {}[{}] = {{\n\t",
+                sprite.height,
+                width,
+                name,
+                bytes.len()
+            )?;
+            writeln!(header, "extern char {}[{}];", name, bytes.len())?;
+            print_bytes(out, bytes)?;
+        }
+        name = &padded_name;
+    }
+    // Sprites whose height is not a multiple of the zones height (a bullet in 16 lines zones for instance)
+    // are padded with blank lines at the bottom
+    let nb_parts = sprite.height.div_ceil(default_height as u32);
+    let l = width * default_height as usize;
+    let mut bytes = bytes.to_vec();
+    bytes.resize(l * nb_parts as usize, 0);
+    if let Layout::Asm(scattered) = layout {
+        // Assembly output: the arrays are laid out in pages at the end
+        let rows: Vec<Vec<u8>> = bytes.chunks(width).map(|r| r.to_vec()).collect();
+        for (i, part) in rows.chunks(default_height as usize).enumerate() {
+            scattered.push(ScatteredArray {
+                name: if i == 0 {
+                    name.to_string()
+                } else {
+                    format!("{}_{}", name, i)
+                },
+                bank,
+                height: default_height,
+                holeydma,
+                rows: part.to_vec(),
+            });
+        }
+        if nb_parts > 1 {
+            writeln!(out, "\n{}_PARTS = {}", name.to_uppercase(), nb_parts)?;
+            print_asm_table(
+                out,
+                &format!("{}_parts", name),
+                ".word",
+                &parts(name, nb_parts),
+            )?;
+        }
+        return Ok(());
+    }
+    print_defines(header, name, sprite.height, width)?;
+    for (part, data) in parts(name, nb_parts).iter().zip(bytes.chunks(l)) {
+        if let Some(b) = bank {
+            write!(out, "bank{} ", b)?;
+        }
+        if holeydma {
+            write!(out, "holeydma ")?;
+        }
+        write!(
+            out,
+            "reversed scattered({},{}) char {}[{}] = {{\n\t",
+            default_height, width, part, l
+        )?;
+        writeln!(header, "extern char {}[{}];", part, l)?;
+        print_bytes(out, data)?;
+    }
+    if nb_parts > 1 {
+        // Vertical sections table, so that the whole sprite can be displayed with a loop
+        writeln!(
+            out,
+            "\nconst char *{}_parts[{}] = {{{}}};",
+            name,
+            nb_parts,
+            parts(name, nb_parts).join(", ")
+        )?;
+        // Without a header file, the define goes along with the data
+        let defines = if let Layout::Scattered(false) = layout {
+            &mut *out
+        } else {
+            &mut *header
+        };
+        writeln!(
+            defines,
+            "#define {}_PARTS {}",
+            name.to_uppercase(),
+            nb_parts
+        )?;
+        writeln!(header, "extern const char *{}_parts[{}];", name, nb_parts)?;
+    }
+    Ok(())
+}
+
+// Output a sprite too wide for a display list entry as columns of at most MAX_DL_WIDTH bytes (<name>,
+// <name>_col1, <name>_col2... from left to right), with the table of the columns and of their horizontal offsets
+fn print_columns(
+    name: &str,
+    bytes: &[u8],
+    sprite: &Sprite,
+    sprite_sheet: &SpriteSheet,
+    out: &mut String,
+    header: &mut String,
+    mut layout: Layout,
+) -> Result<()> {
+    let width = bytes.len() / sprite.height as usize;
+    let nb_columns = width.div_ceil(MAX_DL_WIDTH);
+    let column_width = width.div_ceil(nb_columns);
+    // Horizontal position units (160 pixels per line) per byte
+    let hpos = match sprite.mode.as_deref().unwrap_or(sprite_sheet.mode.as_str()) {
+        "160B" | "320B" | "320C" => 2,
+        _ => 4,
+    };
+    let mut names = Vec::new();
+    let mut offsets = Vec::new();
+    for c in 0..nb_columns {
+        let column = if c == 0 {
+            name.to_string()
+        } else {
+            format!("{}_col{}", name, c)
+        };
+        let x = c * column_width;
+        let column_bytes: Vec<u8> = bytes
+            .chunks(width)
+            .flat_map(|row| row[x..(x + column_width).min(width)].iter().copied())
+            .collect();
+        print_sprite(
+            &column,
+            &column_bytes,
+            sprite,
+            sprite_sheet,
+            out,
+            header,
+            layout.reborrow(),
+        )?;
+        names.push(column);
+        offsets.push((x * hpos).to_string());
+    }
+    if let Layout::Asm(_) = layout {
+        writeln!(out, "\n{}_COLUMNS = {}", name.to_uppercase(), nb_columns)?;
+        print_asm_table(out, &format!("{}_columns", name), ".word", &names)?;
+        print_asm_table(out, &format!("{}_columns_x", name), ".byte", &offsets)?;
+        return Ok(());
+    }
+    writeln!(
+        out,
+        "\nconst char *{}_columns[{}] = {{{}}};",
+        name,
+        nb_columns,
+        names.join(", ")
+    )?;
+    writeln!(
+        out,
+        "const char {}_columns_x[{}] = {{{}}};",
+        name,
+        nb_columns,
+        offsets.join(", ")
+    )?;
+    let defines = if let Layout::Scattered(false) = layout {
+        &mut *out
+    } else {
+        &mut *header
+    };
+    writeln!(
+        defines,
+        "#define {}_COLUMNS {}",
+        name.to_uppercase(),
+        nb_columns
+    )?;
+    writeln!(
+        header,
+        "extern const char *{}_columns[{}];",
+        name, nb_columns
+    )?;
+    writeln!(
+        header,
+        "extern const char {}_columns_x[{}];",
+        name, nb_columns
+    )?;
+    Ok(())
+}
+
+// Names of the arrays of the vertical sections of a sprite (from top to bottom)
+fn parts(name: &str, nb_parts: u32) -> Vec<String> {
+    (0..nb_parts)
+        .map(|i| {
+            if i == 0 {
+                name.to_string()
+            } else {
+                format!("{}_{}", name, i)
+            }
+        })
+        .collect()
+}
+
+// Load the YAML input files, with the sprite sheets, palettes and output order of all the files
+fn load_yaml_files(filenames: &[String]) -> Result<AllSprites> {
+    let mut all_sprites = AllSprites {
+        palettes: None,
+        sprite_sheets: Vec::new(),
+        order: None,
+    };
+    for (file, filename) in filenames.iter().enumerate() {
+        let contents = fs::read_to_string(filename).expect("Unable to read input file");
+        let mut sprites = parse_yaml(&contents)?;
+        for palette in sprites.palettes.into_iter().flatten() {
+            let palettes = all_sprites.palettes.get_or_insert_with(Vec::new);
+            match palettes.iter().find(|p| p.name == palette.name) {
+                Some(p) if p.colors != palette.colors => {
+                    return Err(anyhow!(
+                        "{}: palette {} differs from the one of a previous file",
+                        filename,
+                        palette.name
+                    ));
+                }
+                Some(_) => (),
+                None => palettes.push(palette),
+            }
+        }
+        for sprite_sheet in &mut sprites.sprite_sheets {
+            sprite_sheet.file = file;
+        }
+        all_sprites.sprite_sheets.append(&mut sprites.sprite_sheets);
+        if let Some(order) = sprites.order {
+            let o = all_sprites.order.get_or_insert_with(Vec::new);
+            o.extend(
+                order
+                    .into_iter()
+                    .filter(|k| !o.contains(k))
+                    .collect::<Vec<_>>(),
+            );
+        }
+    }
+    Ok(all_sprites)
+}
+
+// Parse the YAML input file. The attributes of the defaults block of a sprite sheet are inherited by its
+// declared sprites that don't specify them
+fn parse_yaml(contents: &str) -> Result<AllSprites> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(contents)?;
+    let mut inherited = false;
+    for sheet in value
+        .get_mut("sprite_sheets")
+        .and_then(|s| s.as_sequence_mut())
+        .into_iter()
+        .flatten()
+    {
+        let Some(defaults) = sheet.get("defaults").cloned() else {
+            continue;
+        };
+        let defaults = defaults
+            .as_mapping()
+            .ok_or_else(|| anyhow!("Sprite sheet defaults should be a set of sprite attributes"))?
+            .clone();
+        for sprite in sheet
+            .get_mut("sprites")
+            .and_then(|s| s.as_sequence_mut())
+            .into_iter()
+            .flatten()
+        {
+            if let Some(sprite) = sprite.as_mapping_mut() {
+                for (k, v) in &defaults {
+                    if !sprite.contains_key(k) {
+                        sprite.insert(k.clone(), v.clone());
+                    }
+                }
+            }
+        }
+        inherited = true;
+    }
+    if inherited {
+        Ok(serde_yaml::from_value(value)?)
+    } else {
+        // Keep the locations in the error messages
+        Ok(serde_yaml::from_str(contents)?)
+    }
+}
+
+// Sprites added by an atlas, markers, images or an Aseprite or animated image inherit the defaults block
+// of their sprite sheet, as the declared ones, from their own attributes
+fn apply_defaults(sprite_sheet: &mut SpriteSheet) -> Result<()> {
+    let Some(defaults) = &sprite_sheet.defaults else {
+        return Ok(());
+    };
+    for sprite in sprite_sheet.sprites.iter_mut().filter(|s| s.generated) {
+        let mut attributes = defaults.clone();
+        attributes.insert("name".into(), sprite.name.clone().into());
+        attributes.insert("left".into(), sprite.left.into());
+        attributes.insert("top".into(), sprite.top.into());
+        attributes.insert("width".into(), sprite.width.into());
+        attributes.insert("height".into(), sprite.height.into());
+        if let Some(image) = &sprite.image {
+            attributes.insert("image".into(), image.clone().into());
+        }
+        if let Some(palette) = &sprite.palette {
+            attributes.insert("palette".into(), palette.clone().into());
+        }
+        *sprite = serde_yaml::from_value(attributes.into())
+            .map_err(|e| anyhow!("Sprite {}: bad defaults ({})", sprite.name, e))?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let contents = fs::read_to_string(args.filename).expect("Unable to read input file");
-    let all_sprites: AllSprites = serde_yaml::from_str(&contents)?;
-    for sprite_sheet in all_sprites.sprite_sheets {
-        let img = image::open(&sprite_sheet.image)
-            .expect(&format!("Can't open image {}", sprite_sheet.image));
+    let mut all_sprites = load_yaml_files(&args.filenames)?;
+    let asm = args.format == Format::Asm;
+    let comment = if asm { ";" } else { "//" };
+    for sprite_sheet in &mut all_sprites.sprite_sheets {
+        if let Some(atlas) = sprite_sheet.atlas.clone() {
+            load_atlas(sprite_sheet, &atlas)?;
+        }
+        if let Some(markers) = sprite_sheet.markers.clone() {
+            load_markers(sprite_sheet, &markers)?;
+        }
+        if let Some(images) = sprite_sheet.images.clone() {
+            load_images(sprite_sheet, &images)?;
+        }
+        apply_defaults(sprite_sheet)?;
+        // The height of an own image is only known when it is merged into the sheet
+        for sprite in sprite_sheet
+            .sprites
+            .iter_mut()
+            .filter(|s| s.height == 0 && s.image.is_none())
+        {
+            sprite.height = default_sprite_size();
+        }
+        sprite_sheet.sprites = expand_frames(
+            std::mem::take(&mut sprite_sheet.sprites),
+            &mut sprite_sheet.animations,
+        )?;
+        for m in sprite_sheet.color_map.iter().flatten() {
+            if m.slot == 0 || m.slot > 12 {
+                return Err(anyhow!(
+                    "Color map: slot {} of color {:?} should be between 1 and 12",
+                    m.slot,
+                    m.color
+                ));
+            }
+        }
+        let png = Path::new(&sprite_sheet.image)
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+        if png {
+            if let Some((palette, indices)) = png_indices(&sprite_sheet.image)? {
+                sprite_sheet.indexed_palette = Some(palette);
+                sprite_sheet.indices = Some(indices);
+            }
+        }
+        for sprite in &mut sprite_sheet.sprites {
+            if let Some(p) = sprite.palette_number {
+                if p > 7 {
+                    return Err(anyhow!("Sprite {}: Bad palette number {}", sprite.name, p));
+                }
+                let mode = sprite.mode.as_deref().unwrap_or(sprite_sheet.mode.as_str());
+                if mode == "160B" && p & 3 != 0 {
+                    // As with tiles7800, only the P2 bit of the palette number is used in 160B mode
+                    println!("{} Warning: Sprite {}: 160B palette number should be 0 (palettes 0-3) or 4 (palettes 4-7). Using {}", comment, sprite.name, p & 4);
+                    sprite.palette_number = Some(p & 4);
+                }
+            }
+            if let Some(v) = sprite.variant {
+                let mode = sprite.mode.as_deref().unwrap_or(sprite_sheet.mode.as_str());
+                if mode != "320D" || v > 3 {
+                    return Err(anyhow!(
+                        "Sprite {}: variant should be given to 320D sprites only, from 0 to 3",
+                        sprite.name
+                    ));
+                }
+            }
+            if let HoleyDma::Height(h) = sprite.holeydma {
+                if h != 0 && h != 8 && h != 16 {
+                    return Err(anyhow!(
+                        "Sprite {}: holeydma {} should be 0, 8 or 16",
+                        sprite.name,
+                        h
+                    ));
+                }
+            }
+            if sprite.tolerance.is_none() {
+                sprite.tolerance = sprite_sheet.tolerance;
+            }
+            if sprite.alpha_threshold.is_none() {
+                sprite.alpha_threshold = sprite_sheet.alpha_threshold;
+            }
+            if sprite.background.is_none() {
+                sprite.background = sprite_sheet.background.map(Background::Color);
+            }
+            if sprite.dither.is_none() {
+                sprite.dither = sprite_sheet.dither.clone();
+            }
+            if sprite.mirror.is_none() && sprite.alias.is_none() {
+                sprite.mirror = sprite_sheet.mirror.clone();
+            }
+            if sprite.background.is_none() {
+                // Index 0 of an indexed PNG is transparent
+                if let Some(p) = &sprite_sheet.indexed_palette {
+                    sprite.background = p.first().map(|c| Background::Color(*c));
+                }
+            }
+        }
+    }
+    let mut out = String::new();
+    let mut header = String::new();
+    let mut arrays = HashMap::<String, String>::new(); // Generated array of each sprite (or alias)
+                                                       // Graphics data already output (with their zones layout), to share them between the input files
+    let mut store = HashMap::<(Vec<u8>, u32, u8, bool, Option<u8>), (String, usize)>::new();
+    let mut metadata = Vec::<SpriteInfo>::new();
+    if asm && args.plain {
+        return Err(anyhow!("Plain arrays are not supported in assembly format"));
+    }
+    if asm
+        && args.output.is_none()
+        && all_sprites
+            .sprite_sheets
+            .iter()
+            .any(|s| s.bank.is_some() || s.sprites.iter().any(|sprite| sprite.bank.is_some()))
+    {
+        return Err(anyhow!(
+            "Banked sprites need the -O option in assembly format (one graphics file per bank)"
+        ));
+    }
+    if asm && args.collision_helpers {
+        return Err(anyhow!(
+            "Collision helpers are not supported in assembly format"
+        ));
+    }
+    // Colors auto-assigned to free slots, per palette (empty palette name for the sprites without palette)
+    let mut auto_assigned = BTreeMap::<String, BTreeMap<(usize, (u8, u8, u8)), Vec<String>>>::new();
+    let mut effective_colors = HashMap::<String, Vec<(u8, u8, u8)>>::new();
+    let mut scattered = Vec::<ScatteredArray>::new();
+    let mut chunks = Vec::<(Option<u8>, String, String)>::new(); // Bank, sheet image and code, when ordered
+    let mut spans_routine = false; // Collision spans lookup routine already output
+    for mut sprite_sheet in all_sprites.sprite_sheets {
+        let aseprite = Path::new(&sprite_sheet.image)
+            .extension()
+            .is_some_and(|e| e == "aseprite" || e == "ase");
+        let img = if sprite_sheet.image.is_empty() {
+            DynamicImage::new_rgba8(0, 0)
+        } else if aseprite {
+            load_aseprite(
+                &mut sprite_sheet,
+                all_sprites.palettes.get_or_insert_with(Vec::new),
+            )?
+        } else if let Some(strip) = load_animation(&mut sprite_sheet)? {
+            strip
+        } else {
+            image::open(&sprite_sheet.image)
+                .unwrap_or_else(|_| panic!("Can't open image {}", sprite_sheet.image))
+        };
+        apply_defaults(&mut sprite_sheet)?;
+        let mut img = merge_sprite_images(&mut sprite_sheet, img)?;
 
         // Generate sprites data
         for sprite in &sprite_sheet.sprites {
+            let first = metadata.len();
+            if sprite.padded && sprite.height >= default_height(sprite, &sprite_sheet) as u32 {
+                println!(
+                    "{} Warning: Sprite {}: padded is only useful for sprites shorter than their zones",
+                    comment, sprite.name
+                );
+            }
             if sprite.alias.is_none() {
+                let mode = sprite.mode.as_deref().unwrap_or(sprite_sheet.mode.as_str());
+                check_width(sprite, mode, comment);
+            }
+            if let Some(alias) = &sprite.alias {
+                if let Some(a) = arrays.get(alias).cloned() {
+                    arrays.insert(sprite.name.clone(), a);
+                }
+                // The columns and padded variant of the sprite come along
+                let entries: Vec<(String, SpriteInfo)> = metadata
+                    .iter()
+                    .filter_map(|m| {
+                        let column = m.name.strip_prefix(alias.as_str())?;
+                        let suffix = column.strip_prefix("_col");
+                        if column.is_empty()
+                            || column == "_padded"
+                            || suffix.is_some_and(|n| n.parse::<usize>().is_ok())
+                        {
+                            Some((format!("{}{}", sprite.name, column), m.clone()))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                for (name, m) in entries {
+                    let palette = if sprite.palette.is_some() || sprite.palette_number.is_some() {
+                        palette_number(sprite, &all_sprites.palettes)
+                    } else {
+                        m.palette
+                    };
+                    metadata.push(SpriteInfo { name, palette, ..m });
+                }
+            } else if sprite.overlay {
+                let mode = sprite.mode.as_deref().unwrap_or(sprite_sheet.mode.as_str());
+                if mode != "160A" {
+                    return Err(anyhow!(
+                        "Sprite {}: overlay sprites are only supported in 160A mode",
+                        sprite.name
+                    ));
+                }
+                let layers = overlay_layers(&img, sprite, &all_sprites.palettes)?;
+                for (layer, layer_img, colors) in &layers {
+                    let mut colors = *colors;
+                    let bytes = sprite_bytes(
+                        layer_img,
+                        None,
+                        layer,
+                        mode,
+                        &mut colors,
+                        3,
+                        false,
+                        false,
+                        comment,
+                    )?;
+                    if sprite.fake == Some(true) {
+                        continue;
+                    }
+                    metadata.push(SpriteInfo {
+                        name: layer.name.clone(),
+                        gfx: layer.name.clone(),
+                        width: bytes.len() / layer.height as usize,
+                        height: layer.height,
+                        write_mode: 0,
+                        palette: palette_number(sprite, &all_sprites.palettes),
+                        holeydma: if holey_dma(layer, &sprite_sheet) {
+                            default_height(layer, &sprite_sheet)
+                        } else {
+                            0
+                        },
+                        bank: sprite.bank.or(sprite_sheet.bank),
+                        size: bytes.len() / layer.height as usize
+                            * zones_height(layer, &sprite_sheet) as usize,
+                    });
+                    arrays.insert(layer.name.clone(), layer.name.clone());
+                    if let Some(dir) = &args.preview {
+                        write_preview(dir, &layer.name, &bytes, mode, None, layer.height, &colors)?;
+                    }
+                    print_sprite(
+                        &layer.name,
+                        &bytes,
+                        layer,
+                        &sprite_sheet,
+                        &mut out,
+                        &mut header,
+                        Layout::new(asm, args.plain, args.output.is_some(), &mut scattered),
+                    )?;
+                }
+                if sprite.fake != Some(true) {
+                    // The C1-C3 colors of both layers, to be loaded in the palettes they are displayed with
+                    let name = format!("{}_overlay_palettes", sprite.name);
+                    let values: Vec<u8> = layers
+                        .iter()
+                        .flat_map(|(_, _, colors)| colors[0..3].iter().map(find_color_in_palette))
+                        .collect();
+                    if asm {
+                        let values: Vec<String> =
+                            values.iter().map(|v| format!("${:02x}", v)).collect();
+                        print_asm_table(&mut out, &name, ".byte", &values)?;
+                    } else {
+                        writeln!(
+                            out,
+                            "\nconst char {}[6] = {{{}}};",
+                            name,
+                            values
+                                .iter()
+                                .map(|v| format!("0x{:02x}", v))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )?;
+                        writeln!(header, "extern const char {}[6];", name)?;
+                    }
+                }
+            } else {
                 let mode = if let Some(s) = &sprite.mode {
                     s.as_str()
                 } else {
                     sprite_sheet.mode.as_str()
                 };
 
-                let pixel_width = match mode {
-                    "320A" | "320B" | "320C" | "320D" => 1,
-                    _ => 2,
-                };
-                let pixel_bits = match mode {
-                    "320A" | "320D" => 1,
-                    "160B" => 4,
-                    _ => 2,
-                };
                 let maxcolors = match mode {
                     "160A" => 3,
                     "160B" => 12,
                     "320A" => 1,
                     "320B" => 3,
                     "320C" => 4,
+                    "320D" if sprite.variant.is_some() => 3,
                     "320D" => 1,
                     _ => return Err(anyhow!("Unknown gfx {} mode", mode)),
                 };
 
+                if let Some(threshold) = sprite.alpha_threshold {
+                    clear_translucent_pixels(&mut img, sprite, threshold);
+                }
+
                 let mut colors = [(0u8, 0u8, 0u8); 12];
                 if maxcolors != 1 {
                     if let Some(palettes) = &all_sprites.palettes {
                         if let Some(pname) = &sprite.palette {
-                            let px = palettes.into_iter().find(|x| &x.name == pname);
+                            let px = palettes.iter().find(|x| &x.name == pname);
                             if let Some(p) = px {
-                                let mut i = 0;
-                                for c in &p.colors {
+                                for (i, c) in p.colors.iter().enumerate() {
                                     colors[i] = *c;
-                                    i += 1;
                                 }
                             } else {
                                 return Err(anyhow!("Unknown palette {}", pname));
@@ -144,349 +2882,686 @@ fn main() -> Result<()> {
                         }
                     }
                 }
-
-                let mut bytes = Vec::<u8>::new();
-                let mut current_byte: u8 = 0;
-                let mut current_bits: u8 = 0;
-                for y in 0..sprite.height {
-                    for x in 0..sprite.width / pixel_width {
-                        let xp = sprite.left + x * pixel_width;
-                        let yp = sprite.top + y;
-                        let color = img.get_pixel(xp, yp);
-                        let mut cx: Option<u8> = None;
-                        // In case of defined palette, priority is to find the color in the palette, so that black is not considered as a background color
-                        if (color[3] != 0 && sprite.palette.is_some())
-                            || (sprite.palette.is_none()
-                                && (color[0] != 0 || color[1] != 0 || color[2] != 0))
-                        {
-                            // Not transparent
-                            for c in 0..maxcolors {
-                                if color[0] == colors[c].0
-                                    && color[1] == colors[c].1
-                                    && color[2] == colors[c].2
-                                {
-                                    // Ok. this is a pixel of color c
-                                    cx = Some((c + 1) as u8);
-                                    // 320C mode contraint check
-                                    if mode == "320C" {
-                                        // Check next pixel, should be background or same color
-                                        if x & 1 == 0 {
-                                            let colorr = img.get_pixel(
-                                                sprite.left + x * pixel_width + 1,
-                                                sprite.top + y,
-                                            );
-                                            if !(colorr[3] == 0
-                                                || (colorr[0] == 0
-                                                    && colorr[1] == 0
-                                                    && colorr[2] == 0))
-                                            {
-                                                // This is not background
-                                                if colorr != color {
-                                                    // return Err(anyhow!("Sprite {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", sprite.name, x, y, color, colorr));
-                                                    println!("// Warning: Sprite {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", sprite.name, x, y, color, colorr);
-                                                }
-                                            }
-                                        }
-                                    }
-                                    break;
-                                }
-                            }
-                        }
-                        if cx.is_none() {
-                            if color[3] == 0 || (color[0] == 0 && color[1] == 0 && color[2] == 0) {
-                                cx = Some(0); // Background color (either black or transparent)
-                            } else {
-                                // Let's find a unaffected color
-                                for c in 0..maxcolors {
-                                    if colors[c].0 == 0 && colors[c].1 == 0 && colors[c].2 == 0 {
-                                        colors[c].0 = color[0];
-                                        colors[c].1 = color[1];
-                                        colors[c].2 = color[2];
-                                        cx = Some((c + 1) as u8);
-                                        //println!("color {c} affected to {:?}", color);
-                                        if mode == "320C" {
-                                            // Check next pixel, should be background or same color
-                                            if x & 1 == 0 {
-                                                let colorr = img.get_pixel(
-                                                    sprite.left + x * pixel_width + 1,
-                                                    sprite.top + y,
-                                                );
-                                                if !(colorr[3] == 0
-                                                    || (colorr[0] == 0
-                                                        && colorr[1] == 0
-                                                        && colorr[2] == 0))
-                                                {
-                                                    // This is not background
-                                                    if colorr != color {
-                                                        return Err(anyhow!("Sprite {}: Two consecutive pixels have a different color in 320C mode (x = {}, y = {}, color1 = {:?}, color2 = {:?})", sprite.name, x, y, color, colorr));
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        break;
-                                    }
-                                }
-                                if cx.is_none() {
-                                    if sprite.background.is_some() {
-                                        // If a background is specified
-                                        cx = Some(0); // This unknown color is affected to background
-                                    } else {
-                                        println!(
-                                            "Unexpected color {:?} found at {},{}",
-                                            color,
-                                            sprite.left + x * pixel_width,
-                                            sprite.top + y
-                                        );
-                                        return Err(anyhow!(
-                                            "Sprite {} has more than {} colors",
-                                            sprite.name,
-                                            maxcolors
-                                        ));
-                                    }
-                                }
+                let mut group = None;
+                if mode == "160B"
+                    && sprite.palette.is_none()
+                    && sprite_sheet.indexed_palette.is_none()
+                {
+                    if let Some(palettes) = all_sprites
+                        .palettes
+                        .as_ref()
+                        .filter(|p| !p.is_empty() && p.iter().all(|p| p.colors.len() <= 3))
+                    {
+                        // 160B sprite without a 12 colors palette: automatically select the palettes group
+                        let g = palette_group(&img, sprite, palettes)?;
+                        for (i, p) in palettes.iter().skip(g as usize * 4).take(4).enumerate() {
+                            for (j, c) in p.colors.iter().enumerate() {
+                                colors[i * 3 + j] = *c;
                             }
                         }
-                        match mode {
-                            "160A" | "320A" | "320D" => {
-                                current_byte |= cx.unwrap();
-                                current_bits += pixel_bits;
-                                if current_bits == 8 {
-                                    bytes.push(current_byte);
-                                    current_byte = 0;
-                                    current_bits = 0;
-                                } else {
-                                    current_byte <<= pixel_bits;
-                                };
-                            }
-                            "160B" => {
-                                let c = match cx.unwrap() {
-                                    0 => 0,
-                                    1 => 1,
-                                    2 => 2,
-                                    3 => 3,
-                                    4 => 5,
-                                    5 => 6,
-                                    6 => 7,
-                                    7 => 9,
-                                    8 => 10,
-                                    9 => 11,
-                                    10 => 13,
-                                    11 => 14,
-                                    12 => 15,
-                                    _ => 0,
-                                };
-                                current_byte |= (if c & 1 != 0 { 16 } else { 0 })
-                                    | (if c & 2 != 0 { 32 } else { 0 })
-                                    | (if c & 4 != 0 { 1 } else { 0 })
-                                    | (if c & 8 != 0 { 2 } else { 0 });
-                                current_bits += 1;
-                                if current_bits == 2 {
-                                    bytes.push(current_byte);
-                                    current_byte = 0;
-                                    current_bits = 0;
-                                } else {
-                                    current_byte <<= 2;
-                                };
-                            }
-                            "320B" => {
-                                let c = cx.unwrap();
-                                current_byte |= (if c & 1 != 0 { 1 } else { 0 })
-                                    | (if c & 2 != 0 { 16 } else { 0 });
-                                current_bits += 1;
-                                if current_bits == 4 {
-                                    bytes.push(current_byte);
-                                    current_byte = 0;
-                                    current_bits = 0;
-                                } else {
-                                    current_byte <<= 1;
-                                };
-                            }
-                            "320C" => {
-                                let c = cx.unwrap();
-                                //println!("Color: {}", c);
-                                if c != 0 {
-                                    current_byte |= 1 << (7 - current_bits);
-                                    if current_bits < 2 {
-                                        current_byte |= (c - 1) << 2;
-                                    } else {
-                                        current_byte |= c - 1;
-                                    }
-                                }
-                                current_bits += 1;
-                                if current_bits == 4 {
-                                    bytes.push(current_byte);
-                                    current_byte = 0;
-                                    current_bits = 0;
-                                }
-                            }
-                            _ => unreachable!(),
+                        println!(
+                            "{} Sprite {}: 160B palette group {}",
+                            comment,
+                            sprite.name,
+                            if g == 0 { "P0-P3" } else { "P4-P7" }
+                        );
+                        let target = if args.output.is_some() && !asm {
+                            &mut header
+                        } else {
+                            &mut out
                         };
+                        if asm {
+                            writeln!(
+                                target,
+                                "\n{}_PALETTE_GROUP = {}",
+                                sprite.name.to_uppercase(),
+                                g
+                            )?;
+                        } else {
+                            writeln!(
+                                target,
+                                "#define {}_PALETTE_GROUP {}",
+                                sprite.name.to_uppercase(),
+                                g
+                            )?;
+                        }
+                        group = Some(g);
                     }
-                    // If the bytes are not complete, fill with void
-                    while current_bits != 0 {
-                        match mode {
-                            "160A" | "320A" | "320D" => {
-                                current_bits += pixel_bits;
-                                if current_bits == 8 {
-                                    bytes.push(current_byte);
-                                    current_bits = 0;
-                                } else {
-                                    current_byte <<= pixel_bits;
-                                };
-                            }
-                            "160B" => {
-                                current_bits += 1;
-                                if current_bits == 2 {
-                                    bytes.push(current_byte);
-                                    current_bits = 0;
-                                } else {
-                                    current_byte <<= 2;
-                                };
-                            }
-                            "320B" => {
-                                current_bits += 1;
-                                if current_bits == 4 {
-                                    bytes.push(current_byte);
-                                    current_bits = 0;
-                                } else {
-                                    current_byte <<= 1;
-                                };
-                            }
-                            "320C" => {
-                                bytes.push(current_byte);
-                                current_bits = 0;
+                }
+                // The sprites of an indexed PNG image are made of its palette indices (unless they are dithered)
+                let indices = sprite_sheet.indices.as_ref().filter(|_| {
+                    sprite.palette.is_none() && sprite.dither.is_none() && sprite.image.is_none()
+                });
+                if sprite.palette.is_none() {
+                    if let Some(p) = &sprite_sheet.indexed_palette {
+                        // Index 0 is the background, so that the sprite colors are the following indices
+                        for (i, c) in p.iter().skip(1).take(maxcolors).enumerate() {
+                            colors[i] = *c;
+                        }
+                    }
+                    if group.is_none() && indices.is_none() {
+                        // The mapped colors take precedence over the automatic assignment
+                        for m in sprite_sheet.color_map.iter().flatten() {
+                            if (m.slot as usize) <= maxcolors {
+                                colors[m.slot as usize - 1] = m.color;
                             }
-                            _ => unreachable!(),
-                        };
+                        }
+                    }
+                }
+                if let Some(method) = &sprite.dither {
+                    // Reduce the colors of the sprite (in the sprite sheet) to the palette colors,
+                    // or to its most used colors
+                    let mut targets: Vec<_> = colors
+                        .iter()
+                        .take(maxcolors)
+                        .filter(|c| c.0 != 0 || c.1 != 0 || c.2 != 0)
+                        .cloned()
+                        .collect();
+                    if targets.is_empty() {
+                        targets = main_colors(&img, sprite, mode, maxcolors);
+                    }
+                    dither_sprite(&mut img, sprite, mode, &targets, method);
+                }
+
+                let declared = colors;
+                let bytes = sprite_bytes(
+                    &img,
+                    indices,
+                    sprite,
+                    mode,
+                    &mut colors,
+                    maxcolors,
+                    false,
+                    false,
+                    comment,
+                )?;
+                let assigned: Vec<(usize, (u8, u8, u8))> = (0..maxcolors)
+                    .filter(|&i| colors[i] != declared[i])
+                    .map(|i| (i + 1, colors[i]))
+                    .collect();
+                effective_colors.insert(sprite.name.clone(), colors[0..maxcolors].to_vec());
+                if !assigned.is_empty() && sprite.fake != Some(true) {
+                    writeln!(
+                        out,
+                        "{} Sprite {}: auto-assigned colors {}",
+                        comment,
+                        sprite.name,
+                        assigned
+                            .iter()
+                            .map(|(slot, c)| format!("C{} = {:?}", slot, c))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )?;
+                    let palette = auto_assigned
+                        .entry(sprite.palette.clone().unwrap_or_default())
+                        .or_default();
+                    for (slot, c) in assigned {
+                        palette
+                            .entry((slot, c))
+                            .or_default()
+                            .push(sprite.name.clone());
                     }
                 }
 
                 // Whoaw. We do have our pixels vector. Let's output it
                 if sprite.fake != Some(true) {
-                    let bank = if sprite.bank.is_some() {
-                        sprite.bank
-                    } else if sprite_sheet.bank.is_some() {
-                        sprite_sheet.bank
+                    let write_mode = match mode {
+                        "160B" | "320B" | "320C" => 1,
+                        _ => 0,
+                    };
+                    let info = SpriteInfo {
+                        name: sprite.name.clone(),
+                        gfx: sprite.name.clone(),
+                        width: bytes.len() / sprite.height as usize,
+                        height: sprite.height,
+                        write_mode,
+                        palette: match group {
+                            // The group is selected by bit 2 (P2) of the palette number
+                            Some(g) if sprite.palette_number.is_none() => g * 4,
+                            // The 320D variant is given by the P1 and P0 bits of the palette number
+                            _ if sprite.variant.is_some() => {
+                                (palette_number(sprite, &all_sprites.palettes) & 4)
+                                    | sprite.variant.unwrap()
+                            }
+                            _ => palette_number(sprite, &all_sprites.palettes),
+                        },
+                        holeydma: if holey_dma(sprite, &sprite_sheet) {
+                            default_height(sprite, &sprite_sheet)
+                        } else {
+                            0
+                        },
+                        bank: sprite.bank.or(sprite_sheet.bank),
+                        size: bytes.len() / sprite.height as usize
+                            * zones_height(sprite, &sprite_sheet) as usize,
+                    };
+                    // Sprites of a single display list entry can share the graphics of a previous file
+                    let key = (
+                        bytes.clone(),
+                        sprite.height,
+                        default_height(sprite, &sprite_sheet),
+                        holey_dma(sprite, &sprite_sheet),
+                        info.bank,
+                    );
+                    let shared = if args.filenames.len() > 1
+                        && !sprite.mask
+                        && !sprite.padded
+                        && sprite.height <= default_height(sprite, &sprite_sheet) as u32
+                        && info.width <= MAX_DL_WIDTH
+                    {
+                        match store.get(&key) {
+                            Some((gfx, file)) if *file != sprite_sheet.file => Some(gfx.clone()),
+                            Some(_) => None,
+                            None => {
+                                store.insert(key, (sprite.name.clone(), sprite_sheet.file));
+                                None
+                            }
+                        }
                     } else {
                         None
                     };
-                    if let Some(b) = bank {
-                        print!("bank{} ", b);
+                    if let Some(dir) = &args.preview {
+                        write_preview(
+                            dir,
+                            &sprite.name,
+                            &bytes,
+                            mode,
+                            sprite.variant,
+                            sprite.height,
+                            &colors,
+                        )?;
                     }
-                    let default_height = if let Some(h) = sprite_sheet.holeydma {
-                        h
-                    } else if let Some(h) = sprite_sheet.default_height {
-                        h
-                    } else if sprite.height == 8 {
-                        8
+                    if let Some(gfx) = shared {
+                        writeln!(
+                            out,
+                            "{} Sprite {} ({}): same graphics as {}",
+                            comment, sprite.name, args.filenames[sprite_sheet.file], gfx
+                        )?;
+                        if asm {
+                            writeln!(out, "{} = {}", sprite.name, gfx)?;
+                        } else {
+                            // The alias goes to the header file if any
+                            let target = if args.output.is_some() {
+                                &mut header
+                            } else {
+                                &mut out
+                            };
+                            writeln!(target, "#define {} {}", sprite.name, gfx)?;
+                        }
+                        metadata.push(SpriteInfo {
+                            gfx: gfx.clone(),
+                            size: 0,
+                            ..info.clone()
+                        });
+                        arrays.insert(sprite.name.clone(), gfx);
                     } else {
-                        16
+                        metadata.push(info.clone());
+                        arrays.insert(sprite.name.clone(), sprite.name.clone());
+                        print_sprite(
+                            &sprite.name,
+                            &bytes,
+                            sprite,
+                            &sprite_sheet,
+                            &mut out,
+                            &mut header,
+                            Layout::new(asm, args.plain, args.output.is_some(), &mut scattered),
+                        )?;
+                        if sprite.mask {
+                            print_mask(&mut out, &mut header, &sprite.name, &bytes, mode, asm)?;
+                        }
+                    }
+                    let pixel_width = match mode {
+                        "320A" | "320B" | "320C" | "320D" => 1,
+                        _ => 2,
                     };
-                    if sprite.holeydma && (default_height == 8 || default_height == 16) {
-                        print!("holeydma ");
-                    }
-                    if default_height == 16 && sprite.height < 16 {
-                        // This is a special case: small sprite for 16 holey DMA (a bullet for instance)
-                        print!(
-                            "reversed scattered(16,{}) char {}[{}] = {{\n\t",
-                            bytes.len() / sprite.height as usize,
-                            sprite.name,
-                            bytes.len() / sprite.height as usize * default_height as usize
-                        );
-                        let mut c = 1;
-                        for i in 0..bytes.len() {
-                            print!("0x{:02x}", bytes[i]);
-                            if c % 16 != 0 {
-                                print!(", ");
-                            } else {
-                                print!(",\n\t");
-                            }
-                            c += 1;
+                    let bbox = bounding_box(&img, sprite, pixel_width);
+                    if args.bbox {
+                        print_bbox(&mut out, &mut header, &sprite.name, &bbox, asm)?;
+                    }
+                    // The vertically mirrored copy directly follows the sprite (id + 1)
+                    if let Some(Mirror::Vertical | Mirror::Both) = sprite.mirror {
+                        let bytes = sprite_bytes(
+                            &img,
+                            indices,
+                            sprite,
+                            mode,
+                            &mut colors,
+                            maxcolors,
+                            false,
+                            true,
+                            comment,
+                        )?;
+                        let name = format!("{}_vflip", sprite.name);
+                        metadata.push(SpriteInfo {
+                            name: name.clone(),
+                            gfx: name.clone(),
+                            ..info.clone()
+                        });
+                        arrays.insert(name.clone(), name.clone());
+                        if let Some(dir) = &args.preview {
+                            write_preview(
+                                dir,
+                                &name,
+                                &bytes,
+                                mode,
+                                sprite.variant,
+                                sprite.height,
+                                &colors,
+                            )?;
                         }
-                        for _ in bytes.len()
-                            ..bytes.len() / sprite.height as usize * default_height as usize - 1
-                        {
-                            print!("0x00");
-                            if c % 16 != 0 {
-                                print!(", ");
-                            } else {
-                                print!(",\n\t");
-                            }
-                            c += 1;
+                        print_sprite(
+                            &name,
+                            &bytes,
+                            sprite,
+                            &sprite_sheet,
+                            &mut out,
+                            &mut header,
+                            Layout::new(asm, args.plain, args.output.is_some(), &mut scattered),
+                        )?;
+                        if sprite.mask {
+                            print_mask(&mut out, &mut header, &name, &bytes, mode, asm)?;
                         }
-                        println!("0x00\n}};");
-                    } else {
-                        let nb_sprites = sprite.height / default_height as u32;
-                        if nb_sprites * default_height as u32 != sprite.height {
+                        if args.bbox {
+                            let y = sprite.height.saturating_sub(bbox[1] + bbox[3]);
+                            print_bbox(
+                                &mut out,
+                                &mut header,
+                                &name,
+                                &[bbox[0], y, bbox[2], bbox[3]],
+                                asm,
+                            )?;
+                        }
+                    }
+                    if let Some(Mirror::Horizontal | Mirror::Both) = sprite.mirror {
+                        let bytes = sprite_bytes(
+                            &img,
+                            indices,
+                            sprite,
+                            mode,
+                            &mut colors,
+                            maxcolors,
+                            true,
+                            false,
+                            comment,
+                        )?;
+                        let name = format!("{}_hflip", sprite.name);
+                        let variant =
+                            mirrored_variant(sprite.variant, sprite.width.div_ceil(pixel_width));
+                        metadata.push(SpriteInfo {
+                            name: name.clone(),
+                            gfx: name.clone(),
+                            palette: variant.map_or(info.palette, |v| (info.palette & 4) | v),
+                            ..info.clone()
+                        });
+                        arrays.insert(name.clone(), name.clone());
+                        if let Some(dir) = &args.preview {
+                            write_preview(
+                                dir,
+                                &name,
+                                &bytes,
+                                mode,
+                                variant,
+                                sprite.height,
+                                &colors,
+                            )?;
+                        }
+                        print_sprite(
+                            &name,
+                            &bytes,
+                            sprite,
+                            &sprite_sheet,
+                            &mut out,
+                            &mut header,
+                            Layout::new(asm, args.plain, args.output.is_some(), &mut scattered),
+                        )?;
+                        if sprite.mask {
+                            print_mask(&mut out, &mut header, &name, &bytes, mode, asm)?;
+                        }
+                        if args.bbox {
+                            let x = (sprite.width / pixel_width).saturating_sub(bbox[0] + bbox[2]);
+                            print_bbox(
+                                &mut out,
+                                &mut header,
+                                &name,
+                                &[x, bbox[1], bbox[2], bbox[3]],
+                                asm,
+                            )?;
+                        }
+                    }
+                    if let Some(scale) = &sprite.scale {
+                        // Scaled copy, made out of a resized excerpt of the sprite sheet
+                        let (fx, fy) = scale.factors();
+                        let name = format!("{}_{}x{}", sprite.name, fx, fy);
+                        if let (2, Some(v @ (1 | 2))) = (fx, sprite.variant) {
+                            // Each doubled pixel would be both an even and an odd one
                             return Err(anyhow!(
-                                "Sprite {}: height {} not proportional to default height {}",
+                                "Sprite {}: 320D variant {} can't be scaled horizontally (its pixels colors depend on their column parity)",
                                 sprite.name,
-                                sprite.height,
-                                default_height
+                                v
                             ));
                         }
-                        let mut c = 0;
-                        let l = bytes.len() / nb_sprites as usize;
-                        print!(
-                            "reversed scattered({},{}) char {}[{}] = {{\n\t",
-                            default_height,
-                            l / default_height as usize,
-                            sprite.name,
-                            l
-                        );
-                        for _ in 0..l - 1 {
-                            print!("0x{:02x}", bytes[c]);
-                            if (c + 1) % 16 != 0 {
-                                print!(", ");
+                        let excerpt =
+                            img.crop_imm(sprite.left, sprite.top, sprite.width, sprite.height);
+                        let scaled_img = DynamicImage::ImageRgba8(imageops::resize(
+                            &excerpt,
+                            sprite.width * fx,
+                            sprite.height * fy,
+                            FilterType::Nearest,
+                        ));
+                        let scaled_indices = indices.map(|ix| {
+                            imageops::resize(
+                                &*imageops::crop_imm(
+                                    ix,
+                                    sprite.left,
+                                    sprite.top,
+                                    sprite.width,
+                                    sprite.height,
+                                ),
+                                sprite.width * fx,
+                                sprite.height * fy,
+                                FilterType::Nearest,
+                            )
+                        });
+                        let mut scaled = sprite.clone();
+                        scaled.name = name.clone();
+                        scaled.left = 0;
+                        scaled.top = 0;
+                        scaled.width *= fx;
+                        scaled.height *= fy;
+                        let bytes = sprite_bytes(
+                            &scaled_img,
+                            scaled_indices.as_ref(),
+                            &scaled,
+                            mode,
+                            &mut colors,
+                            maxcolors,
+                            false,
+                            false,
+                            comment,
+                        )?;
+                        metadata.push(SpriteInfo {
+                            name: name.clone(),
+                            gfx: name.clone(),
+                            width: bytes.len() / scaled.height as usize,
+                            height: scaled.height,
+                            holeydma: if holey_dma(&scaled, &sprite_sheet) {
+                                default_height(&scaled, &sprite_sheet)
                             } else {
-                                print!(",\n\t");
-                            }
-                            c += 1;
+                                0
+                            },
+                            size: bytes.len() / scaled.height as usize
+                                * zones_height(&scaled, &sprite_sheet) as usize,
+                            ..info.clone()
+                        });
+                        arrays.insert(name.clone(), name.clone());
+                        if let Some(dir) = &args.preview {
+                            write_preview(
+                                dir,
+                                &name,
+                                &bytes,
+                                mode,
+                                sprite.variant,
+                                scaled.height,
+                                &colors,
+                            )?;
                         }
-                        println!("0x{:02x}\n}};", bytes[c]);
-                        c += 1;
-                        for i in 1..nb_sprites {
-                            if sprite.holeydma && (default_height == 8 || default_height == 16) {
-                                print!("holeydma ");
-                            }
-                            if let Some(b) = bank {
-                                print!("bank{} ", b);
+                        print_sprite(
+                            &name,
+                            &bytes,
+                            &scaled,
+                            &sprite_sheet,
+                            &mut out,
+                            &mut header,
+                            Layout::new(asm, args.plain, args.output.is_some(), &mut scattered),
+                        )?;
+                        if sprite.mask {
+                            print_mask(&mut out, &mut header, &name, &bytes, mode, asm)?;
+                        }
+                        if args.bbox {
+                            let bbox = bounding_box(&scaled_img, &scaled, pixel_width);
+                            print_bbox(&mut out, &mut header, &name, &bbox, asm)?;
+                        }
+                    }
+                    for angle in sprite.rotate.iter().flatten() {
+                        // Rotated copy, made out of the excerpt of the sprite sheet rotated in pixels of the gfx mode
+                        if sprite.width / pixel_width != sprite.height {
+                            return Err(anyhow!(
+                                "Sprite {}: only square sprites can be rotated",
+                                sprite.name
+                            ));
+                        }
+                        let name = format!("{}_rot{}", sprite.name, angle);
+                        let excerpt =
+                            img.crop_imm(sprite.left, sprite.top, sprite.width, sprite.height);
+                        let pixels = imageops::resize(
+                            &excerpt,
+                            sprite.width / pixel_width,
+                            sprite.height,
+                            FilterType::Nearest,
+                        );
+                        let pixels = match angle {
+                            90 => imageops::rotate90(&pixels),
+                            180 => imageops::rotate180(&pixels),
+                            270 => imageops::rotate270(&pixels),
+                            _ => {
+                                return Err(anyhow!(
+                                    "Sprite {}: unsupported rotation {} (should be 90, 180 or 270)",
+                                    sprite.name,
+                                    angle
+                                ))
                             }
-                            print!(
-                                "reversed scattered({},{}) char {}_{}[{}] = {{\n\t",
-                                default_height,
-                                l / default_height as usize,
-                                sprite.name,
-                                i,
-                                l
+                        };
+                        let rotated_img = DynamicImage::ImageRgba8(imageops::resize(
+                            &pixels,
+                            sprite.width,
+                            sprite.height,
+                            FilterType::Nearest,
+                        ));
+                        // The palette indices are rotated the same way
+                        let rotated_indices = indices.map(|ix| {
+                            let excerpt = imageops::crop_imm(
+                                ix,
+                                sprite.left,
+                                sprite.top,
+                                sprite.width,
+                                sprite.height,
                             );
-                            for _ in 0..l - 1 {
-                                print!("0x{:02x}", bytes[c]);
-                                if (c + 1) % 16 != 0 {
-                                    print!(", ");
-                                } else {
-                                    print!(",\n\t");
-                                }
-                                c += 1;
+                            let pixels = imageops::resize(
+                                &*excerpt,
+                                sprite.width / pixel_width,
+                                sprite.height,
+                                FilterType::Nearest,
+                            );
+                            let pixels = match angle {
+                                90 => imageops::rotate90(&pixels),
+                                180 => imageops::rotate180(&pixels),
+                                _ => imageops::rotate270(&pixels),
+                            };
+                            imageops::resize(
+                                &pixels,
+                                sprite.width,
+                                sprite.height,
+                                FilterType::Nearest,
+                            )
+                        });
+                        let mut rotated = sprite.clone();
+                        rotated.name = name.clone();
+                        rotated.left = 0;
+                        rotated.top = 0;
+                        // The rotated pixels parity: a half turn mirrors the sprite horizontally, and a quarter
+                        // turn makes columns of rows, which only keeps the variants that don't depend on it
+                        rotated.variant = match (angle, sprite.variant) {
+                            (180, v) => mirrored_variant(v, sprite.width),
+                            (_, Some(v @ (1 | 2))) => {
+                                return Err(anyhow!(
+                                    "Sprite {}: 320D variant {} can't be rotated by {} degrees (its pixels colors depend on their column parity)",
+                                    sprite.name,
+                                    v,
+                                    angle
+                                ))
                             }
-                            println!("0x{:02x}\n}};", bytes[c]);
-                            c += 1;
+                            (_, v) => v,
+                        };
+                        let bytes = sprite_bytes(
+                            &rotated_img,
+                            rotated_indices.as_ref(),
+                            &rotated,
+                            mode,
+                            &mut colors,
+                            maxcolors,
+                            false,
+                            false,
+                            comment,
+                        )?;
+                        metadata.push(SpriteInfo {
+                            name: name.clone(),
+                            gfx: name.clone(),
+                            palette: rotated
+                                .variant
+                                .map_or(info.palette, |v| (info.palette & 4) | v),
+                            ..info.clone()
+                        });
+                        arrays.insert(name.clone(), name.clone());
+                        if let Some(dir) = &args.preview {
+                            write_preview(
+                                dir,
+                                &name,
+                                &bytes,
+                                mode,
+                                rotated.variant,
+                                sprite.height,
+                                &colors,
+                            )?;
                         }
+                        print_sprite(
+                            &name,
+                            &bytes,
+                            &rotated,
+                            &sprite_sheet,
+                            &mut out,
+                            &mut header,
+                            Layout::new(asm, args.plain, args.output.is_some(), &mut scattered),
+                        )?;
+                        if sprite.mask {
+                            print_mask(&mut out, &mut header, &name, &bytes, mode, asm)?;
+                        }
+                        if args.bbox {
+                            let bbox = bounding_box(&rotated_img, &rotated, pixel_width);
+                            print_bbox(&mut out, &mut header, &name, &bbox, asm)?;
+                        }
+                    }
+                }
+            }
+            let zone_height = default_height(sprite, &sprite_sheet) as u32;
+            if sprite.padded && sprite.alias.is_none() && !args.plain {
+                // The short sprites are plain scattered data of their own height, followed by their padded variant
+                let mut i = first;
+                while i < metadata.len() {
+                    let m = metadata[i].clone();
+                    if m.height >= zone_height || m.gfx != m.name {
+                        i += 1;
+                        continue;
+                    }
+                    metadata[i].holeydma = 0;
+                    metadata[i].size = m.width * m.height as usize;
+                    let name = format!("{}_padded", m.name);
+                    metadata.insert(
+                        i + 1,
+                        SpriteInfo {
+                            name: name.clone(),
+                            gfx: name,
+                            height: zone_height,
+                            ..m
+                        },
+                    );
+                    i += 2;
+                }
+            }
+            if !args.plain {
+                // Each column of a split sprite is a display list entry, with its own metadata
+                let mut i = first;
+                while i < metadata.len() {
+                    let m = metadata[i].clone();
+                    if m.width <= MAX_DL_WIDTH {
+                        i += 1;
+                        continue;
+                    }
+                    if args.no_split {
+                        return Err(anyhow!(
+                            "Sprite {}: {} bytes wide, more than the {} bytes of a display list entry",
+                            m.name,
+                            m.width,
+                            MAX_DL_WIDTH
+                        ));
                     }
+                    let nb_columns = m.width.div_ceil(MAX_DL_WIDTH);
+                    let column_width = m.width.div_ceil(nb_columns);
+                    println!(
+                        "{} Sprite {}: {} bytes wide, split into {} columns",
+                        comment, m.name, m.width, nb_columns
+                    );
+                    let column = |name: &str, c: usize| {
+                        if c == 0 {
+                            name.to_string()
+                        } else {
+                            format!("{}_col{}", name, c)
+                        }
+                    };
+                    let columns: Vec<SpriteInfo> = (0..nb_columns)
+                        .map(|c| {
+                            let width = column_width.min(m.width - c * column_width);
+                            SpriteInfo {
+                                name: column(&m.name, c),
+                                gfx: column(&m.gfx, c),
+                                width,
+                                size: m.size / m.width * width,
+                                ..m.clone()
+                            }
+                        })
+                        .collect();
+                    metadata.splice(i..=i, columns);
+                    i += nb_columns;
                 }
             }
+            if all_sprites.order.is_some() {
+                chunks.push((
+                    sprite.bank.or(sprite_sheet.bank),
+                    sprite_sheet.image.clone(),
+                    std::mem::take(&mut out),
+                ));
+            } else if args.output.is_none() {
+                // Output as we go, so that the warnings are next to the sprite code
+                print!("{out}");
+                out.clear();
+            }
         }
 
         // Generate collisions data
         if let Some(collisions) = sprite_sheet.collisions {
             for collision in collisions.clone() {
+                // A sprite can be referenced as name:hflip or name:vflip to use its mirrored copy
+                let (name1, flip1) = collision
+                    .sprite1
+                    .split_once(':')
+                    .unwrap_or((&collision.sprite1, ""));
+                let (name2, flip2) = collision
+                    .sprite2
+                    .split_once(':')
+                    .unwrap_or((&collision.sprite2, ""));
+                for flip in [flip1, flip2] {
+                    if !flip.is_empty() && flip != "hflip" && flip != "vflip" {
+                        return Err(anyhow!(
+                            "Collision computation: Unknown mirroring {} (should be hflip or vflip)",
+                            flip
+                        ));
+                    }
+                }
                 let mut s1 = None;
                 let mut s2 = None;
                 for s in &sprite_sheet.sprites {
-                    if s.name == collision.sprite1 {
+                    if s.name == name1 {
                         s1 = Some(s);
                     }
-                    if s.name == collision.sprite2 {
+                    if s.name == name2 {
                         s2 = Some(s);
                     }
                 }
@@ -505,35 +3580,9 @@ fn main() -> Result<()> {
                         let w2 = (sp2.width / pixel_width) as usize;
                         let h1 = sp1.height as usize;
                         let h2 = sp2.height as usize;
-                        let mut s1map = vec![false; w1 * h1];
                         // Fill s1map and s2map
-                        for y in 0..h1 {
-                            for x in 0..w1 {
-                                let color = img.get_pixel(
-                                    sp1.left + x as u32 * pixel_width,
-                                    sp1.top + y as u32,
-                                );
-                                if color[3] != 0
-                                    && (color[0] != 0 || color[1] != 0 || color[2] != 0)
-                                {
-                                    s1map[x + y * w1] = true;
-                                }
-                            }
-                        }
-                        let mut s2map = vec![false; w2 * h2];
-                        for y in 0..h2 {
-                            for x in 0..w2 {
-                                let color = img.get_pixel(
-                                    sp2.left + x as u32 * pixel_width,
-                                    sp2.top + y as u32,
-                                );
-                                if color[3] != 0
-                                    && (color[0] != 0 || color[1] != 0 || color[2] != 0)
-                                {
-                                    s2map[x + y * w2] = true;
-                                }
-                            }
-                        }
+                        let s1map = collision_map(&img, sp1, pixel_width, flip1);
+                        let s2map = collision_map(&img, sp2, pixel_width, flip2);
                         // Ok, now we can compute the collision map
                         let mut cmap = vec![false; (w1 + w2 - 1) * (h1 + h2 - 1)];
                         for y in 0..(h1 + h2 - 1) {
@@ -548,11 +3597,10 @@ fn main() -> Result<()> {
                                                 && x2 < w2 as i32
                                                 && y2 >= 0
                                                 && y2 < h2 as i32
+                                                && s2map[x2 as usize + y2 as usize * w2]
                                             {
-                                                if s2map[x2 as usize + y2 as usize * w2] {
-                                                    cmap[x + y * (w1 + w2 - 1)] = true;
-                                                    break;
-                                                }
+                                                cmap[x + y * (w1 + w2 - 1)] = true;
+                                                break;
                                             }
                                         }
                                     }
@@ -573,6 +3621,37 @@ fn main() -> Result<()> {
                         }
                         i += 1;
                         }*/
+                        let name = format!(
+                            "collision_{}_{}",
+                            collision.sprite1.replace(':', "_"),
+                            collision.sprite2.replace(':', "_")
+                        );
+                        if args.collision_format == CollisionFormat::Spans {
+                            print_collision_spans(
+                                &mut out,
+                                &mut header,
+                                &name,
+                                &cmap,
+                                w1 + w2 - 1,
+                                asm,
+                            )?;
+                            if !asm && !spans_routine {
+                                out.push_str(COLLISION_SPANS_TEST);
+                                writeln!(header, "extern char collision_spans_test(const char *spans, const char *rows, char x, char y);")?;
+                                spans_routine = true;
+                            }
+                            if args.collision_helpers {
+                                print_collision_helper(
+                                    &mut out,
+                                    &mut header,
+                                    &name,
+                                    (w1, h1),
+                                    (w1 + w2 - 1, h1 + h2 - 1),
+                                    None,
+                                )?;
+                            }
+                            continue;
+                        }
                         // Store it in binary format
                         let wx = (w1 + w2 - 1) / 8 + 1;
                         let w = if wx <= 2 {
@@ -582,36 +3661,51 @@ fn main() -> Result<()> {
                         } else {
                             8
                         };
-                        print!(
-                            "\nconst char collision_{}_{}[{}] = {{",
-                            &sp1.name,
-                            &sp2.name,
-                            w * (h1 + h2 - 1)
-                        );
-                        let mut c = w * (h1 + h2 - 1);
+                        let mut cbytes = Vec::<String>::new();
                         for y in 0..h1 + h2 - 1 {
                             for wc in 0..w {
                                 let mut b: u8 = 0;
                                 for x in 0..8 {
                                     b <<= 1;
-                                    if x + wc * 8 < w1 + w2 - 1 {
-                                        if cmap[y * (w1 + w2 - 1) + x + wc * 8] {
-                                            b |= 1;
-                                        }
+                                    if x + wc * 8 < w1 + w2 - 1
+                                        && cmap[y * (w1 + w2 - 1) + x + wc * 8]
+                                    {
+                                        b |= 1;
                                     }
                                 }
-                                print!("0x{:02x}", b);
-                                c -= 1;
-                                if c != 0 {
-                                    print!(", ");
-                                }
+                                cbytes.push(if asm {
+                                    format!("${:02x}", b)
+                                } else {
+                                    format!("0x{:02x}", b)
+                                });
+                            }
+                        }
+                        if asm {
+                            print_asm_table(&mut out, &name, ".byte", &cbytes)?;
+                        } else {
+                            writeln!(
+                                out,
+                                "\nconst char {}[{}] = {{{}}};",
+                                name,
+                                cbytes.len(),
+                                cbytes.join(", ")
+                            )?;
+                            writeln!(header, "extern const char {}[{}];", name, cbytes.len())?;
+                            if args.collision_helpers {
+                                print_collision_helper(
+                                    &mut out,
+                                    &mut header,
+                                    &name,
+                                    (w1, h1),
+                                    (w1 + w2 - 1, h1 + h2 - 1),
+                                    Some(w),
+                                )?;
                             }
                         }
-                        println!("}};");
                     } else {
                         return Err(anyhow!(
                             "Collision computation: Unknown sprite2 {}",
-                            collision.sprite1
+                            collision.sprite2
                         ));
                     }
                 } else {
@@ -622,6 +3716,548 @@ fn main() -> Result<()> {
                 }
             }
         }
+
+        // Generate sprite versus tiles collision tables
+        for tc in sprite_sheet.tile_collisions.iter().flatten() {
+            let (name, flip) = tc.sprite.split_once(':').unwrap_or((&tc.sprite, ""));
+            if !flip.is_empty() && flip != "hflip" && flip != "vflip" {
+                return Err(anyhow!(
+                    "Tile collision computation: Unknown mirroring {} (should be hflip or vflip)",
+                    flip
+                ));
+            }
+            let sprite = sprite_sheet
+                .sprites
+                .iter()
+                .find(|s| s.name == name)
+                .ok_or_else(|| anyhow!("Tile collision computation: Unknown sprite {}", name))?;
+            let (tw, th) = (tc.tile_width as usize, tc.tile_height as usize);
+            if tw == 0 || th == 0 {
+                return Err(anyhow!(
+                    "Tile collision computation: {}: tile size should not be 0",
+                    tc.sprite
+                ));
+            }
+            let mode = sprite.mode.as_deref().unwrap_or(sprite_sheet.mode.as_str());
+            let pixel_width = pixel_width(mode);
+            let w = (sprite.width / pixel_width) as usize;
+            let h = sprite.height as usize;
+            let map = collision_map(&img, sprite, pixel_width, flip);
+            // Number of tile columns and rows the sprite can overlap
+            let columns = (w + tw - 1).div_ceil(tw);
+            let rows = (h + th - 1).div_ceil(th);
+            if columns > 8 {
+                return Err(anyhow!(
+                    "Tile collision computation: {} overlaps more than 8 tile columns",
+                    tc.sprite
+                ));
+            }
+            // For each (x, y) offset of the sprite in its top left tile, a byte per tile row with
+            // the bits of the overlapped tile columns (from left to right)
+            let mut tbytes = Vec::<String>::new();
+            for py in 0..th {
+                for px in 0..tw {
+                    let mut tiles = vec![0u8; rows];
+                    for y in 0..h {
+                        for x in 0..w {
+                            if map[x + y * w] {
+                                tiles[(y + py) / th] |= 0x80 >> ((x + px) / tw);
+                            }
+                        }
+                    }
+                    tbytes.extend(tiles.iter().map(|b| {
+                        if asm {
+                            format!("${:02x}", b)
+                        } else {
+                            format!("0x{:02x}", b)
+                        }
+                    }));
+                }
+            }
+            let name = format!("tile_collision_{}", tc.sprite.replace(':', "_"));
+            if asm {
+                writeln!(out, "\n{}_ROWS = {}", name.to_uppercase(), rows)?;
+                print_asm_table(&mut out, &name, ".byte", &tbytes)?;
+            } else {
+                writeln!(
+                    out,
+                    "\nconst char {}[{}] = {{{}}};",
+                    name,
+                    tbytes.len(),
+                    tbytes.join(", ")
+                )?;
+                writeln!(header, "#define {}_ROWS {}", name.to_uppercase(), rows)?;
+                writeln!(header, "extern const char {}[{}];", name, tbytes.len())?;
+            }
+        }
+
+        // Generate animations pointer tables
+        for animation in sprite_sheet.animations.iter().flatten() {
+            let n = animation.frames.len();
+            let mut frames = Vec::<&str>::new();
+            for f in &animation.frames {
+                frames.push(arrays.get(f).ok_or_else(|| {
+                    anyhow!("Animation {}: Unknown sprite {}", animation.name, f)
+                })?);
+            }
+            if let Some(durations) = &animation.durations {
+                if durations.len() != n {
+                    return Err(anyhow!(
+                        "Animation {}: {} durations for {} frames",
+                        animation.name,
+                        durations.len(),
+                        n
+                    ));
+                }
+            }
+            if asm {
+                writeln!(
+                    out,
+                    "\nANIM_{}_FRAMES = {}",
+                    animation.name.to_uppercase(),
+                    n
+                )?;
+                let frames: Vec<String> = frames.iter().map(|f| f.to_string()).collect();
+                print_asm_table(
+                    &mut out,
+                    &format!("anim_{}", animation.name),
+                    ".word",
+                    &frames,
+                )?;
+                if let Some(durations) = &animation.durations {
+                    let durations: Vec<String> = durations.iter().map(|d| d.to_string()).collect();
+                    print_asm_table(
+                        &mut out,
+                        &format!("anim_{}_durations", animation.name),
+                        ".byte",
+                        &durations,
+                    )?;
+                }
+                continue;
+            }
+            // The plain arrays are unsigned
+            let gfx_type = if args.plain {
+                "const unsigned char"
+            } else {
+                "const char"
+            };
+            writeln!(
+                out,
+                "\n{} *anim_{}[{}] = {{{}}};",
+                gfx_type,
+                animation.name,
+                n,
+                frames.join(", ")
+            )?;
+            writeln!(
+                header,
+                "#define ANIM_{}_FRAMES {}",
+                animation.name.to_uppercase(),
+                n
+            )?;
+            writeln!(
+                header,
+                "extern {} *anim_{}[{}];",
+                gfx_type, animation.name, n
+            )?;
+            if let Some(durations) = &animation.durations {
+                writeln!(
+                    out,
+                    "const char anim_{}_durations[{}] = {{{}}};",
+                    animation.name,
+                    n,
+                    durations
+                        .iter()
+                        .map(|d| d.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+                writeln!(
+                    header,
+                    "extern const char anim_{}_durations[{}];",
+                    animation.name, n
+                )?;
+            }
+        }
+        if all_sprites.order.is_some() {
+            chunks.push((
+                sprite_sheet.bank,
+                sprite_sheet.image.clone(),
+                std::mem::take(&mut out),
+            ));
+        } else if args.output.is_none() {
+            print!("{out}");
+            out.clear();
+        }
+    }
+
+    if let Some(order) = &all_sprites.order {
+        // The code of each sprite (and the tables of each sprite sheet) goes to the first group it matches,
+        // in the generation order within a group. The code that matches no group comes last
+        let matches = |key: &str, bank: Option<u8>, image: &str| match key.strip_prefix("bank") {
+            Some("") | None => key == image || (key == "nobank" && bank.is_none()),
+            Some(b) => b.parse::<u8>().ok() == bank || key == image,
+        };
+        for key in order {
+            if !chunks
+                .iter()
+                .any(|(bank, image, _)| matches(key, *bank, image))
+            {
+                return Err(anyhow!(
+                    "Order: {} matches no bank nor sprite sheet image",
+                    key
+                ));
+            }
+        }
+        chunks.sort_by_key(|(bank, image, _)| {
+            order
+                .iter()
+                .position(|key| matches(key, *bank, image))
+                .unwrap_or(order.len())
+        });
+        for (_, _, code) in chunks.drain(..) {
+            out.push_str(&code);
+        }
+    }
+
+    if !auto_assigned.is_empty() {
+        // Summary of the auto-assigned colors, to set up consistent palettes in the init code
+        writeln!(out)?;
+        for (palette, slots) in &auto_assigned {
+            let name = if palette.is_empty() {
+                "Sprites without palette".to_string()
+            } else {
+                format!("Palette {}", palette)
+            };
+            for ((slot, c), sprites) in slots {
+                writeln!(
+                    out,
+                    "{} {}: C{} = {:?} (0x{:02x}) in {}",
+                    comment,
+                    name,
+                    slot,
+                    c,
+                    find_color_in_palette(c),
+                    sprites.join(", ")
+                )?;
+            }
+        }
+    }
+
+    for group in &args.check_shared_palette {
+        // The free slots (black) of a sprite are compatible with any color
+        let mut shared = Vec::<(u8, u8, u8)>::new();
+        let mut owners = Vec::<&str>::new();
+        for name in group.split(',').map(str::trim) {
+            let colors = effective_colors
+                .get(name)
+                .ok_or_else(|| anyhow!("Shared palette check: Unknown sprite {}", name))?;
+            for (i, c) in colors.iter().enumerate() {
+                if i >= shared.len() {
+                    shared.push((0, 0, 0));
+                    owners.push("");
+                }
+                if *c == (0, 0, 0) {
+                    continue;
+                }
+                if shared[i] == (0, 0, 0) {
+                    shared[i] = *c;
+                    owners[i] = name;
+                } else if shared[i] != *c {
+                    return Err(anyhow!(
+                        "Shared palette check: C{} is {:?} in sprite {} but {:?} in sprite {}",
+                        i + 1,
+                        shared[i],
+                        owners[i],
+                        c,
+                        name
+                    ));
+                }
+            }
+        }
+    }
+
+    if args.palettes {
+        for palette in all_sprites.palettes.iter().flatten() {
+            let n = palette.colors.len();
+            if asm {
+                let values: Vec<String> = palette
+                    .colors
+                    .iter()
+                    .map(|c| format!("${:02x}", find_color_in_palette(c)))
+                    .collect();
+                print_asm_table(
+                    &mut out,
+                    &format!("palette_{}", palette.name),
+                    ".byte",
+                    &values,
+                )?;
+                continue;
+            }
+            writeln!(
+                out,
+                "\nconst char palette_{}[{}] = {{{}}};",
+                palette.name,
+                n,
+                palette
+                    .colors
+                    .iter()
+                    .map(|c| format!("0x{:02x}", find_color_in_palette(c)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+            writeln!(header, "extern const char palette_{}[{}];", palette.name, n)?;
+        }
+    }
+
+    if args.metadata || args.enum_ids {
+        let n = metadata.len();
+        // Sprites ids go to the header file if any
+        let ids = if args.output.is_some() && !asm {
+            &mut header
+        } else {
+            &mut out
+        };
+        if args.enum_ids && !asm {
+            // The last enumerator is the number of sprites
+            writeln!(ids, "\nenum sprite_id {{")?;
+            for m in &metadata {
+                writeln!(ids, "\tSPRITE_{},", m.name.to_uppercase())?;
+            }
+            writeln!(ids, "\tSPRITES_NB\n}};")?;
+        } else {
+            let define = if asm { "" } else { "#define " };
+            let equal = if asm { " = " } else { " " };
+            writeln!(ids, "\n{}SPRITES_NB{}{}", define, equal, n)?;
+            for (i, m) in metadata.iter().enumerate() {
+                writeln!(
+                    ids,
+                    "{}SPRITE_{}{}{}",
+                    define,
+                    m.name.to_uppercase(),
+                    equal,
+                    i
+                )?;
+            }
+        }
+    }
+
+    if args.metadata {
+        let n = metadata.len();
+        let tables: [(&str, Vec<String>); 4] = [
+            (
+                "width",
+                metadata.iter().map(|m| m.width.to_string()).collect(),
+            ),
+            (
+                "height",
+                metadata.iter().map(|m| m.height.to_string()).collect(),
+            ),
+            (
+                "mode",
+                metadata.iter().map(|m| m.write_mode.to_string()).collect(),
+            ),
+            (
+                "palette",
+                metadata.iter().map(|m| m.palette.to_string()).collect(),
+            ),
+        ];
+        for (table, values) in tables {
+            if asm {
+                print_asm_table(&mut out, &format!("sprites_{}", table), ".byte", &values)?;
+                continue;
+            }
+            writeln!(
+                out,
+                "const char sprites_{}[{}] = {{{}}};",
+                table,
+                n,
+                values.join(", ")
+            )?;
+            writeln!(header, "extern const char sprites_{}[{}];", table, n)?;
+        }
+    }
+
+    if args.sprite_defs {
+        if asm {
+            return Err(anyhow!(
+                "Sprite descriptors are not supported in assembly format"
+            ));
+        }
+        let n = metadata.len();
+        // The type definition goes to the header file if any
+        let typedef = if args.output.is_some() {
+            &mut header
+        } else {
+            &mut out
+        };
+        if args.plain {
+            // Plain arrays are unsigned, and have no holey DMA layout
+            writeln!(
+                typedef,
+                "\ntypedef struct {{\n\tconst unsigned char *gfx;\n\tchar width;\n\tchar mode;\n\tchar palette;\n}} sprite_def;"
+            )?;
+        } else {
+            writeln!(
+                typedef,
+                "\ntypedef struct {{\n\tchar *gfx;\n\tchar width;\n\tchar mode;\n\tchar palette;\n\tchar holeydma;\n}} sprite_def;"
+            )?;
+        }
+        writeln!(out, "\nconst sprite_def sprite_defs[{}] = {{", n)?;
+        for (i, m) in metadata.iter().enumerate() {
+            let holeydma = if args.plain {
+                String::new()
+            } else {
+                format!(", {}", m.holeydma)
+            };
+            writeln!(
+                out,
+                "\t{{{}, {}, {}, {}{}}}{}",
+                m.gfx,
+                m.width,
+                m.write_mode,
+                m.palette,
+                holeydma,
+                if i + 1 < n { "," } else { "" }
+            )?;
+        }
+        writeln!(out, "}};")?;
+        writeln!(header, "extern const sprite_def sprite_defs[{}];", n)?;
+    }
+
+    if args.holey_report {
+        // Bytes used in each page of the holey DMA regions, per bank and zone height
+        let mut usage = BTreeMap::<(Option<u8>, u8), usize>::new();
+        for m in metadata
+            .iter()
+            .filter(|m| m.holeydma != 0 && m.gfx == m.name)
+        {
+            let arrays = m.height.div_ceil(m.holeydma as u32) as usize;
+            *usage.entry((m.bank, m.holeydma)).or_default() += m.width * arrays;
+        }
+        let mut regions = BTreeMap::<Option<u8>, (usize, usize)>::new();
+        for ((bank, height), bytes) in &usage {
+            let bank_name = bank.map_or("No bank".to_string(), |b| format!("Bank {b}"));
+            let n = bytes.div_ceil(256);
+            println!(
+                "{} {}: {} lines holey DMA data: {} bytes per page, {} {}K region(s)",
+                comment,
+                bank_name,
+                height,
+                bytes,
+                n,
+                height / 4
+            );
+            let r = regions.entry(*bank).or_default();
+            if *height == 16 {
+                r.0 += n;
+            } else {
+                r.1 += n;
+            }
+        }
+        for (bank, (r16, r8)) in regions {
+            // 16 lines data lives in the 4K blocks with A12 clear, 8 lines data in the 2K blocks with A11 clear,
+            // so a 16K bank holds 2 4K regions, or 4 2K regions minus one per 4K region
+            let (bank_name, size) =
+                bank.map_or(("No bank".to_string(), 3), |b| (format!("Bank {b}"), 1));
+            if r16 > 2 * size || r8 + r16 > 4 * size {
+                println!(
+                    "{} Warning: {}: holey DMA data ({} 4K and {} 2K regions) doesn't fit in {}K",
+                    comment,
+                    bank_name,
+                    r16,
+                    r8,
+                    size * 16
+                );
+            }
+        }
+    }
+
+    if args.bank_report {
+        // Graphics bytes per bank, holey DMA and plain scattered data (the aliases share their data)
+        let mut usage = BTreeMap::<Option<u8>, (usize, usize)>::new();
+        for m in metadata.iter().filter(|m| m.gfx == m.name) {
+            let u = usage.entry(m.bank).or_default();
+            if m.holeydma != 0 {
+                u.0 += m.size;
+            } else {
+                u.1 += m.size;
+            }
+        }
+        println!("{} Bank      Holey DMA  Scattered      Total", comment);
+        let mut total = (0, 0);
+        for (bank, (holey, plain)) in &usage {
+            let bank_name = bank.map_or("No bank".to_string(), |b| format!("Bank {b}"));
+            println!(
+                "{} {:<8} {:>10} {:>10} {:>10}",
+                comment,
+                bank_name,
+                holey,
+                plain,
+                holey + plain
+            );
+            total.0 += holey;
+            total.1 += plain;
+        }
+        println!(
+            "{} {:<8} {:>10} {:>10} {:>10}",
+            comment,
+            "Total",
+            total.0,
+            total.1,
+            total.0 + total.1
+        );
+    }
+
+    if args.dma_report {
+        // Direct mode graphics: 3 cycles per byte, plus 8 cycles for a 4 bytes DL header,
+        // or 10 cycles for a 5 bytes one (needed to set the write mode of 160B, 320B and 320C sprites)
+        for m in &metadata {
+            let header_cycles = if m.write_mode == 1 { 10 } else { 8 };
+            println!(
+                "{} Sprite {}: {} byte(s) wide, {} MARIA cycles per scanline ({} for the DL header + {} for the graphics)",
+                comment,
+                m.name,
+                m.width,
+                header_cycles + 3 * m.width,
+                header_cycles,
+                3 * m.width
+            );
+        }
+    }
+
+    if asm {
+        print_asm_scattered(&mut out, &scattered, None)?;
+        // The graphics of each bank go to their own file, to be assembled in the bank segment
+        let banks: BTreeSet<u8> = scattered.iter().filter_map(|a| a.bank).collect();
+        if let Some(basename) = &args.output {
+            for bank in banks {
+                let mut code = String::new();
+                print_asm_scattered(&mut code, &scattered, Some(bank))?;
+                fs::write(format!("{basename}_bank{bank}.asm"), code)?;
+            }
+            fs::write(format!("{basename}.asm"), out)?;
+        } else {
+            print!("{out}");
+        }
+        return Ok(());
+    }
+
+    if let Some(basename) = &args.output {
+        let guard = Path::new(basename)
+            .file_name()
+            .map_or("SPRITES".into(), |f| f.to_string_lossy().to_uppercase())
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>()
+            + "_H";
+        fs::write(format!("{basename}.c"), out)?;
+        fs::write(
+            format!("{basename}.h"),
+            format!("#ifndef {guard}\n#define {guard}\n\n{header}\n#endif\n"),
+        )?;
+    } else {
+        print!("{out}");
     }
 
     Ok(())
@@ -1,11 +1,14 @@
 use anyhow::{anyhow, Result};
 use clap::Parser;
-use image::{GenericImageView, Rgba};
-use serde::Deserialize;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use xml_dom::level2::{Node, NodeType};
 
 //
 // DONE: For lonely and consecutive tiles, automatically switch to immediate mode
@@ -24,9 +27,15 @@ struct Args {
     /// Sparse tiling code generation (provide yaml file)
     #[arg(long = "sparse")]
     yaml: Option<String>,
-    /// Generated array name (default: tilemap)
+    /// Generated array name (default: layer name if a layer is selected, tilemap otherwise)
     #[arg(short, long)]
     varname: Option<String>,
+    /// Name of the layer to convert (default: first layer)
+    #[arg(short, long)]
+    layer: Option<String>,
+    /// Tile used to fill empty or unknown cells (tile name, or tile number)
+    #[arg(long)]
+    fill: Option<String>,
     /// Tileset maximum size
     #[arg(short, long)]
     maxsize: Option<usize>,
@@ -39,6 +48,44 @@ struct Args {
     /// Forbid immediate mode usage when generating tilesets
     #[arg(short = 'f', long, default_value = "false")]
     forbid_immediate: bool,
+    /// Select immediate or indirect mode for each tileset by comparing their byte cost (with a report of the decisions)
+    #[arg(
+        short = 'a',
+        long,
+        default_value = "false",
+        conflicts_with = "immediate"
+    )]
+    auto_immediate: bool,
+    /// Maximum size in bytes of a row headers list (7 bytes per tileset, i.e. the sparse_tiling.h row buffer size)
+    #[arg(long)]
+    max_row_bytes: Option<usize>,
+    /// Zone height (8 or 16). Rows of 8 pixels high tiles are grouped by 2 in 16 pixels high zones
+    #[arg(long)]
+    zone_height: Option<u32>,
+    /// Cache file used to reuse the code generated for the unchanged rows (sparse tiling only)
+    #[arg(long)]
+    cache: Option<String>,
+    /// Screen width (in tiles) used to split the map into screens (for flip-screen games)
+    #[arg(long)]
+    screen_width: Option<usize>,
+    /// Direct mode: generate ready to use display lists headers (5 bytes per tileset) instead of sparse_tiling.h data
+    #[arg(long, default_value = "false")]
+    direct: bool,
+    /// Horizontal offset (in pixels) added to the X position of headers in direct mode
+    #[arg(long, default_value = "0", allow_negative_numbers = true)]
+    offset: i16,
+    /// Export the objects of the object layers (grouped by class, as C structs)
+    #[arg(long, default_value = "false")]
+    objects: bool,
+    /// Generate RAM copies of the indirect tilesets (for destructible terrain), with the tables and the C function to initialize them
+    #[arg(long, default_value = "false")]
+    ram: bool,
+    /// Maximum DMA cycles per row (indirect tilesets are converted to immediate mode to respect it)
+    #[arg(long)]
+    max_dma: Option<usize>,
+    /// JSON file reporting the tiles mapping (Tiled GID, Atari index, palette, mode, bank) and the arrays used by each row (sparse tiling only)
+    #[arg(long)]
+    map_report: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -46,6 +93,17 @@ struct AllSprites {
     #[serde(default)]
     palettes: Option<Vec<Palette>>,
     sprite_sheets: Vec<SpriteSheet>,
+    #[serde(default)]
+    attributes: Attributes,
+}
+
+// cc7800 attributes (aligned(256), section names...) prepended to the generated arrays
+#[derive(Deserialize, Default)]
+struct Attributes {
+    rows: Option<String>,      // Rows data (tilesets headers)
+    tilesets: Option<String>,  // Tilesets (indirect tile numbers or immediate graphics)
+    sequences: Option<String>, // Pregenerated sequences
+    tables: Option<String>,    // Rows pointers and other tables
 }
 
 #[derive(Deserialize)]
@@ -86,6 +144,13 @@ enum Mirror {
     Both,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Priority {
+    Foreground,
+    Background,
+}
+
 #[allow(unused)]
 #[derive(Deserialize)]
 struct Sprite {
@@ -111,6 +176,19 @@ struct Sprite {
     background: Option<String>,
     #[serde(default)]
     fake: Option<bool>,
+    #[serde(default)]
+    priority: Option<Priority>,
+    #[serde(default)]
+    cycle: Option<Cycle>,
+}
+
+// Palette cycling info (color rotation of one palette register)
+#[derive(Deserialize)]
+struct Cycle {
+    palette: Option<u8>, // Palette number (default: the tile palette number)
+    color: u8,           // Color index in the palette (1 to 3)
+    period: u8,          // Number of frames between 2 rotations
+    colors: Vec<u8>,     // Atari colors to cycle through
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +199,43 @@ struct Tile<'a> {
     background: Option<u32>,
     gfx: Vec<u8>,
     fake: bool,
+    priority: Option<Priority>,
+}
+
+// Code generated for a row of tiles, kept in the cache file between runs
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedRow {
+    hash: u64,
+    output: String,                       // Generated tilesets arrays
+    store: Vec<(String, Vec<u32>, bool)>, // Tilesets (tile numbers) defined by this row
+    refs: Vec<(String, Vec<u32>, bool)>,  // Tilesets (tile numbers) referenced by this row
+    tilemap: String,                      // Row tilesets headers
+    nsets: usize,
+    #[serde(default)]
+    ram: usize, // Size of the RAM copy of the row indirect tilesets
+    #[serde(default)]
+    dma: usize, // DMA cycles of the row tilesets (with --max-dma)
+}
+
+// Tileset reference found in the TMX file (either inline or in an external TSX file)
+#[derive(Debug)]
+struct Tileset {
+    firstgid: u32,
+    image: Option<String>,
+    imagewidth: Option<u32>,
+    offset: (i32, i32),
+    margin: u32,
+    spacing: u32,
+    properties: HashMap<u32, HashMap<String, String>>, // Tiles custom properties, by tile id
+    source: Option<PathBuf>,                           // External tileset file
+}
+
+// Object found in an object layer
+struct Object {
+    class: String,
+    x: i32,
+    y: i32,
+    properties: Vec<(String, String, String)>, // Custom properties (name, type, value)
 }
 
 fn default_sprite_size() -> u32 {
@@ -170,10 +285,8 @@ fn sprite_gfx(
             if let Some(pname) = &sprite.palette {
                 let px = palettes.iter().find(|x| &x.name == pname);
                 if let Some(p) = px {
-                    let mut i = 0;
-                    for c in &p.colors {
+                    for (i, c) in p.colors.iter().enumerate() {
                         colors[i] = *c;
-                        i += 1;
                     }
                 }
             }
@@ -194,9 +307,8 @@ fn sprite_gfx(
                 || (sprite.palette.is_none() && (color[0] != 0 || color[1] != 0 || color[2] != 0))
             {
                 // Not transparent
-                for c in 0..maxcolors {
-                    if color[0] == colors[c].0 && color[1] == colors[c].1 && color[2] == colors[c].2
-                    {
+                for (c, cc) in colors.iter().enumerate().take(maxcolors) {
+                    if color[0] == cc.0 && color[1] == cc.1 && color[2] == cc.2 {
                         // Ok. this is a pixel of color c
                         cx = Some((c + 1) as u8);
                         // 320C mode contraint check
@@ -224,11 +336,11 @@ fn sprite_gfx(
                     cx = Some(0); // Background color (either black or transparent)
                 } else {
                     // Let's find a unaffected color
-                    for c in 0..maxcolors {
-                        if colors[c].0 == 0 && colors[c].1 == 0 && colors[c].2 == 0 {
-                            colors[c].0 = color[0];
-                            colors[c].1 = color[1];
-                            colors[c].2 = color[2];
+                    for (c, cc) in colors.iter_mut().enumerate().take(maxcolors) {
+                        if cc.0 == 0 && cc.1 == 0 && cc.2 == 0 {
+                            cc.0 = color[0];
+                            cc.1 = color[1];
+                            cc.2 = color[2];
                             cx = Some((c + 1) as u8);
                             //println!("color {c} affected to {:?}", color);
                             if mode == "320C" {
@@ -351,78 +463,682 @@ fn sprite_gfx(
     Ok(bytes)
 }
 
-fn main() -> Result<()> {
-    let mut width = 0;
-    let mut height = 0;
-    let mut tilewidth: u32 = 8;
-    let mut tileheight: u32 = 8;
-    let args = Args::parse();
-    let xml = fs::read_to_string(args.filename).expect("Unable to read input file");
-    let varname = args.varname.unwrap_or("tilemap".into());
-
-    let dom = xml_dom::parser::read_xml(&xml)?;
-    let root = dom.first_child().unwrap();
-    if root.local_name() == "map" {
-        for a in &root.attributes() {
-            if a.0.local_name() == "tileheight" {
-                let h = a.1.first_child().unwrap().node_value();
-                if let Some(s) = h {
-                    tileheight = s.parse::<u32>()?;
-                }
+// XML element of a TMX/TSX/TX file. The CSV data of the selected layers is decoded while reading the file,
+// so that the text of huge maps is never kept (the data of the other layers being skipped)
+#[derive(Default)]
+struct Element {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+    cells: Vec<u32>, // Decoded layer data
+}
+
+impl Element {
+    fn new(e: &BytesStart) -> Result<Element> {
+        let mut attributes = Vec::new();
+        for a in e.attributes() {
+            let a = a?;
+            attributes.push((
+                String::from_utf8_lossy(a.key.local_name().as_ref()).into_owned(),
+                a.unescape_value()?.into_owned(),
+            ));
+        }
+        Ok(Element {
+            name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+            attributes,
+            ..Default::default()
+        })
+    }
+}
+
+// Read a XML file with a streaming parser, returning its root element. Only the data of the given layer (or
+// of all the layers) is decoded
+fn read_xml(path: &Path, layer: Option<&str>) -> Result<Element> {
+    let mut reader = Reader::from_file(path)?;
+    let mut buf = Vec::new();
+    let mut stack = vec![Element::default()];
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => stack.push(Element::new(&e)?),
+            Event::Empty(e) => {
+                let element = Element::new(&e)?;
+                stack.last_mut().unwrap().children.push(element);
             }
-            if a.0.local_name() == "tilewidth" {
-                let h = a.1.first_child().unwrap().node_value();
-                if let Some(s) = h {
-                    tilewidth = s.parse::<u32>()?;
+            Event::End(_) => {
+                let element = stack.pop().unwrap();
+                stack
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("Unexpected end tag in {}", path.display()))?
+                    .children
+                    .push(element);
+            }
+            Event::Text(t) => {
+                let selected = stack.len() >= 2 && {
+                    let parent = &stack[stack.len() - 2];
+                    parent.name == "layer"
+                        && (layer.is_none() || attribute(parent, "name").as_deref() == layer)
+                };
+                let element = stack.last_mut().unwrap();
+                if element.name == "data" && !selected {
+                    // Data of a layer which isn't converted
+                } else if element.name == "data"
+                    && attribute(element, "encoding").as_deref() == Some("csv")
+                {
+                    // CSV layer data
+                    let text = t.unescape()?;
+                    for v in text.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+                        element.cells.push(u32::from_str(v)?);
+                    }
+                } else {
+                    let text = t.unescape()?;
+                    element.text.push_str(&text);
                 }
             }
+            Event::CData(t) => {
+                let element = stack.last_mut().unwrap();
+                element.text.push_str(&String::from_utf8_lossy(&t));
+            }
+            Event::Eof => break,
+            _ => (),
         }
+        buf.clear();
     }
-    let mut imagewidth = None;
-    for n in &root.child_nodes() {
-        if n.node_type() == NodeType::Element && n.local_name() == "tileset" {
-            for nx in &n.child_nodes() {
-                if nx.node_type() == NodeType::Element && nx.local_name() == "image" {
-                    for a in &nx.attributes() {
-                        if a.0.local_name() == "width" {
-                            let h = a.1.first_child().unwrap().node_value();
-                            if let Some(s) = h {
-                                imagewidth = s.parse::<u32>().ok();
-                            }
+    if stack.len() != 1 {
+        return Err(anyhow!("Unexpected end of file {}", path.display()));
+    }
+    stack
+        .pop()
+        .unwrap()
+        .children
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No root element in {}", path.display()))
+}
+
+fn attribute(node: &Element, name: &str) -> Option<String> {
+    node.attributes
+        .iter()
+        .find(|a| a.0 == name)
+        .map(|a| a.1.clone())
+}
+
+fn parse_tileset(node: &Element, dir: &Path) -> Result<Tileset> {
+    let mut tileset = Tileset {
+        firstgid: attribute(node, "firstgid")
+            .map(|s| s.parse::<u32>())
+            .transpose()?
+            .unwrap_or(1),
+        image: None,
+        imagewidth: None,
+        offset: (0, 0),
+        margin: 0,
+        spacing: 0,
+        properties: HashMap::new(),
+        source: None,
+    };
+    let external;
+    let node = if let Some(source) = attribute(node, "source") {
+        // External tileset. Its path is relative to the TMX file
+        let path = dir.join(source);
+        external = read_xml(&path, None)
+            .map_err(|e| anyhow!("Unable to read tileset {}: {}", path.display(), e))?;
+        if external.name != "tileset" {
+            return Err(anyhow!("{} is not a tileset file", path.display()));
+        }
+        tileset.source = Some(fs::canonicalize(&path).unwrap_or(path));
+        &external
+    } else {
+        node
+    };
+    if let Some(s) = attribute(node, "margin") {
+        tileset.margin = s.parse::<u32>()?;
+    }
+    if let Some(s) = attribute(node, "spacing") {
+        tileset.spacing = s.parse::<u32>()?;
+    }
+    for nx in &node.children {
+        if nx.name == "image" {
+            tileset.image = attribute(nx, "source");
+            if let Some(s) = attribute(nx, "width") {
+                tileset.imagewidth = s.parse::<u32>().ok();
+            }
+        } else if nx.name == "tileoffset" {
+            let x = attribute(nx, "x").unwrap_or("0".into()).parse::<i32>()?;
+            let y = attribute(nx, "y").unwrap_or("0".into()).parse::<i32>()?;
+            tileset.offset = (x, y);
+        } else if nx.name == "tile" {
+            if let Some(id) = attribute(nx, "id") {
+                let mut properties = HashMap::new();
+                for np in nx.children.iter().filter(|n| n.name == "properties") {
+                    for property in np.children.iter().filter(|n| n.name == "property") {
+                        if let Some(name) = attribute(property, "name") {
+                            properties
+                                .insert(name, attribute(property, "value").unwrap_or_default());
                         }
                     }
                 }
+                tileset.properties.insert(id.parse::<u32>()?, properties);
+            }
+        }
+    }
+    Ok(tileset)
+}
+
+// Make a C identifier out of a Tiled name
+fn c_identifier(name: &str) -> String {
+    let mut v: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !v.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        v.insert(0, '_');
+    }
+    v
+}
+
+fn parse_properties(node: &Element, properties: &mut Vec<(String, String, String)>) {
+    for np in node.children.iter().filter(|n| n.name == "properties") {
+        for property in np.children.iter().filter(|n| n.name == "property") {
+            if let Some(name) = attribute(property, "name") {
+                let ptype = attribute(property, "type").unwrap_or("string".into());
+                // Multiline strings are stored as the property text
+                let value = attribute(property, "value").unwrap_or_else(|| property.text.clone());
+                properties.retain(|p| p.0 != name);
+                properties.push((name, ptype, value));
+            }
+        }
+    }
+}
+
+// Object template (.tx file): the template object, and the tileset used by its tile (firstgid, path)
+type Template = (Element, Option<(u32, PathBuf)>);
+
+fn parse_template(path: &Path) -> Result<Template> {
+    let root = read_xml(path, None)
+        .map_err(|e| anyhow!("Unable to read template {}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut object = None;
+    let mut tileset = None;
+    for n in root.children {
+        if n.name == "object" {
+            object = Some(n);
+        } else if n.name == "tileset" {
+            if let Some(source) = attribute(&n, "source") {
+                let firstgid = attribute(&n, "firstgid")
+                    .map(|s| s.parse::<u32>())
+                    .transpose()?
+                    .unwrap_or(1);
+                let path = dir.join(source);
+                tileset = Some((firstgid, fs::canonicalize(&path).unwrap_or(path)));
             }
-        } else if n.node_type() == NodeType::Element && n.local_name() == "layer" {
-            for a in &n.attributes() {
-                if a.0.local_name() == "width" {
-                    let w = a.1.first_child().unwrap().node_value();
-                    if let Some(s) = w {
-                        width = s.parse::<usize>()?;
-                        //println!("Tilemap width = {}", width);
+        }
+    }
+    let object = object.ok_or_else(|| anyhow!("No object in template {}", path.display()))?;
+    Ok((object, tileset))
+}
+
+fn parse_object(
+    node: &Element,
+    tilesets: &[Tileset],
+    dir: &Path,
+    templates: &mut HashMap<String, Template>,
+) -> Result<Option<Object>> {
+    // Objects instantiated from a template only store the overridden attributes and properties
+    let template = if let Some(t) = attribute(node, "template") {
+        if !templates.contains_key(&t) {
+            // The template path is relative to the TMX file
+            let parsed = parse_template(&dir.join(&t))?;
+            templates.insert(t.clone(), parsed);
+        }
+        templates.get(&t)
+    } else {
+        None
+    };
+    let attr =
+        |name| attribute(node, name).or_else(|| template.and_then(|t| attribute(&t.0, name)));
+    // Tiled 1.9+ uses "class", older versions "type"
+    let class = attr("class").or(attr("type"));
+    let Some(class) = class.filter(|c| !c.is_empty()) else {
+        return Ok(None);
+    };
+    let coordinate = |name| -> Result<f64> {
+        Ok(attr(name)
+            .map(|s| s.parse::<f64>())
+            .transpose()?
+            .unwrap_or(0.0))
+    };
+    let mut x = coordinate("x")?;
+    let mut y = coordinate("y")?;
+    let gid = if let Some(gid) = attribute(node, "gid") {
+        Some(gid.parse::<u32>()? & 0x0fffffff) // Remove the flipping flags
+    } else if let Some((tnode, tileset)) = template {
+        // The template tile refers to the template tileset: find the same tileset in the map
+        if let Some(gid) = attribute(tnode, "gid") {
+            let gid = gid.parse::<u32>()? & 0x0fffffff;
+            let (firstgid, path) = tileset
+                .as_ref()
+                .ok_or_else(|| anyhow!("No tileset for the tile of template {}", class))?;
+            let ts = tilesets
+                .iter()
+                .find(|ts| ts.source.as_ref() == Some(path))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Tileset {} of template {} is not used by the map",
+                        path.display(),
+                        class
+                    )
+                })?;
+            Some(gid - firstgid + ts.firstgid)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+    if let Some(gid) = gid {
+        // Tile object: its position is the bottom left corner, shifted by the tileset tileoffset
+        y -= coordinate("height")?;
+        if let Some(ts) = tilesets
+            .iter()
+            .filter(|ts| ts.firstgid <= gid)
+            .max_by_key(|ts| ts.firstgid)
+        {
+            x += ts.offset.0 as f64;
+            y += ts.offset.1 as f64;
+        }
+    }
+    let mut properties = Vec::new();
+    if let Some(t) = template {
+        parse_properties(&t.0, &mut properties);
+    }
+    parse_properties(node, &mut properties);
+    Ok(Some(Object {
+        class,
+        x: x.round() as i32,
+        y: y.round() as i32,
+        properties,
+    }))
+}
+
+// Output one C struct definition and one array per object class
+fn export_objects(objects: &[Object], varname: &str) -> Result<()> {
+    let mut classes = Vec::<&str>::new();
+    for o in objects {
+        if !classes.contains(&o.class.as_str()) {
+            classes.push(&o.class);
+        }
+    }
+    for class in classes {
+        let objects = objects
+            .iter()
+            .filter(|o| o.class == class)
+            .collect::<Vec<_>>();
+        let cname = c_identifier(class).to_lowercase();
+        // Fields (name, Tiled type) in order of appearance
+        let mut fields = vec![
+            ("x".to_string(), "int".to_string()),
+            ("y".to_string(), "int".to_string()),
+        ];
+        for o in &objects {
+            for p in &o.properties {
+                if let Some(f) = fields.iter().find(|f| f.0 == p.0) {
+                    if f.1 != p.1 {
+                        return Err(anyhow!(
+                            "Class {}: property {} has different types ({} and {})",
+                            class,
+                            p.0,
+                            f.1,
+                            p.1
+                        ));
                     }
+                } else {
+                    fields.push((p.0.clone(), p.1.clone()));
                 }
-                if a.0.local_name() == "height" {
-                    let h = a.1.first_child().unwrap().node_value();
-                    if let Some(s) = h {
-                        height = s.parse::<usize>()?;
-                        //println!("Tilemap height = {}", height);
+            }
+        }
+        // Values of each object, in C syntax. Missing properties are set to 0
+        let mut values = Vec::<Vec<String>>::new();
+        for o in &objects {
+            let mut v = vec![o.x.to_string(), o.y.to_string()];
+            for f in &fields[2..] {
+                let value = o
+                    .properties
+                    .iter()
+                    .find(|p| p.0 == f.0)
+                    .map(|p| p.2.as_str());
+                v.push(
+                    match f.1.as_str() {
+                        "bool" => (value == Some("true")) as u8 as i32,
+                        "int" | "object" => {
+                            value.map(|s| s.parse::<i32>()).transpose()?.unwrap_or(0)
+                        }
+                        "float" => value
+                            .map(|s| s.parse::<f64>())
+                            .transpose()?
+                            .unwrap_or(0.0)
+                            .round() as i32,
+                        _ => {
+                            v.push(format!(
+                                "\"{}\"",
+                                value
+                                    .unwrap_or_default()
+                                    .replace('\\', "\\\\")
+                                    .replace('"', "\\\"")
+                            ));
+                            continue;
+                        }
+                    }
+                    .to_string(),
+                );
+            }
+            values.push(v);
+        }
+        // Field types inferred from the property types and values
+        println!("struct {} {{", cname);
+        for (i, f) in fields.iter().enumerate() {
+            let ctype = match f.1.as_str() {
+                "bool" | "int" | "object" | "float" => {
+                    if values
+                        .iter()
+                        .all(|v| v[i].parse::<i32>().is_ok_and(|n| (0..256).contains(&n)))
+                    {
+                        "char"
+                    } else {
+                        "short"
                     }
                 }
+                _ => "const char *",
+            };
+            let separator = if ctype.ends_with('*') { "" } else { " " };
+            println!("\t{}{}{};", ctype, separator, c_identifier(&f.0));
+        }
+        println!("}};\n");
+        println!(
+            "#define {}_{}_COUNT {}",
+            varname.to_uppercase(),
+            cname.to_uppercase(),
+            objects.len()
+        );
+        println!(
+            "const struct {} {}_{}[{}] = {{\n\t{}\n}};\n",
+            cname,
+            varname,
+            cname,
+            objects.len(),
+            values
+                .iter()
+                .map(|v| format!("{{{}}}", v.join(", ")))
+                .collect::<Vec<_>>()
+                .join(",\n\t")
+        );
+    }
+    Ok(())
+}
+
+// Tiled draws the tiles of a tileset with a <tileoffset> shifted by the given number of pixels.
+// Move these cells to where they are actually displayed. Only whole tiles offsets can be
+// represented in the generated data.
+fn apply_tileoffsets(
+    array: Vec<u32>,
+    width: usize,
+    height: usize,
+    tilesets: &[Tileset],
+    tilewidth: u32,
+    tileheight: u32,
+) -> Result<Vec<u32>> {
+    if tilesets.iter().all(|t| t.offset == (0, 0)) {
+        return Ok(array);
+    }
+    for t in tilesets {
+        if t.offset.0 % tilewidth as i32 != 0 || t.offset.1 % tileheight as i32 != 0 {
+            return Err(anyhow!(
+                "Tileset (firstgid = {}): offset ({}, {}) is not a multiple of the tile size ({}x{})",
+                t.firstgid,
+                t.offset.0,
+                t.offset.1,
+                tilewidth,
+                tileheight
+            ));
+        }
+    }
+    let mut shifted = vec![0u32; array.len()];
+    let mut lost = 0;
+    for y in 0..height {
+        for x in 0..width {
+            let cell = array[y * width + x];
+            if cell == 0 {
+                continue;
+            }
+            let offset = tilesets
+                .iter()
+                .filter(|t| t.firstgid <= cell)
+                .max_by_key(|t| t.firstgid)
+                .map_or((0, 0), |t| t.offset);
+            let xx = x as i32 + offset.0 / tilewidth as i32;
+            let yy = y as i32 + offset.1 / tileheight as i32;
+            if xx >= 0 && xx < width as i32 && yy >= 0 && yy < height as i32 {
+                shifted[yy as usize * width + xx as usize] = cell;
+            } else {
+                lost += 1;
+            }
+        }
+    }
+    if lost != 0 {
+        eprintln!(
+            "{} tiles are moved out of the map by their tileset offset",
+            lost
+        );
+    }
+    Ok(shifted)
+}
+
+// Remove the outer margin and the spacing between tiles from a tileset image, so that tiles are
+// contiguous and the usual GID computations apply
+fn pack_tileset_image(
+    img: &DynamicImage,
+    image_width: u32,
+    tilewidth: u32,
+    tileheight: u32,
+    margin: u32,
+    spacing: u32,
+) -> RgbaImage {
+    let columns = (image_width + spacing).saturating_sub(2 * margin) / (tilewidth + spacing);
+    let rows = (img.height() + spacing).saturating_sub(2 * margin) / (tileheight + spacing);
+    let mut packed = RgbaImage::new(columns * tilewidth, rows * tileheight);
+    for (x, y, p) in packed.enumerate_pixels_mut() {
+        let xx = margin + x / tilewidth * (tilewidth + spacing) + x % tilewidth;
+        let yy = margin + y / tileheight * (tileheight + spacing) + y % tileheight;
+        if xx < img.width() && yy < img.height() {
+            *p = img.get_pixel(xx, yy);
+        }
+    }
+    packed
+}
+
+// Convert a tileset image position to the corresponding position in the packed image
+fn unspace_position(v: u32, size: u32, margin: u32, spacing: u32) -> u32 {
+    let v = v.saturating_sub(margin);
+    v / (size + spacing) * size + v % (size + spacing)
+}
+
+// Convert a tileset image extent (including spacing) to the corresponding packed extent
+fn unspace_extent(v: u32, size: u32, spacing: u32) -> u32 {
+    v - v / (size + spacing) * spacing
+}
+
+// DMA cycles used by a tileset (header + graphics)
+fn tileset_dma(immediate: bool, width: usize) -> usize {
+    if immediate {
+        (10 + 3 * width) / 2
+    } else {
+        (10 + 3 + 9 * width) / 2
+    }
+}
+
+// Header mode (immediate or not) and width of a tileset, as it will be generated
+fn tileset_layout(
+    tn: &[u32],
+    continuous: bool,
+    forced_immediate: bool,
+    forbid_immediate: bool,
+    immediate: bool,
+    tiles_store: &[(String, Vec<u32>, bool)],
+    bytes_per_tile: usize,
+) -> (bool, usize) {
+    let immediate = if continuous && !forbid_immediate && !immediate && !forced_immediate {
+        true
+    } else {
+        tiles_store
+            .iter()
+            .find(|c| (c.2 || !forced_immediate) && c.1.windows(tn.len()).any(|w| tn == w))
+            .map_or(immediate || forced_immediate, |c| c.2)
+    };
+    if immediate {
+        (true, tn.len() * bytes_per_tile)
+    } else {
+        (false, tn.len())
+    }
+}
+
+// Reorder the tilesets of a row so that tilesets using the same write mode are consecutive,
+// keeping the drawing order of the tilesets that overlap
+fn group_write_modes(tilesets: Vec<(u32, Vec<Tile>)>) -> Vec<(u32, Vec<Tile>)> {
+    let write_mode = |s: &(u32, Vec<Tile>)| !matches!(s.1[0].mode, "160A" | "320A" | "320D");
+    let overlap = |a: &(u32, Vec<Tile>), b: &(u32, Vec<Tile>)| {
+        a.0 < b.0 + b.1.len() as u32 && b.0 < a.0 + a.1.len() as u32
+    };
+    let mut remaining: Vec<Option<(u32, Vec<Tile>)>> = tilesets.into_iter().map(Some).collect();
+    let mut result = Vec::with_capacity(remaining.len());
+    let mut current = None;
+    while result.len() < remaining.len() {
+        // Tilesets that can be drawn now (all the overlapping tilesets before them are drawn)
+        let ready = (0..remaining.len())
+            .filter(|&i| {
+                remaining[i]
+                    .as_ref()
+                    .is_some_and(|s| remaining[..i].iter().flatten().all(|p| !overlap(p, s)))
+            })
+            .collect::<Vec<_>>();
+        let i = ready
+            .iter()
+            .find(|&&i| Some(write_mode(remaining[i].as_ref().unwrap())) == current)
+            .unwrap_or(&ready[0]);
+        let s = remaining[*i].take().unwrap();
+        current = Some(write_mode(&s));
+        result.push(s);
+    }
+    result
+}
+
+// Close a tileset in construction, putting it in front of the tilesets of the row (background) or after them
+fn close_tileset<'a>(
+    tilesets: &mut VecDeque<(u32, Vec<Tile<'a>>)>,
+    tileset: &mut Vec<Tile<'a>>,
+    startx: u32,
+    front: bool,
+) {
+    if !tileset.is_empty() {
+        let tileset = std::mem::take(tileset);
+        if front {
+            tilesets.push_front((startx, tileset));
+        } else {
+            tilesets.push_back((startx, tileset));
+        }
+    }
+}
+
+// Add a tile at x to a tileset in construction, which is closed first if the tile isn't compatible with it or
+// if it is full
+fn push_tile<'a>(
+    tilesets: &mut VecDeque<(u32, Vec<Tile<'a>>)>,
+    tileset: &mut Vec<Tile<'a>>,
+    startx: &mut u32,
+    tile: &Tile<'a>,
+    x: u32,
+    maxsize: usize,
+    front: bool,
+) {
+    if let Some(tx) = tileset.last() {
+        if !(tile.mode == tx.mode
+            && tile.palette_number == tx.palette_number
+            && tile.fake == tx.fake)
+            || tileset.len() >= maxsize
+        {
+            close_tileset(tilesets, tileset, *startx, front);
+        }
+    }
+    if tileset.is_empty() {
+        *startx = x;
+    }
+    tileset.push(tile.clone());
+}
+
+fn main() -> Result<()> {
+    let mut width = 0;
+    let mut height = 0;
+    let mut tilewidth: u32 = 8;
+    let mut tileheight: u32 = 8;
+    let args = Args::parse();
+    let dir = Path::new(&args.filename)
+        .parent()
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    let varname = if let Some(v) = &args.varname {
+        v.clone()
+    } else if let Some(l) = &args.layer {
+        c_identifier(l)
+    } else {
+        "tilemap".into()
+    };
+
+    let mut root = read_xml(Path::new(&args.filename), args.layer.as_deref())
+        .map_err(|e| anyhow!("Unable to read input file {}: {}", args.filename, e))?;
+    if root.name == "map" {
+        if let Some(s) = attribute(&root, "tileheight") {
+            tileheight = s.parse::<u32>()?;
+        }
+        if let Some(s) = attribute(&root, "tilewidth") {
+            tilewidth = s.parse::<u32>()?;
+        }
+    }
+    let mut tilesets = Vec::<Tileset>::new();
+    for n in &root.children {
+        if n.name == "tileset" {
+            tilesets.push(parse_tileset(n, &dir)?);
+        }
+    }
+    if args.objects {
+        let mut objects = Vec::new();
+        let mut templates = HashMap::new();
+        for n in root.children.iter().filter(|n| n.name == "objectgroup") {
+            for o in n.children.iter().filter(|n| n.name == "object") {
+                if let Some(object) = parse_object(o, &tilesets, &dir, &mut templates)? {
+                    objects.push(object);
+                }
+            }
+        }
+        export_objects(&objects, &varname)?;
+    }
+    for n in &mut root.children {
+        if n.name == "layer" && (args.layer.is_none() || attribute(n, "name") == args.layer) {
+            if let Some(s) = attribute(n, "width") {
+                width = s.parse::<usize>()?;
+                //println!("Tilemap width = {}", width);
+            }
+            if let Some(s) = attribute(n, "height") {
+                height = s.parse::<usize>()?;
+                //println!("Tilemap height = {}", height);
             }
-            for nx in &n.child_nodes() {
-                if nx.node_type() == NodeType::Element && nx.local_name() == "data" {
-                    let t = nx.first_child().unwrap();
-                    if t.node_type() == NodeType::Text {
-                        let csv = t.node_value().unwrap();
-                        let csv: String = csv.split_whitespace().collect();
-                        //println!("Tiles: {}", csv);
-                        let array = csv
-                            .split(',')
-                            .map(|x| u32::from_str(x).unwrap())
-                            .collect::<Vec<_>>();
+            for nx in &mut n.children {
+                if nx.name == "data" {
+                    if attribute(nx, "encoding").as_deref() == Some("csv") {
+                        let array = std::mem::take(&mut nx.cells);
                         if array.len() == width * height {
-                            if let Some(yaml_file) = args.yaml {
+                            let array = apply_tileoffsets(
+                                array, width, height, &tilesets, tilewidth, tileheight,
+                            )?;
+                            if let Some(yaml_file) = &args.yaml {
                                 let tileset_maxsize =
                                     args.maxsize
                                         .unwrap_or(if tilewidth == 8 && !args.immediate {
@@ -432,166 +1148,353 @@ fn main() -> Result<()> {
                                         });
                                 let contents = fs::read_to_string(yaml_file)
                                     .expect("Unable to read input file");
-                                let t: AllSprites = serde_yaml::from_str(&contents)?;
+                                let mut t: AllSprites = serde_yaml::from_str(&contents)?;
                                 // OK, we have the array, we have the tiles specs. Let's match them
-                                // Let's scan all the tiles to make sure all this makes sense
-                                if t.sprite_sheets.len() != 1 {
-                                    eprintln!("Only the first sprite sheet (tiles) will be used");
+                                // Each sprite sheet is associated to the map tileset using the
+                                // same image (or else to the tileset at the same position)
+                                let mut sheets = Vec::<(usize, u32, DynamicImage, u32)>::new();
+                                for (s, sheet) in t.sprite_sheets.iter_mut().enumerate() {
+                                    let tileset = tilesets
+                                        .iter()
+                                        .find(|ts| {
+                                            ts.image.as_ref().is_some_and(|i| {
+                                                Path::new(i).file_name()
+                                                    == Path::new(&sheet.image).file_name()
+                                            })
+                                        })
+                                        .or(tilesets.get(s));
+                                    let (firstgid, imagewidth, margin, spacing) = match tileset {
+                                        Some(ts) => {
+                                            (ts.firstgid, ts.imagewidth, ts.margin, ts.spacing)
+                                        }
+                                        None if s == 0 => (1, None, 0, 0),
+                                        None => {
+                                            eprintln!(
+                                                "Sprite sheet {} doesn't match any tileset. Ignored",
+                                                sheet.image
+                                            );
+                                            continue;
+                                        }
+                                    };
+                                    let img = image::open(&sheet.image).unwrap_or_else(|_| {
+                                        panic!("Can't open image {}", sheet.image)
+                                    });
+                                    let image_width = if let Some(iw) = imagewidth {
+                                        iw
+                                    } else {
+                                        img.width()
+                                    };
+                                    let (img, image_width) = if margin != 0 || spacing != 0 {
+                                        // Work on a packed image, with tiles positions converted accordingly
+                                        for tile in &mut sheet.sprites {
+                                            tile.left = unspace_position(
+                                                tile.left, tilewidth, margin, spacing,
+                                            );
+                                            tile.top = unspace_position(
+                                                tile.top, tileheight, margin, spacing,
+                                            );
+                                            tile.width =
+                                                unspace_extent(tile.width, tilewidth, spacing);
+                                            tile.height =
+                                                unspace_extent(tile.height, tileheight, spacing);
+                                        }
+                                        let packed = pack_tileset_image(
+                                            &img,
+                                            image_width,
+                                            tilewidth,
+                                            tileheight,
+                                            margin,
+                                            spacing,
+                                        );
+                                        let w = packed.width();
+                                        (DynamicImage::ImageRgba8(packed), w)
+                                    } else {
+                                        (img, image_width)
+                                    };
+                                    sheets.push((s, firstgid, img, image_width));
                                 }
-                                let tiles_sheet = &t.sprite_sheets[0];
-                                let forbid_immediate =
-                                    args.forbid_immediate || tiles_sheet.mirror.is_some(); // Forbid imediate mode if there is any mirroring implied
-
-                                let img = image::open(&tiles_sheet.image)
-                                    .expect(&format!("Can't open image {}", tiles_sheet.image));
-                                let image_width = if let Some(iw) = imagewidth {
-                                    iw
-                                } else {
-                                    img.width()
+                                let bank = t.sprite_sheets[0].bank;
+                                let attributes = |a: &Option<String>| {
+                                    a.as_ref().map_or(String::new(), |a| format!("{a} "))
                                 };
+                                let forbid_immediate = args.forbid_immediate
+                                    || t.sprite_sheets.iter().any(|s| s.mirror.is_some()); // Forbid imediate mode if there is any mirroring implied
+                                let sequences = sheets
+                                    .iter()
+                                    .flat_map(|(s, _, _, _)| {
+                                        let sheet = &t.sprite_sheets[*s];
+                                        sheet.sequences.iter().flatten().map(|q| (q, sheet.bank))
+                                    })
+                                    .collect::<Vec<_>>();
+
                                 let mut index = 0;
-                                let defmode = tiles_sheet.mode.as_str();
                                 let mut tiles = HashMap::<u32, Tile>::new();
+                                let mut mirrors = HashMap::<u32, u32>::new(); // Vertically mirrored copy of each tile
                                 let mut tile_names_ex = HashMap::<u32, String>::new();
-                                let mut aliases = HashMap::<String, u32>::new();
+                                // Aliasable tiles: index, mode, palette number and name of the YAML entry
+                                let mut aliases = HashMap::<String, (u32, &str, u8, String)>::new();
+                                let mut report_tiles = Vec::new();
                                 let mut refs = HashMap::<String, u32>::new(); // Mapping from tile name in the Atari YAML file to tile number in tiled array
                                 let bytes_per_tile: usize = if tilewidth == 8 { 1 } else { 2 };
-                                for tile in &tiles_sheet.sprites {
-                                    let gfx = sprite_gfx(&img, &t, tiles_sheet, tile)?;
-                                    let mode = if let Some(m) = &tile.mode {
-                                        m.as_str()
-                                    } else {
-                                        defmode
-                                    };
-                                    let tile_bytes = match mode {
-                                        "160A" => tilewidth / 8,
-                                        "160B" => tilewidth / 4,
-                                        "320A" => tilewidth / 8,
-                                        "320B" => tilewidth / 4,
-                                        "320C" => tilewidth / 4,
-                                        "320D" => tilewidth / 8,
-                                        _ => unreachable!(),
-                                    };
-                                    if tile.alias.is_none() {
-                                        aliases.insert(tile.name.clone(), index);
-                                    }
-                                    let y = tile.top / tileheight;
-                                    let x = tile.left / tilewidth;
-                                    let ix = 1 + x + y * image_width / tilewidth;
-                                    let ixx = 1
-                                        + x
-                                        + (img.height() / tileheight - 1 - y) * image_width
-                                            / tilewidth;
-                                    // ixx is the tile number in tiled
-                                    // (reversed). index + 1 is an odd tile number that can be used
-                                    // by C code for vertical reflection
-                                    refs.insert(tile.name.clone(), ix); // index is the tile number in
-                                                                        // generated atari 7800 tiles (in the order of yaml file), ix is the tile number in tiled
-                                    let nbtilesx = tile.width / tilewidth;
-                                    let nbtilesy = tile.height / tileheight;
-                                    let palette_number = tile.palette_number.unwrap_or_default();
-                                    let background = if let Some(b) = &tile.background {
-                                        refs.get(b).copied()
-                                    } else {
-                                        None
-                                    };
-                                    let mut idx = if let Some(alias) = &tile.alias {
-                                        if let Some(i) = aliases.get(alias.as_str()) {
-                                            if let Some(Mirror::Vertical) = tile.mirror {
-                                                *i + 1 // Add 1 for vertical mirroring
-                                            } else {
-                                                *i
-                                            }
+                                for (s, firstgid, img, image_width) in &sheets {
+                                    let (firstgid, image_width) = (*firstgid, *image_width);
+                                    let tiles_sheet = &t.sprite_sheets[*s];
+                                    let defmode = tiles_sheet.mode.as_str();
+                                    for tile in &tiles_sheet.sprites {
+                                        let gfx = sprite_gfx(img, &t, tiles_sheet, tile)?;
+                                        let mode = if let Some(m) = &tile.mode {
+                                            m.as_str()
                                         } else {
-                                            return Err(anyhow!("Bad alias {}", alias));
+                                            defmode
+                                        };
+                                        let tile_bytes = match mode {
+                                            "160A" => tilewidth / 8,
+                                            "160B" => tilewidth / 4,
+                                            "320A" => tilewidth / 8,
+                                            "320B" => tilewidth / 4,
+                                            "320C" => tilewidth / 4,
+                                            "320D" => tilewidth / 8,
+                                            _ => unreachable!(),
+                                        };
+                                        let y = tile.top / tileheight;
+                                        let x = tile.left / tilewidth;
+                                        let ix = firstgid + x + y * image_width / tilewidth;
+                                        let ixx = firstgid
+                                            + x
+                                            + (img.height() / tileheight - 1 - y) * image_width
+                                                / tilewidth;
+                                        // ixx is the tile number in tiled
+                                        // (reversed). index + 1 is an odd tile number that can be used
+                                        // by C code for vertical reflection
+                                        refs.insert(tile.name.clone(), ix); // index is the tile number in
+                                                                            // generated atari 7800 tiles (in the order of yaml file), ix is the tile number in tiled
+                                        let nbtilesx = tile.width / tilewidth;
+                                        let nbtilesy = tile.height / tileheight;
+                                        let mut palette_number =
+                                            tile.palette_number.unwrap_or_default();
+                                        if palette_number > 7 {
+                                            return Err(anyhow!(
+                                                "Tile {}: Bad palette number {}",
+                                                tile.name,
+                                                palette_number
+                                            ));
                                         }
-                                    } else {
-                                        index
-                                    };
-                                    let mut offset = 0;
-                                    for j in 0..nbtilesy {
-                                        for i in 0..nbtilesx {
-                                            let tgfx = {
-                                                let w = bytes_per_tile
-                                                    * match mode {
-                                                        "160A" | "320A" | "320D" => 1,
-                                                        _ => 2,
-                                                    };
-                                                let mut t = Vec::<u8>::new();
-                                                for y in 0..tileheight {
-                                                    for c in 0..w {
-                                                        t.push(
-                                                            gfx[((j * tileheight + y) as usize
-                                                                * w
-                                                                * nbtilesx as usize)
-                                                                + i as usize * w
-                                                                + c],
-                                                        )
-                                                    }
+                                        if mode == "160B" && palette_number & 3 != 0 {
+                                            // In 160B mode, only the P2 bit of the header palette is used, selecting
+                                            // palettes 0-3 or 4-7. The others bits come from the graphics data.
+                                            eprintln!("Tile {}: 160B palette number should be 0 (palettes 0-3) or 4 (palettes 4-7). Using {}", tile.name, palette_number & 4);
+                                            palette_number &= 4;
+                                        }
+                                        let background = if let Some(b) = &tile.background {
+                                            refs.get(b).copied()
+                                        } else {
+                                            None
+                                        };
+                                        let mut idx = if let Some(alias) = &tile.alias {
+                                            if let Some((i, m, p, name)) =
+                                                aliases.get(alias.as_str()).cloned()
+                                            {
+                                                // The header of the alias uses its own mode and palette, so
+                                                // the mode must match the one of the aliased graphics, and
+                                                // a different palette must be explicitly given (palette swap)
+                                                if m != mode
+                                                    || (p != palette_number
+                                                        && tile.palette_number.is_none())
+                                                {
+                                                    return Err(anyhow!(
+                                                        "Tile {} (mode {}, palette {}) is an alias of tile {} (mode {}, palette {})",
+                                                        tile.name,
+                                                        mode,
+                                                        palette_number,
+                                                        name,
+                                                        m,
+                                                        p
+                                                    ));
                                                 }
-                                                t
-                                            };
-                                            tiles.insert(
-                                                ix + i + j * image_width / tilewidth,
-                                                Tile {
-                                                    index: idx,
-                                                    mode,
-                                                    palette_number,
-                                                    background,
-                                                    gfx: tgfx.clone(),
-                                                    fake: tile.fake.unwrap_or(false),
-                                                },
-                                            );
-                                            tile_names_ex.insert(
-                                                index,
-                                                format!("{} + {}", tile.name, offset),
-                                            );
-                                            if tile.alias.is_none() {
+                                                // Aliases of this alias refer to the same graphics
                                                 aliases.insert(
-                                                    format!("{} + {}", tile.name, offset),
-                                                    index,
-                                                );
-                                                refs.insert(
-                                                    format!("{} + {}", tile.name, offset),
-                                                    ix + i + j * image_width / tilewidth,
+                                                    tile.name.clone(),
+                                                    (i, mode, palette_number, name),
                                                 );
-                                            }
-                                            if let Some(Mirror::Vertical) = tiles_sheet.mirror {
-                                                let bg = if let Some(b) = background {
-                                                    let yy = (b - 1) / (image_width / tilewidth);
-                                                    let xx =
-                                                        (b - 1) - yy * (image_width / tilewidth);
-                                                    Some(
-                                                        1 + xx
-                                                            + (img.height() / tileheight - 1 - yy)
-                                                                * image_width
-                                                                / tilewidth,
-                                                    )
+                                                if let Some(Mirror::Vertical) = tile.mirror {
+                                                    i + 1 // Add 1 for vertical mirroring
                                                 } else {
-                                                    None
+                                                    i
+                                                }
+                                            } else {
+                                                return Err(anyhow!("Bad alias {}", alias));
+                                            }
+                                        } else {
+                                            aliases.insert(
+                                                tile.name.clone(),
+                                                (index, mode, palette_number, tile.name.clone()),
+                                            );
+                                            index
+                                        };
+                                        report_tiles.push(serde_json::json!({
+                                            "name": tile.name,
+                                            "gid": ix,
+                                            "index": idx,
+                                            "width": nbtilesx,
+                                            "height": nbtilesy,
+                                            "palette": palette_number,
+                                            "mode": mode,
+                                            "bank": tiles_sheet.bank,
+                                            "alias": tile.alias,
+                                        }));
+                                        let mut offset = 0;
+                                        for j in 0..nbtilesy {
+                                            for i in 0..nbtilesx {
+                                                let tgfx = {
+                                                    let w = bytes_per_tile
+                                                        * match mode {
+                                                            "160A" | "320A" | "320D" => 1,
+                                                            _ => 2,
+                                                        };
+                                                    let mut t = Vec::<u8>::new();
+                                                    for y in 0..tileheight {
+                                                        for c in 0..w {
+                                                            t.push(
+                                                                gfx[((j * tileheight + y)
+                                                                    as usize
+                                                                    * w
+                                                                    * nbtilesx as usize)
+                                                                    + i as usize * w
+                                                                    + c],
+                                                            )
+                                                        }
+                                                    }
+                                                    t
                                                 };
                                                 tiles.insert(
-                                                    ixx + i - j * image_width / tilewidth,
+                                                    ix + i + j * image_width / tilewidth,
                                                     Tile {
-                                                        index: idx + 1,
+                                                        index: idx,
                                                         mode,
                                                         palette_number,
-                                                        background: bg,
-                                                        gfx: tgfx,
+                                                        background,
+                                                        gfx: tgfx.clone(),
                                                         fake: tile.fake.unwrap_or(false),
+                                                        priority: tile.priority,
                                                     },
                                                 );
+                                                tile_names_ex.insert(
+                                                    index,
+                                                    format!("{} + {}", tile.name, offset),
+                                                );
+                                                if tile.alias.is_none() {
+                                                    aliases.insert(
+                                                        format!("{} + {}", tile.name, offset),
+                                                        (
+                                                            index,
+                                                            mode,
+                                                            palette_number,
+                                                            tile.name.clone(),
+                                                        ),
+                                                    );
+                                                    refs.insert(
+                                                        format!("{} + {}", tile.name, offset),
+                                                        ix + i + j * image_width / tilewidth,
+                                                    );
+                                                }
+                                                if let Some(Mirror::Vertical) = tiles_sheet.mirror {
+                                                    let bg = if let Some(b) = background {
+                                                        let yy = (b - firstgid)
+                                                            / (image_width / tilewidth);
+                                                        let xx = (b - firstgid)
+                                                            - yy * (image_width / tilewidth);
+                                                        Some(
+                                                            firstgid
+                                                                + xx
+                                                                + (img.height() / tileheight
+                                                                    - 1
+                                                                    - yy)
+                                                                    * image_width
+                                                                    / tilewidth,
+                                                        )
+                                                    } else {
+                                                        None
+                                                    };
+                                                    mirrors.insert(
+                                                        ix + i + j * image_width / tilewidth,
+                                                        ixx + i - j * image_width / tilewidth,
+                                                    );
+                                                    tiles.insert(
+                                                        ixx + i - j * image_width / tilewidth,
+                                                        Tile {
+                                                            index: idx + 1,
+                                                            mode,
+                                                            palette_number,
+                                                            background: bg,
+                                                            gfx: tgfx,
+                                                            fake: tile.fake.unwrap_or(false),
+                                                            priority: tile.priority,
+                                                        },
+                                                    );
+                                                }
+                                                if tile.alias.is_none() {
+                                                    index += tile_bytes;
+                                                }
+                                                idx += tile_bytes;
+                                                offset += tile_bytes;
+                                            }
+                                        }
+                                    }
+                                }
+                                // Tiled tile properties override the YAML file
+                                for ts in &tilesets {
+                                    for (id, properties) in &ts.properties {
+                                        if let Some(p) = properties.get("priority") {
+                                            let priority = match p.as_str() {
+                                                "foreground" => Priority::Foreground,
+                                                "background" => Priority::Background,
+                                                _ => {
+                                                    return Err(anyhow!(
+                                                        "Tile {}: Unknown priority {}",
+                                                        ts.firstgid + id,
+                                                        p
+                                                    ))
+                                                }
+                                            };
+                                            if let Some(tile) = tiles.get_mut(&(ts.firstgid + id)) {
+                                                tile.priority = Some(priority);
                                             }
-                                            if tile.alias.is_none() {
-                                                index += tile_bytes;
+                                            // Its mirrored copy goes with it, unless it has its own priority
+                                            if let Some(m) = mirrors.get(&(ts.firstgid + id)) {
+                                                let own = m
+                                                    .checked_sub(ts.firstgid)
+                                                    .and_then(|id| ts.properties.get(&id))
+                                                    .is_some_and(|p| p.contains_key("priority"));
+                                                if let Some(tile) =
+                                                    tiles.get_mut(m).filter(|_| !own)
+                                                {
+                                                    tile.priority = Some(priority);
+                                                }
                                             }
-                                            idx += tile_bytes;
-                                            offset += tile_bytes;
                                         }
                                     }
                                 }
                                 //println!("Tiles : {:?}", tiles);
 
+                                // Replace empty or unknown cells by the fill tile
+                                let array = if let Some(f) = &args.fill {
+                                    let ix = if let Ok(index) = f.parse::<u32>() {
+                                        tile_names_ex.get(&index).and_then(|n| refs.get(n))
+                                    } else {
+                                        refs.get(f)
+                                    };
+                                    if let Some(ix) = ix {
+                                        array
+                                            .iter()
+                                            .map(|c| if tiles.contains_key(c) { *c } else { *ix })
+                                            .collect::<Vec<_>>()
+                                    } else {
+                                        return Err(anyhow!("Unknown fill tile {}", f));
+                                    }
+                                } else {
+                                    array
+                                };
+
                                 // Generate the C code for the the sparse tiles
                                 // to be used with multisprite.h or sparse_tiling.h header
                                 let mut tiles_store = Vec::<(String, Vec<u32>, bool)>::new();
@@ -599,246 +1502,459 @@ fn main() -> Result<()> {
                                 let mut sequences_used = HashSet::<String>::new();
 
                                 // Process sequences & pregenerate immediate data
-                                if let Some(sequences) = &tiles_sheet.sequences {
-                                    for (i, sequence) in sequences.iter().enumerate() {
-                                        let ignore = if let Some(names) = &sequence.ignore {
-                                            names.contains(&varname)
+                                for (i, (sequence, sheet_bank)) in sequences.iter().enumerate() {
+                                    let ignore = if let Some(names) = &sequence.ignore {
+                                        names.contains(&varname)
+                                    } else {
+                                        false
+                                    };
+                                    if !ignore {
+                                        let name = if let Some(n) = &sequence.name {
+                                            format!("{}_{}", varname, n.clone())
                                         } else {
-                                            false
+                                            format!("{}_sequence_{}", varname, i)
                                         };
-                                        if !ignore {
-                                            let name = if let Some(n) = &sequence.name {
-                                                format!("{}_{}", varname, n.clone())
+                                        let mut tn = Vec::new();
+                                        let mut tileset = Vec::new();
+                                        for s in &sequence.sequence {
+                                            let ix;
+                                            let idx = s.parse::<u32>();
+                                            if let Ok(index) = idx {
+                                                let tile_name = tile_names_ex.get(&index);
+                                                if tile_name.is_none() {
+                                                    return Err(anyhow!(
+                                                        "Unknown tile number {}",
+                                                        index
+                                                    ));
+                                                }
+                                                ix = refs.get(tile_name.unwrap());
                                             } else {
-                                                format!("{}_sequence_{}", varname, i)
+                                                ix = refs.get(s);
+                                            }
+                                            if ix.is_none() {
+                                                return Err(anyhow!("Unknown tile name {}", s));
+                                            }
+                                            let tile = tiles.get(ix.unwrap()).unwrap();
+                                            let nb = match tile.mode {
+                                                "160A" | "320A" | "320D" => 1,
+                                                _ => 2,
                                             };
-                                            let mut tn = Vec::new();
-                                            let mut tileset = Vec::new();
-                                            for s in &sequence.sequence {
-                                                let ix;
-                                                let idx = s.parse::<u32>();
-                                                if let Ok(index) = idx {
-                                                    let tile_name = tile_names_ex.get(&index);
-                                                    if tile_name.is_none() {
-                                                        return Err(anyhow!(
-                                                            "Unknown tile number {}",
-                                                            index
-                                                        ));
-                                                    }
-                                                    ix = refs.get(tile_name.unwrap());
-                                                } else {
-                                                    ix = refs.get(s);
-                                                }
-                                                if ix.is_none() {
-                                                    return Err(anyhow!("Unknown tile name {}", s));
-                                                }
-                                                let tile = tiles.get(ix.unwrap()).unwrap();
-                                                let nb = match tile.mode {
-                                                    "160A" | "320A" | "320D" => 1,
-                                                    _ => 2,
-                                                };
-                                                for i in 0..nb {
-                                                    tn.push(
-                                                        tile.index + (i * bytes_per_tile) as u32,
-                                                    );
-                                                }
-                                                tileset.push(tile);
+                                            for i in 0..nb {
+                                                tn.push(tile.index + (i * bytes_per_tile) as u32);
                                             }
+                                            tileset.push(tile);
+                                        }
 
-                                            let mut seq = Vec::<&Tile>::new();
-                                            let mut tnx = Vec::new();
-                                            if let Some(prefix) = &sequence.prefix {
-                                                let ix;
-                                                let idx = prefix.parse::<u32>();
-                                                if let Ok(index) = idx {
-                                                    let tile_name = tile_names_ex.get(&index);
-                                                    if tile_name.is_none() {
-                                                        return Err(anyhow!(
-                                                            "Unknown tile number {}",
-                                                            index
-                                                        ));
-                                                    }
-                                                    ix = refs.get(tile_name.unwrap());
-                                                } else {
-                                                    ix = refs.get(prefix);
-                                                }
-                                                if ix.is_none() {
+                                        let mut seq = Vec::<&Tile>::new();
+                                        let mut tnx = Vec::new();
+                                        if let Some(prefix) = &sequence.prefix {
+                                            let ix;
+                                            let idx = prefix.parse::<u32>();
+                                            if let Ok(index) = idx {
+                                                let tile_name = tile_names_ex.get(&index);
+                                                if tile_name.is_none() {
                                                     return Err(anyhow!(
-                                                        "Unknown tile name {}",
-                                                        prefix
+                                                        "Unknown tile number {}",
+                                                        index
                                                     ));
                                                 }
-                                                let tile = tiles.get(ix.unwrap()).unwrap();
-                                                let nb = match tile.mode {
-                                                    "160A" | "320A" | "320D" => 1,
-                                                    _ => 2,
-                                                };
-                                                for i in 0..nb {
-                                                    tnx.push(
-                                                        tile.index + (i * bytes_per_tile) as u32,
-                                                    );
-                                                }
-                                                seq.push(tile);
+                                                ix = refs.get(tile_name.unwrap());
+                                            } else {
+                                                ix = refs.get(prefix);
                                             }
-                                            for _ in 0..sequence.repeat.unwrap_or(1) {
-                                                seq.extend(tileset.iter());
-                                                tnx.extend(tn.iter());
+                                            if ix.is_none() {
+                                                return Err(anyhow!(
+                                                    "Unknown tile name {}",
+                                                    prefix
+                                                ));
                                             }
-                                            if let Some(postfix) = &sequence.postfix {
-                                                let ix;
-                                                let idx = postfix.parse::<u32>();
-                                                if let Ok(index) = idx {
-                                                    let tile_name = tile_names_ex.get(&index);
-                                                    if tile_name.is_none() {
-                                                        return Err(anyhow!(
-                                                            "Unknown tile number {}",
-                                                            index
-                                                        ));
-                                                    }
-                                                    ix = refs.get(tile_name.unwrap());
-                                                } else {
-                                                    ix = refs.get(postfix);
-                                                }
-                                                if ix.is_none() {
+                                            let tile = tiles.get(ix.unwrap()).unwrap();
+                                            let nb = match tile.mode {
+                                                "160A" | "320A" | "320D" => 1,
+                                                _ => 2,
+                                            };
+                                            for i in 0..nb {
+                                                tnx.push(tile.index + (i * bytes_per_tile) as u32);
+                                            }
+                                            seq.push(tile);
+                                        }
+                                        for _ in 0..sequence.repeat.unwrap_or(1) {
+                                            seq.extend(tileset.iter());
+                                            tnx.extend(tn.iter());
+                                        }
+                                        if let Some(postfix) = &sequence.postfix {
+                                            let ix;
+                                            let idx = postfix.parse::<u32>();
+                                            if let Ok(index) = idx {
+                                                let tile_name = tile_names_ex.get(&index);
+                                                if tile_name.is_none() {
                                                     return Err(anyhow!(
-                                                        "Unknown tile name {}",
-                                                        postfix
+                                                        "Unknown tile number {}",
+                                                        index
                                                     ));
                                                 }
-                                                let tile = tiles.get(ix.unwrap()).unwrap();
-                                                let nb = match tile.mode {
-                                                    "160A" | "320A" | "320D" => 1,
-                                                    _ => 2,
-                                                };
-                                                for i in 0..nb {
-                                                    tnx.push(
-                                                        tile.index + (i * bytes_per_tile) as u32,
-                                                    );
-                                                }
-                                                seq.push(tile);
+                                                ix = refs.get(tile_name.unwrap());
+                                            } else {
+                                                ix = refs.get(postfix);
                                             }
-                                            let mut generate = true;
-                                            if let Some(g) = sequence.generate {
-                                                if !g {
-                                                    generate = false;
-                                                }
+                                            if ix.is_none() {
+                                                return Err(anyhow!(
+                                                    "Unknown tile name {}",
+                                                    postfix
+                                                ));
                                             }
-                                            if generate {
-                                                let mut s = String::new();
-
-                                                let l = tnx.len() * bytes_per_tile;
-                                                if let Some(b) = sequence.bank {
-                                                    s.push_str(&format!("bank{b} "));
-                                                } else if let Some(b) = tiles_sheet.bank {
-                                                    s.push_str(&format!("bank{b} "));
-                                                }
-                                                if let Some(h) = sequence.holeydma {
-                                                    if h {
-                                                        s.push_str("holeydma ");
-                                                    }
+                                            let tile = tiles.get(ix.unwrap()).unwrap();
+                                            let nb = match tile.mode {
+                                                "160A" | "320A" | "320D" => 1,
+                                                _ => 2,
+                                            };
+                                            for i in 0..nb {
+                                                tnx.push(tile.index + (i * bytes_per_tile) as u32);
+                                            }
+                                            seq.push(tile);
+                                        }
+                                        let mut generate = true;
+                                        if let Some(g) = sequence.generate {
+                                            if !g {
+                                                generate = false;
+                                            }
+                                        }
+                                        if generate {
+                                            let mut s = String::new();
+
+                                            let l = tnx.len() * bytes_per_tile;
+                                            if let Some(b) = sequence.bank {
+                                                s.push_str(&format!("bank{b} "));
+                                            } else if let Some(b) = sheet_bank {
+                                                s.push_str(&format!("bank{b} "));
+                                            }
+                                            s.push_str(&attributes(&t.attributes.sequences));
+                                            if let Some(h) = sequence.holeydma {
+                                                if h {
+                                                    s.push_str("holeydma ");
                                                 }
-                                                s.push_str(&format!(
+                                            }
+                                            s.push_str(&format!(
                                                 "reversed scattered({},{}) char {}[{}] = {{\n\t",
                                                 tileheight,
                                                 l,
                                                 &name,
                                                 l * tileheight as usize
                                             ));
-                                                let mut i = 0;
-                                                for y in 0..tileheight as usize {
-                                                    for t in &seq {
-                                                        let nb = match t.mode {
-                                                            "160A" | "320A" | "320D" => 1,
-                                                            _ => 2,
-                                                        };
-                                                        for b in 0..(nb * bytes_per_tile) {
-                                                            s.push_str(&format!(
-                                                                "0x{:02x}",
-                                                                t.gfx
-                                                                    [y * (nb * bytes_per_tile) + b]
-                                                            ));
-                                                            if i != l * tileheight as usize - 1 {
-                                                                if (i + 1) % 16 != 0 {
-                                                                    s.push_str(", ");
-                                                                } else {
-                                                                    s.push_str(",\n\t");
-                                                                }
+                                            let mut i = 0;
+                                            for y in 0..tileheight as usize {
+                                                for t in &seq {
+                                                    let nb = match t.mode {
+                                                        "160A" | "320A" | "320D" => 1,
+                                                        _ => 2,
+                                                    };
+                                                    for b in 0..(nb * bytes_per_tile) {
+                                                        s.push_str(&format!(
+                                                            "0x{:02x}",
+                                                            t.gfx[y * (nb * bytes_per_tile) + b]
+                                                        ));
+                                                        if i != l * tileheight as usize - 1 {
+                                                            if (i + 1) % 16 != 0 {
+                                                                s.push_str(", ");
+                                                            } else {
+                                                                s.push_str(",\n\t");
                                                             }
-                                                            i += 1;
                                                         }
+                                                        i += 1;
                                                     }
                                                 }
-                                                s.push_str("};\n");
-                                                sequences_code.insert(name.clone(), s);
                                             }
-                                            tiles_store.push((name, tnx, true));
+                                            s.push_str("};\n");
+                                            sequences_code.insert(name.clone(), s);
                                         }
+                                        tiles_store.push((name, tnx, true));
                                     }
                                 }
 
                                 let mut tilesmap_store = Vec::<(String, String)>::new();
+                                let mut auto_report = (0, 0, 0); // Immediate tilesets, indirect tilesets, bytes
                                 let mut tilesmap = Vec::<String>::new();
+                                let mut row_nsets = Vec::<usize>::new();
+                                let mut ram_sizes = Vec::<usize>::new(); // For each row, size of the RAM copy of indirect tilesets
+
+                                // Number of rows per zone
+                                let zone_rows = if let Some(z) = args.zone_height {
+                                    if (z != 8 && z != 16) || z % tileheight != 0 {
+                                        return Err(anyhow!(
+                                            "Bad zone height {} (should be 8 or 16, and a multiple of the tiles height)",
+                                            z
+                                        ));
+                                    }
+                                    (z / tileheight) as usize
+                                } else {
+                                    1
+                                };
+                                let mut zone_str = String::new();
+                                let entry_size = if args.direct { 5 } else { 7 }; // Size of a tileset header
+                                let mut zone_nsets = 0;
+                                let mut zone_dma = 0; // DMA cycles of the previous rows of the zone (with --max-dma)
                                 let mut output = String::new();
 
-                                for y in 0..height {
-                                    // For each line, find the tilesets
-                                    let mut tilesets =
-                                        VecDeque::<(u32, Vec<Tile>)>::with_capacity(10);
-                                    let mut background_tileset = Vec::<Tile>::new();
-                                    let mut foreground_tileset = Vec::<Tile>::new();
-                                    let mut deferred_tileset = Vec::<Vec<Tile>>::new();
-                                    let mut background_startx = 0;
-                                    let mut foreground_startx = 0;
-                                    let mut deferred_startx = Vec::<u32>::new();
-                                    for x in 0..width {
-                                        let cell = array[y * width + x];
-                                        if cell == 0 {
-                                            // Empty cell
-                                            if !background_tileset.is_empty() {
-                                                if args.force_left_to_right_order {
+                                // Tile numbers (in Atari 7800 format) of a tileset, and whether the tiles are continuous in the
+                                // tiles sheet (so that tile graphics can be used directly in immediate mode)
+                                let tileset_numbers = |tileset: &[Tile]| {
+                                    let mut tn = Vec::new();
+                                    let mut continuous_tileset = true;
+                                    let mut previous_index = None;
+                                    for t in tileset {
+                                        let tile_bytes = match t.mode {
+                                            "160A" => tilewidth / 8,
+                                            "160B" => tilewidth / 4,
+                                            "320A" => tilewidth / 8,
+                                            "320B" => tilewidth / 4,
+                                            "320C" => tilewidth / 4,
+                                            "320D" => tilewidth / 8,
+                                            _ => unreachable!(),
+                                        };
+                                        let nb = match t.mode {
+                                            "160A" | "320A" | "320D" => 1,
+                                            _ => 2,
+                                        };
+                                        for i in 0..nb {
+                                            tn.push(t.index + (i * bytes_per_tile) as u32);
+                                        }
+                                        if let Some(pi) = previous_index {
+                                            if pi + tile_bytes != t.index {
+                                                continuous_tileset = false;
+                                            }
+                                        }
+                                        previous_index = Some(t.index);
+                                        if t.fake {
+                                            continuous_tileset = false; // Avoid direct use
+                                                                        // of immediate tile data, since it's fake
+                                        }
+                                    }
+                                    (tn, continuous_tileset)
+                                };
+
+                                // Load the cache of previously generated rows. Rows are reused if the row, the YAML file, the tilesets
+                                // and the options are unchanged, and the tilesets they reference still exist
+                                let mut cache = if let Some(c) = &args.cache {
+                                    fs::read_to_string(c)
+                                        .ok()
+                                        .and_then(|s| {
+                                            serde_yaml::from_str::<Vec<Option<CachedRow>>>(&s).ok()
+                                        })
+                                        .unwrap_or_default()
+                                } else {
+                                    Vec::new()
+                                };
+                                // Split the map into screens
+                                let screen_width = args.screen_width.unwrap_or(width);
+                                if screen_width == 0 || width % screen_width != 0 {
+                                    return Err(anyhow!(
+                                        "Map width ({}) is not a multiple of the screen width ({})",
+                                        width,
+                                        screen_width
+                                    ));
+                                }
+                                let nscreens = width / screen_width;
+                                let prefixes = if args.screen_width.is_some() {
+                                    (0..nscreens)
+                                        .map(|s| format!("{varname}_{s}"))
+                                        .collect::<Vec<_>>()
+                                } else {
+                                    vec![varname.clone()]
+                                };
+                                cache.resize(nscreens * height, None);
+                                let mut hasher = DefaultHasher::new();
+                                contents.hash(&mut hasher);
+                                format!("{:?}", args).hash(&mut hasher);
+                                format!("{:?}", tilesets).hash(&mut hasher);
+                                (width, tilewidth, tileheight).hash(&mut hasher);
+                                // The tiles graphics change with the images of the sprite sheets
+                                for sheet in &t.sprite_sheets {
+                                    fs::read(&sheet.image)?.hash(&mut hasher);
+                                }
+                                let global_hash = hasher.finish();
+                                let mut report_rows = Vec::new();
+
+                                for (screen, y) in
+                                    (0..nscreens).flat_map(|s| (0..height).map(move |y| (s, y)))
+                                {
+                                    let prefix = &prefixes[screen];
+                                    let row = screen * height + y;
+                                    let x0 = y * width + screen * screen_width; // Index of the row first cell of the screen
+                                    let mut hasher = DefaultHasher::new();
+                                    global_hash.hash(&mut hasher);
+                                    array[x0..x0 + screen_width].hash(&mut hasher);
+                                    let row_hash = hasher.finish();
+                                    let store_start = tiles_store.len();
+                                    let mut cached = false;
+                                    if let Some(row) = &cache[row] {
+                                        // The row must also fit in what the previous rows of its zone left
+                                        if row.hash == row_hash
+                                            && args.max_dma.is_none_or(|m| zone_dma + row.dma <= m)
+                                            && args.max_row_bytes.is_none_or(|m| {
+                                                (zone_nsets + row.nsets) * entry_size <= m
+                                            })
+                                        {
+                                            tiles_store.extend(row.store.iter().cloned());
+                                            if row.refs.iter().all(|r| tiles_store.contains(r)) {
+                                                for r in &row.refs {
+                                                    sequences_used.insert(r.0.clone());
+                                                }
+                                                output.push_str(&row.output);
+                                                ram_sizes.push(row.ram);
+                                                zone_str.push_str(&row.tilemap);
+                                                zone_nsets += row.nsets;
+                                                zone_dma += row.dma;
+                                                cached = true;
+                                            } else {
+                                                tiles_store.truncate(store_start);
+                                            }
+                                        }
+                                    }
+                                    if !cached {
+                                        let output_start = output.len();
+                                        let mut row_refs = Vec::new();
+                                        let mut row_ram = Vec::<u32>::new();
+                                        // For each line, find the tilesets
+                                        let mut tilesets =
+                                            VecDeque::<(u32, Vec<Tile>)>::with_capacity(10);
+                                        let mut background_tileset = Vec::<Tile>::new();
+                                        let mut foreground_tileset = Vec::<Tile>::new();
+                                        let mut deferred_tileset = Vec::<Vec<Tile>>::new();
+                                        let mut background_startx = 0;
+                                        let mut foreground_startx = 0;
+                                        let mut deferred_startx = Vec::<u32>::new();
+                                        for x in 0..screen_width {
+                                            let cell = array[x0 + x];
+                                            if cell == 0 {
+                                                // Empty cell
+                                                if !background_tileset.is_empty() {
+                                                    if args.force_left_to_right_order {
+                                                        tilesets.push_back((
+                                                            background_startx,
+                                                            background_tileset,
+                                                        ));
+                                                    } else {
+                                                        tilesets.push_front((
+                                                            background_startx,
+                                                            background_tileset,
+                                                        ));
+                                                    }
+                                                    background_tileset = Vec::<Tile>::new();
+                                                }
+                                                if !foreground_tileset.is_empty() {
                                                     tilesets.push_back((
-                                                        background_startx,
-                                                        background_tileset,
-                                                    ));
-                                                } else {
-                                                    tilesets.push_front((
-                                                        background_startx,
-                                                        background_tileset,
+                                                        foreground_startx,
+                                                        foreground_tileset,
                                                     ));
+                                                    foreground_tileset = Vec::<Tile>::new();
                                                 }
-                                                background_tileset = Vec::<Tile>::new();
-                                            }
-                                            if !foreground_tileset.is_empty() {
-                                                tilesets.push_back((
-                                                    foreground_startx,
-                                                    foreground_tileset,
-                                                ));
-                                                foreground_tileset = Vec::<Tile>::new();
-                                            }
-                                            for i in 0..deferred_tileset.len() {
-                                                tilesets.push_back((
-                                                    deferred_startx[i],
-                                                    deferred_tileset[i].clone(),
-                                                ))
-                                            }
-                                            deferred_tileset = Vec::<Vec<Tile>>::new();
-                                            deferred_startx = Vec::<u32>::new();
-                                        } else if let Some(t) = tiles.get(&cell) {
-                                            if let Some(r) = t.background {
-                                                // It's a tile with background info
-                                                if let Some(bt) = tiles.get(&r) {
-                                                    // Let's check the background tile
-                                                    if let Some(tx) = background_tileset.last() {
-                                                        // Is the cell compatible with the background tileset in construction ?
-                                                        if bt.mode == tx.mode
-                                                            && bt.palette_number
-                                                                == tx.palette_number
-                                                            && bt.fake == tx.fake
+                                                for i in 0..deferred_tileset.len() {
+                                                    tilesets.push_back((
+                                                        deferred_startx[i],
+                                                        deferred_tileset[i].clone(),
+                                                    ))
+                                                }
+                                                deferred_tileset = Vec::<Vec<Tile>>::new();
+                                                deferred_startx = Vec::<u32>::new();
+                                            } else if let Some(t) = tiles.get(&cell) {
+                                                if t.priority == Some(Priority::Background) {
+                                                    // It's a tile forced to the background (or, for a tile with
+                                                    // background info, whose background tile is forced to the
+                                                    // background, the tile itself being drawn over it)
+                                                    let bt = match t.background {
+                                                        Some(r) => tiles.get(&r),
+                                                        None => Some(t),
+                                                    };
+                                                    if let Some(bt) = bt {
+                                                        push_tile(
+                                                            &mut tilesets,
+                                                            &mut background_tileset,
+                                                            &mut background_startx,
+                                                            bt,
+                                                            x as u32,
+                                                            tileset_maxsize,
+                                                            !args.force_left_to_right_order,
+                                                        );
+                                                    }
+                                                    if t.background.is_some() {
+                                                        // The tile itself goes on with the foreground tileset
+                                                        push_tile(
+                                                            &mut tilesets,
+                                                            &mut foreground_tileset,
+                                                            &mut foreground_startx,
+                                                            t,
+                                                            x as u32,
+                                                            tileset_maxsize,
+                                                            false,
+                                                        );
+                                                    } else if !foreground_tileset.is_empty() {
+                                                        // The foreground tileset in construction is over
+                                                        deferred_tileset
+                                                            .push(foreground_tileset.clone());
+                                                        deferred_startx.push(foreground_startx);
+                                                        foreground_tileset = Vec::<Tile>::new();
+                                                    }
+                                                } else if t.priority == Some(Priority::Foreground) {
+                                                    // It's a tile forced to the foreground.
+                                                    // The background tileset in construction is over
+                                                    close_tileset(
+                                                        &mut tilesets,
+                                                        &mut background_tileset,
+                                                        background_startx,
+                                                        !args.force_left_to_right_order,
+                                                    );
+                                                    // Its background tile (if any) starts a new background tileset
+                                                    if let Some(bt) =
+                                                        t.background.and_then(|r| tiles.get(&r))
+                                                    {
+                                                        background_startx = x as u32;
+                                                        background_tileset.push(bt.clone());
+                                                    }
+                                                    push_tile(
+                                                        &mut tilesets,
+                                                        &mut foreground_tileset,
+                                                        &mut foreground_startx,
+                                                        t,
+                                                        x as u32,
+                                                        tileset_maxsize,
+                                                        false,
+                                                    );
+                                                } else if let Some(r) = t.background {
+                                                    // It's a tile with background info
+                                                    if let Some(bt) = tiles.get(&r) {
+                                                        // Let's check the background tile
+                                                        if let Some(tx) = background_tileset.last()
                                                         {
-                                                            // Yes, let's add it to the current background tileset
-                                                            if background_tileset.len()
-                                                                >= tileset_maxsize
+                                                            // Is the cell compatible with the background tileset in construction ?
+                                                            if bt.mode == tx.mode
+                                                                && bt.palette_number
+                                                                    == tx.palette_number
+                                                                && bt.fake == tx.fake
                                                             {
+                                                                // Yes, let's add it to the current background tileset
+                                                                if background_tileset.len()
+                                                                    >= tileset_maxsize
+                                                                {
+                                                                    if args
+                                                                        .force_left_to_right_order
+                                                                    {
+                                                                        tilesets.push_back((
+                                                                            background_startx,
+                                                                            background_tileset,
+                                                                        ));
+                                                                    } else {
+                                                                        tilesets.push_front((
+                                                                            background_startx,
+                                                                            background_tileset,
+                                                                        ));
+                                                                    }
+                                                                    background_tileset =
+                                                                        Vec::<Tile>::new();
+                                                                    background_startx = x as u32;
+                                                                }
+                                                                background_tileset.push(bt.clone());
+                                                            } else {
+                                                                // No. Let's write this background tileset
                                                                 if args.force_left_to_right_order {
                                                                     tilesets.push_back((
                                                                         background_startx,
@@ -850,58 +1966,107 @@ fn main() -> Result<()> {
                                                                         background_tileset,
                                                                     ));
                                                                 }
+                                                                // And let's start a new background tileset
                                                                 background_tileset =
-                                                                    Vec::<Tile>::new();
+                                                                    vec![bt.clone()];
                                                                 background_startx = x as u32;
                                                             }
-                                                            background_tileset.push(bt.clone());
                                                         } else {
-                                                            // No. Let's write this background tileset
-                                                            if args.force_left_to_right_order {
-                                                                tilesets.push_back((
-                                                                    background_startx,
-                                                                    background_tileset,
-                                                                ));
+                                                            // Let's look at the current foreground
+                                                            // tileset to see if it would fit as a
+                                                            // background tileset
+                                                            if let Some(tx) =
+                                                                foreground_tileset.last()
+                                                            {
+                                                                // Is the cell compatible with the foreground tileset in construction ?
+                                                                if bt.mode == tx.mode
+                                                                    && bt.palette_number
+                                                                        == tx.palette_number
+                                                                    && bt.fake == tx.fake
+                                                                {
+                                                                    // Yes, it's compatible. Let's
+                                                                    // convert this foreground tileset
+                                                                    // into a background tileset
+                                                                    background_tileset =
+                                                                        foreground_tileset.clone();
+                                                                    background_startx =
+                                                                        foreground_startx;
+                                                                    foreground_tileset =
+                                                                        Vec::<Tile>::new();
+                                                                } else {
+                                                                    background_startx = x as u32;
+                                                                }
                                                             } else {
-                                                                tilesets.push_front((
-                                                                    background_startx,
-                                                                    background_tileset,
+                                                                background_startx = x as u32;
+                                                            }
+                                                            // No, so start a new background tileset
+                                                            background_tileset.push(bt.clone());
+                                                            // And send the current foreground
+                                                            if !foreground_tileset.is_empty() {
+                                                                tilesets.push_back((
+                                                                    foreground_startx,
+                                                                    foreground_tileset,
                                                                 ));
+                                                                foreground_tileset =
+                                                                    Vec::<Tile>::new();
                                                             }
-                                                            // And let's start a new background tileset
-                                                            background_tileset = vec![bt.clone()];
-                                                            background_startx = x as u32;
                                                         }
-                                                    } else {
-                                                        // Let's look at the current foreground
-                                                        // tileset to see if it would fit as a
-                                                        // background tileset
+                                                        // Let's check the foreground tile
                                                         if let Some(tx) = foreground_tileset.last()
                                                         {
                                                             // Is the cell compatible with the foreground tileset in construction ?
-                                                            if bt.mode == tx.mode
-                                                                && bt.palette_number
+                                                            if t.mode == tx.mode
+                                                                && t.palette_number
                                                                     == tx.palette_number
-                                                                && bt.fake == tx.fake
+                                                                && t.fake == tx.fake
                                                             {
-                                                                // Yes, it's compatible. Let's
-                                                                // convert this foreground tileset
-                                                                // into a background tileset
-                                                                background_tileset =
-                                                                    foreground_tileset.clone();
-                                                                background_startx =
-                                                                    foreground_startx;
+                                                                // Yes, let's add it to the current foreground tileset
+                                                                if foreground_tileset.len()
+                                                                    >= tileset_maxsize
+                                                                {
+                                                                    tilesets.push_back((
+                                                                        foreground_startx,
+                                                                        foreground_tileset,
+                                                                    ));
+                                                                    foreground_tileset =
+                                                                        Vec::<Tile>::new();
+                                                                    foreground_startx = x as u32;
+                                                                }
+                                                                foreground_tileset.push(t.clone());
+                                                                //println!("foreground_tileset = {:?}", foreground_tileset);
+                                                            } else {
+                                                                // No. Let's write this foreground tileset
+                                                                tilesets.push_back((
+                                                                    foreground_startx,
+                                                                    foreground_tileset,
+                                                                ));
                                                                 foreground_tileset =
                                                                     Vec::<Tile>::new();
-                                                            } else {
-                                                                background_startx = x as u32;
+                                                                // And let's start a new foreground tileset
+                                                                foreground_tileset.push(t.clone());
+                                                                foreground_startx = x as u32;
                                                             }
                                                         } else {
-                                                            background_startx = x as u32;
+                                                            // No, so start a new foreground tileset
+                                                            foreground_tileset.push(t.clone());
+                                                            foreground_startx = x as u32;
+                                                        }
+                                                    } else {
+                                                        // Empty cell
+                                                        if !background_tileset.is_empty() {
+                                                            if args.force_left_to_right_order {
+                                                                tilesets.push_back((
+                                                                    background_startx,
+                                                                    background_tileset,
+                                                                ));
+                                                            } else {
+                                                                tilesets.push_front((
+                                                                    background_startx,
+                                                                    background_tileset,
+                                                                ));
+                                                            }
+                                                            background_tileset = Vec::<Tile>::new();
                                                         }
-                                                        // No, so start a new background tileset
-                                                        background_tileset.push(bt.clone());
-                                                        // And send the current foreground
                                                         if !foreground_tileset.is_empty() {
                                                             tilesets.push_back((
                                                                 foreground_startx,
@@ -910,79 +2075,48 @@ fn main() -> Result<()> {
                                                             foreground_tileset = Vec::<Tile>::new();
                                                         }
                                                     }
-                                                    // Let's check the foreground tile
-                                                    if let Some(tx) = foreground_tileset.last() {
-                                                        // Is the cell compatible with the foreground tileset in construction ?
+                                                } else {
+                                                    // It's a normal tile
+                                                    if let Some(tx) = background_tileset.last() {
+                                                        // Is the cell compatible with the background tileset in construction ?
                                                         if t.mode == tx.mode
                                                             && t.palette_number == tx.palette_number
                                                             && t.fake == tx.fake
                                                         {
-                                                            // Yes, let's add it to the current foreground tileset
-                                                            if foreground_tileset.len()
+                                                            // Yes, let's add it the current background tileset
+                                                            if background_tileset.len()
                                                                 >= tileset_maxsize
                                                             {
-                                                                tilesets.push_back((
-                                                                    foreground_startx,
-                                                                    foreground_tileset,
-                                                                ));
+                                                                if args.force_left_to_right_order {
+                                                                    tilesets.push_back((
+                                                                        background_startx,
+                                                                        background_tileset,
+                                                                    ));
+                                                                } else {
+                                                                    tilesets.push_front((
+                                                                        background_startx,
+                                                                        background_tileset,
+                                                                    ));
+                                                                }
+                                                                background_tileset =
+                                                                    Vec::<Tile>::new();
+                                                                background_startx = x as u32;
+                                                            }
+                                                            background_tileset.push(t.clone());
+                                                            // Is there a foreground tileset ?
+                                                            if !foreground_tileset.is_empty() {
+                                                                // Yes. Let's write this foreground tileset
+                                                                deferred_tileset.push(
+                                                                    foreground_tileset.clone(),
+                                                                );
+                                                                deferred_startx
+                                                                    .push(foreground_startx);
+                                                                //println!("deferred_tileset = {:?}", deferred_tileset);
                                                                 foreground_tileset =
                                                                     Vec::<Tile>::new();
-                                                                foreground_startx = x as u32;
                                                             }
-                                                            foreground_tileset.push(t.clone());
-                                                            //println!("foreground_tileset = {:?}", foreground_tileset);
-                                                        } else {
-                                                            // No. Let's write this foreground tileset
-                                                            tilesets.push_back((
-                                                                foreground_startx,
-                                                                foreground_tileset,
-                                                            ));
-                                                            foreground_tileset = Vec::<Tile>::new();
-                                                            // And let's start a new foreground tileset
-                                                            foreground_tileset.push(t.clone());
-                                                            foreground_startx = x as u32;
-                                                        }
-                                                    } else {
-                                                        // No, so start a new foreground tileset
-                                                        foreground_tileset.push(t.clone());
-                                                        foreground_startx = x as u32;
-                                                    }
-                                                } else {
-                                                    // Empty cell
-                                                    if !background_tileset.is_empty() {
-                                                        if args.force_left_to_right_order {
-                                                            tilesets.push_back((
-                                                                background_startx,
-                                                                background_tileset,
-                                                            ));
                                                         } else {
-                                                            tilesets.push_front((
-                                                                background_startx,
-                                                                background_tileset,
-                                                            ));
-                                                        }
-                                                        background_tileset = Vec::<Tile>::new();
-                                                    }
-                                                    if !foreground_tileset.is_empty() {
-                                                        tilesets.push_back((
-                                                            foreground_startx,
-                                                            foreground_tileset,
-                                                        ));
-                                                        foreground_tileset = Vec::<Tile>::new();
-                                                    }
-                                                }
-                                            } else {
-                                                // It's a normal tile
-                                                if let Some(tx) = background_tileset.last() {
-                                                    // Is the cell compatible with the background tileset in construction ?
-                                                    if t.mode == tx.mode
-                                                        && t.palette_number == tx.palette_number
-                                                        && t.fake == tx.fake
-                                                    {
-                                                        // Yes, let's add it the current background tileset
-                                                        if background_tileset.len()
-                                                            >= tileset_maxsize
-                                                        {
+                                                            // No. Let's write this background tileset
                                                             if args.force_left_to_right_order {
                                                                 tilesets.push_back((
                                                                     background_startx,
@@ -995,36 +2129,54 @@ fn main() -> Result<()> {
                                                                 ));
                                                             }
                                                             background_tileset = Vec::<Tile>::new();
-                                                            background_startx = x as u32;
-                                                        }
-                                                        background_tileset.push(t.clone());
-                                                        // Is there a foreground tileset ?
-                                                        if !foreground_tileset.is_empty() {
-                                                            // Yes. Let's write this foreground tileset
-                                                            deferred_tileset
-                                                                .push(foreground_tileset.clone());
-                                                            deferred_startx.push(foreground_startx);
-                                                            //println!("deferred_tileset = {:?}", deferred_tileset);
-                                                            foreground_tileset = Vec::<Tile>::new();
-                                                        }
-                                                    } else {
-                                                        // No. Let's write this background tileset
-                                                        if args.force_left_to_right_order {
-                                                            tilesets.push_back((
-                                                                background_startx,
-                                                                background_tileset,
-                                                            ));
-                                                        } else {
-                                                            tilesets.push_front((
-                                                                background_startx,
-                                                                background_tileset,
-                                                            ));
+                                                            // Is there a foreground tileset ?
+                                                            if let Some(tx) =
+                                                                foreground_tileset.last()
+                                                            {
+                                                                // Yes. Is it compatible ?
+                                                                if t.mode == tx.mode
+                                                                    && t.palette_number
+                                                                        == tx.palette_number
+                                                                    && t.fake == tx.fake
+                                                                {
+                                                                    // Yes, let's add it the current foreground tileset
+                                                                    if foreground_tileset.len()
+                                                                        >= tileset_maxsize
+                                                                    {
+                                                                        tilesets.push_back((
+                                                                            foreground_startx,
+                                                                            foreground_tileset,
+                                                                        ));
+                                                                        foreground_tileset =
+                                                                            Vec::<Tile>::new();
+                                                                        foreground_startx =
+                                                                            x as u32;
+                                                                    }
+                                                                    foreground_tileset
+                                                                        .push(t.clone());
+                                                                } else {
+                                                                    // No. It's not compatible. Let's write this foreground tileset
+                                                                    tilesets.push_back((
+                                                                        foreground_startx,
+                                                                        foreground_tileset,
+                                                                    ));
+                                                                    foreground_tileset =
+                                                                        Vec::<Tile>::new();
+                                                                    // And let's start a new background tileset
+                                                                    background_tileset
+                                                                        .push(t.clone());
+                                                                    background_startx = x as u32;
+                                                                }
+                                                            } else {
+                                                                // No, so start a new background tileset
+                                                                background_tileset.push(t.clone());
+                                                                background_startx = x as u32;
+                                                            }
                                                         }
-                                                        background_tileset = Vec::<Tile>::new();
-                                                        // Is there a foreground tileset ?
+                                                    } else {
+                                                        // There is no background tileset. But maybe is there a foregound tileset ?
                                                         if let Some(tx) = foreground_tileset.last()
                                                         {
-                                                            // Yes. Is it compatible ?
                                                             if t.mode == tx.mode
                                                                 && t.palette_number
                                                                     == tx.palette_number
@@ -1044,182 +2196,130 @@ fn main() -> Result<()> {
                                                                 }
                                                                 foreground_tileset.push(t.clone());
                                                             } else {
-                                                                // No. It's not compatible. Let's write this foreground tileset
-                                                                tilesets.push_back((
-                                                                    foreground_startx,
-                                                                    foreground_tileset,
-                                                                ));
-                                                                foreground_tileset =
-                                                                    Vec::<Tile>::new();
-                                                                // And let's start a new background tileset
-                                                                background_tileset.push(t.clone());
-                                                                background_startx = x as u32;
-                                                            }
-                                                        } else {
-                                                            // No, so start a new background tileset
-                                                            background_tileset.push(t.clone());
-                                                            background_startx = x as u32;
-                                                        }
-                                                    }
-                                                } else {
-                                                    // There is no background tileset. But maybe is there a foregound tileset ?
-                                                    if let Some(tx) = foreground_tileset.last() {
-                                                        if t.mode == tx.mode
-                                                            && t.palette_number == tx.palette_number
-                                                            && t.fake == tx.fake
-                                                        {
-                                                            // Yes, let's add it the current foreground tileset
-                                                            if foreground_tileset.len()
-                                                                >= tileset_maxsize
-                                                            {
+                                                                // No, it's not compatible. Let's write the foreground tileset as it is
                                                                 tilesets.push_back((
                                                                     foreground_startx,
                                                                     foreground_tileset,
                                                                 ));
                                                                 foreground_tileset =
                                                                     Vec::<Tile>::new();
+                                                                // And let's start a foreground tileset
+                                                                foreground_tileset.push(t.clone());
                                                                 foreground_startx = x as u32;
                                                             }
-                                                            foreground_tileset.push(t.clone());
                                                         } else {
-                                                            // No, it's not compatible. Let's write the foreground tileset as it is
-                                                            tilesets.push_back((
-                                                                foreground_startx,
-                                                                foreground_tileset,
-                                                            ));
-                                                            foreground_tileset = Vec::<Tile>::new();
-                                                            // And let's start a foreground tileset
+                                                            // No there is nothing. So let's start a foreground tileset
                                                             foreground_tileset.push(t.clone());
                                                             foreground_startx = x as u32;
                                                         }
+                                                    }
+                                                }
+                                            } else {
+                                                //return Err(anyhow!("Wrong tilesheet. Index unknown"));
+                                                // It's not in the tilesheet. Consider it as 0 (empty)
+                                                if !background_tileset.is_empty() {
+                                                    if args.force_left_to_right_order {
+                                                        tilesets.push_back((
+                                                            background_startx,
+                                                            background_tileset,
+                                                        ));
                                                     } else {
-                                                        // No there is nothing. So let's start a foreground tileset
-                                                        foreground_tileset.push(t.clone());
-                                                        foreground_startx = x as u32;
+                                                        tilesets.push_front((
+                                                            background_startx,
+                                                            background_tileset,
+                                                        ));
                                                     }
+                                                    //    .push((background_startx, background_tileset));
+                                                    background_tileset = Vec::<Tile>::new();
                                                 }
-                                            }
-                                        } else {
-                                            //return Err(anyhow!("Wrong tilesheet. Index unknown"));
-                                            // It's not in the tilesheet. Consider it as 0 (empty)
-                                            if !background_tileset.is_empty() {
-                                                if args.force_left_to_right_order {
+                                                if !foreground_tileset.is_empty() {
                                                     tilesets.push_back((
-                                                        background_startx,
-                                                        background_tileset,
-                                                    ));
-                                                } else {
-                                                    tilesets.push_front((
-                                                        background_startx,
-                                                        background_tileset,
+                                                        foreground_startx,
+                                                        foreground_tileset,
                                                     ));
+                                                    foreground_tileset = Vec::<Tile>::new();
                                                 }
-                                                //    .push((background_startx, background_tileset));
-                                                background_tileset = Vec::<Tile>::new();
+                                                for i in 0..deferred_tileset.len() {
+                                                    tilesets.push_back((
+                                                        deferred_startx[i],
+                                                        deferred_tileset[i].clone(),
+                                                    ))
+                                                }
+                                                deferred_tileset = Vec::<Vec<Tile>>::new();
+                                                deferred_startx = Vec::<u32>::new();
                                             }
-                                            if !foreground_tileset.is_empty() {
+                                        }
+                                        // Write the last tilesets
+                                        if !background_tileset.is_empty() {
+                                            if args.force_left_to_right_order {
                                                 tilesets.push_back((
-                                                    foreground_startx,
-                                                    foreground_tileset,
+                                                    background_startx,
+                                                    background_tileset,
+                                                ));
+                                            } else {
+                                                tilesets.push_front((
+                                                    background_startx,
+                                                    background_tileset,
                                                 ));
-                                                foreground_tileset = Vec::<Tile>::new();
-                                            }
-                                            for i in 0..deferred_tileset.len() {
-                                                tilesets.push_back((
-                                                    deferred_startx[i],
-                                                    deferred_tileset[i].clone(),
-                                                ))
                                             }
-                                            deferred_tileset = Vec::<Vec<Tile>>::new();
-                                            deferred_startx = Vec::<u32>::new();
                                         }
-                                    }
-                                    // Write the last tilesets
-                                    if !background_tileset.is_empty() {
-                                        if args.force_left_to_right_order {
+                                        if !foreground_tileset.is_empty() {
                                             tilesets
-                                                .push_back((background_startx, background_tileset));
-                                        } else {
-                                            tilesets.push_front((
-                                                background_startx,
-                                                background_tileset,
+                                                .push_back((foreground_startx, foreground_tileset));
+                                        }
+                                        for i in 0..deferred_tileset.len() {
+                                            tilesets.push_back((
+                                                deferred_startx[i],
+                                                deferred_tileset[i].clone(),
                                             ));
                                         }
-                                    }
-                                    if !foreground_tileset.is_empty() {
-                                        tilesets.push_back((foreground_startx, foreground_tileset));
-                                    }
-                                    for i in 0..deferred_tileset.len() {
-                                        tilesets.push_back((
-                                            deferred_startx[i],
-                                            deferred_tileset[i].clone(),
-                                        ));
-                                    }
 
-                                    // OK. Now we have the tilesets. Let's try to see if we can
-                                    // break these into a new tilesets for better optimization
-                                    let mut tilesets_ex = Vec::<(u32, Vec<Tile>)>::new();
-                                    for s in tilesets {
-                                        if s.1.len() >= 5 {
-                                            // The tilesets must be at least 5 tiles long
-                                            let mut tn = Vec::new(); // The vector of tile numbers (in Atari 7800 format)
-                                            let nb = match s.1[0].mode {
-                                                "160A" | "320A" | "320D" => 1,
-                                                _ => 2,
-                                            };
-                                            for t in &s.1 {
-                                                for i in 0..nb {
-                                                    tn.push(t.index + (i * bytes_per_tile) as u32);
-                                                }
-                                            }
-                                            // Let's look at the previous sequences
-                                            let mut found = false;
-                                            for c in &tiles_store {
-                                                if c.2 {
-                                                    //println!("Compare {:?} with {}", tn, c.0);
-                                                    // Look only at immediate sequences
-                                                    // Look for tn in c.1
-                                                    if c.1.windows(tn.len()).any(|w| tn == w) {
-                                                        found = true;
-                                                        break;
+                                        // OK. Now we have the tilesets. Let's try to see if we can
+                                        // break these into a new tilesets for better optimization
+                                        let unsplit_tilesets = if args.max_row_bytes.is_some() {
+                                            Some(tilesets.iter().cloned().collect::<Vec<_>>())
+                                        } else {
+                                            None
+                                        };
+                                        let mut tilesets_ex = Vec::<(u32, Vec<Tile>)>::new();
+                                        for s in tilesets {
+                                            if s.1.len() >= 5 {
+                                                // The tilesets must be at least 5 tiles long
+                                                let mut tn = Vec::new(); // The vector of tile numbers (in Atari 7800 format)
+                                                let nb = match s.1[0].mode {
+                                                    "160A" | "320A" | "320D" => 1,
+                                                    _ => 2,
+                                                };
+                                                for t in &s.1 {
+                                                    for i in 0..nb {
+                                                        tn.push(
+                                                            t.index + (i * bytes_per_tile) as u32,
+                                                        );
                                                     }
                                                 }
-                                            }
-                                            if found {
-                                                // Keep it. It's a part of sequence
-                                                tilesets_ex.push(s);
-                                            } else {
-                                                // OK. This is not a sequence. Let's try to cut it.
-                                                // Let's look at the sequence but the first tile
-                                                // And then at the sequence but the last tile
-                                                let mut tnx = VecDeque::from(tn.clone());
-                                                for _ in 0..nb {
-                                                    tnx.pop_front();
-                                                }
+                                                // Let's look at the previous sequences
+                                                let mut found = false;
                                                 for c in &tiles_store {
                                                     if c.2 {
-                                                        //println!("Compare {:?} with {}", tnx, c.0);
+                                                        //println!("Compare {:?} with {}", tn, c.0);
                                                         // Look only at immediate sequences
-                                                        // Look for tnx in c.1
-                                                        if c.1.windows(tnx.len()).any(|w| tnx == w)
-                                                        {
+                                                        // Look for tn in c.1
+                                                        if c.1.windows(tn.len()).any(|w| tn == w) {
                                                             found = true;
                                                             break;
                                                         }
                                                     }
                                                 }
                                                 if found {
-                                                    //println!("I was here");
-                                                    // Let's split it into two tilesets
-                                                    let tileset1 = vec![s.1[0].clone()];
-                                                    tilesets_ex.push((s.0, tileset1));
-                                                    let mut tileset2 = s.1.clone();
-                                                    tileset2.remove(0);
-                                                    tilesets_ex.push((s.0 + 1, tileset2));
+                                                    // Keep it. It's a part of sequence
+                                                    tilesets_ex.push(s);
                                                 } else {
-                                                    let mut tnx = tn.clone();
+                                                    // OK. This is not a sequence. Let's try to cut it.
+                                                    // Let's look at the sequence but the first tile
+                                                    // And then at the sequence but the last tile
+                                                    let mut tnx = VecDeque::from(tn.clone());
                                                     for _ in 0..nb {
-                                                        tnx.pop();
+                                                        tnx.pop_front();
                                                     }
                                                     for c in &tiles_store {
                                                         if c.2 {
@@ -1238,237 +2338,622 @@ fn main() -> Result<()> {
                                                     if found {
                                                         //println!("I was here");
                                                         // Let's split it into two tilesets
+                                                        let tileset1 = vec![s.1[0].clone()];
+                                                        tilesets_ex.push((s.0, tileset1));
                                                         let mut tileset2 = s.1.clone();
-                                                        let tileset1 =
-                                                            vec![tileset2.pop().unwrap()];
-                                                        tilesets_ex.push((
-                                                            s.0 + tileset2.len() as u32,
-                                                            tileset1,
-                                                        ));
-                                                        tilesets_ex.push((s.0, tileset2));
+                                                        tileset2.remove(0);
+                                                        tilesets_ex.push((s.0 + 1, tileset2));
                                                     } else {
-                                                        tilesets_ex.push(s);
+                                                        let mut tnx = tn.clone();
+                                                        for _ in 0..nb {
+                                                            tnx.pop();
+                                                        }
+                                                        for c in &tiles_store {
+                                                            if c.2 {
+                                                                //println!("Compare {:?} with {}", tnx, c.0);
+                                                                // Look only at immediate sequences
+                                                                // Look for tnx in c.1
+                                                                if c.1
+                                                                    .windows(tnx.len())
+                                                                    .any(|w| tnx == w)
+                                                                {
+                                                                    found = true;
+                                                                    break;
+                                                                }
+                                                            }
+                                                        }
+                                                        if found {
+                                                            //println!("I was here");
+                                                            // Let's split it into two tilesets
+                                                            let mut tileset2 = s.1.clone();
+                                                            let tileset1 =
+                                                                vec![tileset2.pop().unwrap()];
+                                                            tilesets_ex.push((
+                                                                s.0 + tileset2.len() as u32,
+                                                                tileset1,
+                                                            ));
+                                                            tilesets_ex.push((s.0, tileset2));
+                                                        } else {
+                                                            tilesets_ex.push(s);
+                                                        }
                                                     }
                                                 }
+                                            } else {
+                                                tilesets_ex.push(s);
                                             }
-                                        } else {
-                                            tilesets_ex.push(s);
                                         }
-                                    }
 
-                                    // Write this line of data
-                                    {
-                                        let mut c = 0;
-                                        let mut w = Vec::new();
-                                        let mut tile_names = Vec::new();
-                                        let mut imm = Vec::new();
-                                        for s in &tilesets_ex {
-                                            let mut immediate = args.immediate;
-                                            let mut tn = Vec::new(); // The vector of tile numbers (in Atari 7800 format)
-                                            let mut continuous_tileset = true;
-                                            let mut previous_index = None;
-                                            for t in &s.1 {
-                                                let tile_bytes = match t.mode {
-                                                    "160A" => tilewidth / 8,
-                                                    "160B" => tilewidth / 4,
-                                                    "320A" => tilewidth / 8,
-                                                    "320B" => tilewidth / 4,
-                                                    "320C" => tilewidth / 4,
-                                                    "320D" => tilewidth / 8,
-                                                    _ => unreachable!(),
-                                                };
-                                                let nb = match t.mode {
-                                                    "160A" | "320A" | "320D" => 1,
-                                                    _ => 2,
-                                                };
-                                                for i in 0..nb {
-                                                    tn.push(t.index + (i * bytes_per_tile) as u32);
+                                        // If the row headers list doesn't fit in the buffer, give up the sequences optimization
+                                        if let (Some(max), Some(unsplit)) =
+                                            (args.max_row_bytes, unsplit_tilesets)
+                                        {
+                                            if (zone_nsets + tilesets_ex.len()) * entry_size > max {
+                                                tilesets_ex = unsplit;
+                                            }
+                                        }
+
+                                        // Group tilesets by write mode to minimize write mode changes
+                                        if !args.force_left_to_right_order {
+                                            tilesets_ex = group_write_modes(tilesets_ex);
+                                        }
+
+                                        // Respect the DMA limit by converting indirect tilesets to immediate mode
+                                        // (splitting them when they are too long for immediate mode)
+                                        // (the rows of a zone sharing the DMA of its lines)
+                                        let mut forced = vec![false; tilesets_ex.len()];
+                                        let mut row_dma = 0;
+                                        if let Some(max_dma) = args.max_dma {
+                                            row_dma = loop {
+                                                let layouts = tilesets_ex
+                                                    .iter()
+                                                    .zip(&forced)
+                                                    .map(|(s, f)| {
+                                                        let (tn, continuous) =
+                                                            tileset_numbers(&s.1);
+                                                        tileset_layout(
+                                                            &tn,
+                                                            continuous,
+                                                            *f,
+                                                            forbid_immediate,
+                                                            args.immediate,
+                                                            &tiles_store,
+                                                            bytes_per_tile,
+                                                        )
+                                                    })
+                                                    .collect::<Vec<_>>();
+                                                let total: usize = layouts
+                                                    .iter()
+                                                    .map(|l| tileset_dma(l.0, l.1))
+                                                    .sum();
+                                                if zone_dma + total <= max_dma {
+                                                    break total;
                                                 }
-                                                if let Some(pi) = previous_index {
-                                                    if pi + tile_bytes != t.index {
-                                                        continuous_tileset = false;
+                                                // Find the indirect tileset whose conversion saves the most DMA
+                                                let mut best = None;
+                                                if !forbid_immediate {
+                                                    for (i, l) in layouts.iter().enumerate() {
+                                                        if !l.0 {
+                                                            let tile_width = l.1
+                                                                / tilesets_ex[i].1.len()
+                                                                * bytes_per_tile;
+                                                            let per_chunk = 31 / tile_width;
+                                                            let cost: usize = tilesets_ex[i]
+                                                                .1
+                                                                .chunks(per_chunk)
+                                                                .map(|c| {
+                                                                    tileset_dma(
+                                                                        true,
+                                                                        c.len() * tile_width,
+                                                                    )
+                                                                })
+                                                                .sum();
+                                                            let saving = tileset_dma(false, l.1)
+                                                                .saturating_sub(cost);
+                                                            let nb_tilesets = tilesets_ex.len()
+                                                                + tilesets_ex[i]
+                                                                    .1
+                                                                    .len()
+                                                                    .div_ceil(per_chunk)
+                                                                - 1;
+                                                            if saving > 0
+                                                                && args.max_row_bytes.is_none_or(
+                                                                    |m| {
+                                                                        (zone_nsets + nb_tilesets)
+                                                                            * entry_size
+                                                                            <= m
+                                                                    },
+                                                                )
+                                                                && best.is_none_or(|(_, _, s)| {
+                                                                    saving > s
+                                                                })
+                                                            {
+                                                                best = Some((i, per_chunk, saving));
+                                                            }
+                                                        }
                                                     }
                                                 }
-                                                previous_index = Some(t.index);
-                                                if t.fake {
-                                                    continuous_tileset = false; // Avoid direct use
-                                                                                // of immediate tile data, since it's fake
+                                                if let Some((i, per_chunk, _)) = best {
+                                                    let (startx, tileset) = tilesets_ex.remove(i);
+                                                    forced.remove(i);
+                                                    for (j, c) in
+                                                        tileset.chunks(per_chunk).enumerate().rev()
+                                                    {
+                                                        tilesets_ex.insert(
+                                                            i,
+                                                            (
+                                                                startx + (j * per_chunk) as u32,
+                                                                c.to_vec(),
+                                                            ),
+                                                        );
+                                                        forced.insert(i, true);
+                                                    }
+                                                } else {
+                                                    return Err(anyhow!(
+                                                    "Row {}: DMA usage ({} cycles, with the previous rows of its zone) exceeds the maximum of {} cycles",
+                                                    y,
+                                                    zone_dma + total,
+                                                    max_dma
+                                                ));
                                                 }
-                                            }
-                                            if continuous_tileset
-                                                && !forbid_immediate
-                                                && !args.immediate
-                                            {
-                                                w.push(tn.len() * bytes_per_tile);
-                                                imm.push(true);
-                                                tile_names.push(
-                                                    tile_names_ex
-                                                        .get(&s.1[0].index)
-                                                        .unwrap()
-                                                        .clone(),
+                                            };
+                                        }
+
+                                        // Split the tilesets that are too wide for the 5 bits header width field (31 bytes max)
+                                        let mut i = 0;
+                                        while i < tilesets_ex.len() {
+                                            let (tn, continuous) =
+                                                tileset_numbers(&tilesets_ex[i].1);
+                                            let (_, width) = tileset_layout(
+                                                &tn,
+                                                continuous,
+                                                forced[i],
+                                                forbid_immediate,
+                                                args.immediate,
+                                                &tiles_store,
+                                                bytes_per_tile,
+                                            );
+                                            if width > 31 {
+                                                let per_chunk =
+                                                    31 / (width / tilesets_ex[i].1.len());
+                                                let (startx, tileset) = tilesets_ex.remove(i);
+                                                let f = forced.remove(i);
+                                                eprintln!(
+                                                    "Row {}: Tileset at x = {} is too wide ({} bytes). Split into {} tilesets",
+                                                    y,
+                                                    startx,
+                                                    width,
+                                                    tileset.len().div_ceil(per_chunk)
                                                 );
+                                                for (j, c) in
+                                                    tileset.chunks(per_chunk).enumerate().rev()
+                                                {
+                                                    tilesets_ex.insert(
+                                                        i,
+                                                        (
+                                                            startx + (j * per_chunk) as u32,
+                                                            c.to_vec(),
+                                                        ),
+                                                    );
+                                                    forced.insert(i, f);
+                                                }
                                             } else {
-                                                // 1st optimization : look in the tiles_store if it's already there
-                                                let mut found = None;
-                                                for c in &tiles_store {
-                                                    // Look for tn in c.1
-                                                    if let Some(p) =
-                                                        c.1.windows(tn.len()).position(|w| tn == w)
+                                                i += 1;
+                                            }
+                                        }
+
+                                        if let Some(max) = args.max_row_bytes {
+                                            if (zone_nsets + tilesets_ex.len()) * entry_size > max {
+                                                return Err(anyhow!(
+                                                "Row {}: Headers list ({} bytes, with the previous rows of its zone) exceeds the maximum of {} bytes",
+                                                y,
+                                                (zone_nsets + tilesets_ex.len()) * entry_size,
+                                                max
+                                            ));
+                                            }
+                                        }
+
+                                        // Write this line of data
+                                        {
+                                            let mut c = 0;
+                                            let mut w = Vec::new();
+                                            let mut tile_names = Vec::new();
+                                            let mut imm = Vec::new();
+                                            for s in &tilesets_ex {
+                                                let forced_immediate = forced[c];
+                                                let mut immediate =
+                                                    args.immediate || forced_immediate;
+                                                // The vector of tile numbers (in Atari 7800 format)
+                                                let (tn, continuous_tileset) =
+                                                    tileset_numbers(&s.1);
+                                                if continuous_tileset
+                                                    && !forbid_immediate
+                                                    && !args.immediate
+                                                    && !forced_immediate
+                                                {
+                                                    w.push(tn.len() * bytes_per_tile);
+                                                    imm.push(true);
+                                                    tile_names.push(
+                                                        tile_names_ex
+                                                            .get(&s.1[0].index)
+                                                            .unwrap()
+                                                            .clone(),
+                                                    );
+                                                } else {
+                                                    // With automatic selection, compare the cost (in bytes) of both modes
+                                                    let mut selected = None;
+                                                    if args.auto_immediate
+                                                        && !forced_immediate
+                                                        && !forbid_immediate
                                                     {
-                                                        sequences_used.insert(c.0.clone());
-                                                        immediate = c.2;
-                                                        found = if p == 0 {
-                                                            Some(c.0.clone())
+                                                        let reusable = |immediate: bool| {
+                                                            tiles_store.iter().any(|c| {
+                                                                c.2 == immediate
+                                                                    && c.1
+                                                                        .windows(tn.len())
+                                                                        .any(|w| tn == w)
+                                                            })
+                                                        };
+                                                        let immediate_cost = if reusable(true) {
+                                                            0
+                                                        } else {
+                                                            tn.len()
+                                                                * bytes_per_tile
+                                                                * tileheight as usize
+                                                        };
+                                                        let indirect_cost = if reusable(false) {
+                                                            0
+                                                        } else {
+                                                            tn.len()
+                                                        };
+                                                        immediate = immediate_cost <= indirect_cost;
+                                                        eprintln!(
+                                                        "Row {}, x = {}: {} mode ({} bytes in immediate mode, {} bytes in indirect mode)",
+                                                        y,
+                                                        s.0,
+                                                        if immediate { "immediate" } else { "indirect" },
+                                                        immediate_cost,
+                                                        indirect_cost
+                                                    );
+                                                        let cost = if immediate {
+                                                            auto_report.0 += 1;
+                                                            immediate_cost
                                                         } else {
-                                                            let offset = if immediate {
-                                                                p * bytes_per_tile
+                                                            auto_report.1 += 1;
+                                                            indirect_cost
+                                                        };
+                                                        auto_report.2 += cost;
+                                                        selected = Some(immediate);
+                                                    }
+                                                    // 1st optimization : look in the tiles_store if it's already there
+                                                    let mut found = None;
+                                                    for c in &tiles_store {
+                                                        // RAM copies of indirect tilesets can't be shared
+                                                        if (forced_immediate && !c.2)
+                                                            || selected.is_some_and(|i| i != c.2)
+                                                            || (args.ram && !c.2)
+                                                        {
+                                                            continue;
+                                                        }
+                                                        // Look for tn in c.1
+                                                        if let Some(p) =
+                                                            c.1.windows(tn.len())
+                                                                .position(|w| tn == w)
+                                                        {
+                                                            sequences_used.insert(c.0.clone());
+                                                            row_refs.push(c.clone());
+                                                            immediate = c.2;
+                                                            found = if p == 0 {
+                                                                Some(c.0.clone())
                                                             } else {
-                                                                p
+                                                                let offset = if immediate {
+                                                                    p * bytes_per_tile
+                                                                } else {
+                                                                    p
+                                                                };
+                                                                Some(format!(
+                                                                    "{} + {}",
+                                                                    c.0.clone(),
+                                                                    offset
+                                                                ))
                                                             };
-                                                            Some(format!(
-                                                                "{} + {}",
-                                                                c.0.clone(),
-                                                                offset
-                                                            ))
-                                                        };
-                                                        break;
-                                                    } /*
-                                                      if c.1.starts_with(&tn) {
-                                                          found = Some(c.0.clone());
-                                                          immediate = c.2;
-                                                          break;
-                                                      }
-                                                      */
-                                                }
+                                                            break;
+                                                        } /*
+                                                          if c.1.starts_with(&tn) {
+                                                              found = Some(c.0.clone());
+                                                              immediate = c.2;
+                                                              break;
+                                                          }
+                                                          */
+                                                    }
 
-                                                // l is the number of bytes in the current tileset
-                                                let l = if immediate {
-                                                    tn.len() * bytes_per_tile
-                                                } else {
-                                                    tn.len()
-                                                };
-                                                w.push(l);
-                                                imm.push(immediate);
+                                                    // l is the number of bytes in the current tileset
+                                                    let l = if immediate {
+                                                        tn.len() * bytes_per_tile
+                                                    } else {
+                                                        tn.len()
+                                                    };
+                                                    w.push(l);
+                                                    imm.push(immediate);
 
-                                                if let Some(name) = found {
-                                                    tile_names.push(name);
-                                                } else {
-                                                    let name = format!("{}_{}_{}", varname, y, c);
-                                                    if let Some(b) = tiles_sheet.bank {
-                                                        output.push_str(&format!("bank{b} "));
-                                                    }
-                                                    if immediate {
-                                                        output.push_str(&format!(
+                                                    if let Some(name) = found {
+                                                        tile_names.push(name);
+                                                    } else if args.ram && !immediate {
+                                                        // This tileset is in the RAM copy of the row
+                                                        tile_names.push(if row_ram.is_empty() {
+                                                            format!("{}_{}_ram", prefix, y)
+                                                        } else {
+                                                            format!(
+                                                                "{}_{}_ram + {}",
+                                                                prefix,
+                                                                y,
+                                                                row_ram.len()
+                                                            )
+                                                        });
+                                                        row_ram.extend(tn.iter());
+                                                    } else {
+                                                        let name =
+                                                            format!("{}_{}_{}", prefix, y, c);
+                                                        if let Some(b) = bank {
+                                                            output.push_str(&format!("bank{b} "));
+                                                        }
+                                                        output.push_str(&attributes(
+                                                            &t.attributes.tilesets,
+                                                        ));
+                                                        if immediate {
+                                                            output.push_str(&format!(
                                                         "reversed scattered({},{}) char {}[{}] = {{\n\t",
                                                         tileheight,
                                                         l,
                                                         &name,
                                                         l * tileheight as usize
                                                     ));
-                                                        let mut i = 0;
-                                                        for y in 0..tileheight as usize {
-                                                            for t in &s.1 {
-                                                                let nb = match t.mode {
-                                                                    "160A" | "320A" | "320D" => 1,
-                                                                    _ => 2,
-                                                                };
-                                                                for b in 0..(nb * bytes_per_tile) {
-                                                                    output.push_str(&format!(
-                                                                        "0x{:02x}",
-                                                                        t.gfx[y
-                                                                            * (nb
-                                                                                * bytes_per_tile)
-                                                                            + b]
-                                                                    ));
-                                                                    if i != l * tileheight as usize
-                                                                        - 1
+                                                            let mut i = 0;
+                                                            for y in 0..tileheight as usize {
+                                                                for t in &s.1 {
+                                                                    let nb = match t.mode {
+                                                                        "160A" | "320A"
+                                                                        | "320D" => 1,
+                                                                        _ => 2,
+                                                                    };
+                                                                    for b in
+                                                                        0..(nb * bytes_per_tile)
                                                                     {
-                                                                        if (i + 1) % 16 != 0 {
-                                                                            output.push_str(", ");
-                                                                        } else {
-                                                                            output
-                                                                                .push_str(",\n\t");
+                                                                        output.push_str(&format!(
+                                                                            "0x{:02x}",
+                                                                            t.gfx[y * (nb
+                                                                                * bytes_per_tile)
+                                                                                + b]
+                                                                        ));
+                                                                        if i != l * tileheight
+                                                                            as usize
+                                                                            - 1
+                                                                        {
+                                                                            if (i + 1) % 16 != 0 {
+                                                                                output
+                                                                                    .push_str(", ");
+                                                                            } else {
+                                                                                output.push_str(
+                                                                                    ",\n\t",
+                                                                                );
+                                                                            }
                                                                         }
+                                                                        i += 1;
                                                                     }
-                                                                    i += 1;
                                                                 }
                                                             }
+                                                            output.push_str("};\n");
+                                                        } else {
+                                                            output.push_str(&format!(
+                                                                "const char {}[{}] = {{",
+                                                                &name,
+                                                                tn.len()
+                                                            ));
+                                                            for t in &tn[..tn.len() - 1] {
+                                                                output
+                                                                    .push_str(&format!("{}, ", t));
+                                                            }
+                                                            output.push_str(&format!(
+                                                                "{}}};\n",
+                                                                tn[tn.len() - 1]
+                                                            ));
                                                         }
-                                                        output.push_str("};\n");
-                                                    } else {
-                                                        output.push_str(&format!(
-                                                            "const char {}[{}] = {{",
-                                                            &name,
-                                                            tn.len()
+                                                        tiles_store.push((
+                                                            name.clone(),
+                                                            tn,
+                                                            immediate,
                                                         ));
-                                                        for i in 0..tn.len() - 1 {
-                                                            output
-                                                                .push_str(&format!("{}, ", tn[i]));
-                                                        }
-                                                        output.push_str(&format!(
-                                                            "{}}};\n",
-                                                            tn[tn.len() - 1]
+                                                        tile_names.push(name);
+                                                    }
+                                                }
+                                                c += 1;
+                                            }
+                                            if !row_ram.is_empty() {
+                                                // RAM copy of the row indirect tilesets, and its initialization data
+                                                output.push_str(&format!(
+                                                    "char {}_{}_ram[{}];\n",
+                                                    prefix,
+                                                    y,
+                                                    row_ram.len()
+                                                ));
+                                                if let Some(b) = bank {
+                                                    output.push_str(&format!("bank{b} "));
+                                                }
+                                                output
+                                                    .push_str(&attributes(&t.attributes.tilesets));
+                                                output.push_str(&format!(
+                                                    "const char {}_{}_ram_init[{}] = {{{}}};\n",
+                                                    prefix,
+                                                    y,
+                                                    row_ram.len(),
+                                                    row_ram
+                                                        .iter()
+                                                        .map(|t| t.to_string())
+                                                        .collect::<Vec<_>>()
+                                                        .join(", ")
+                                                ));
+                                            }
+                                            ram_sizes.push(row_ram.len());
+                                            c = 0;
+                                            let mut tilemap_str = String::new();
+                                            for s in &tilesets_ex {
+                                                let ttype = s.1.first().unwrap();
+                                                let write_mode = match ttype.mode {
+                                                    "160A" | "320A" | "320D" => 0x40,
+                                                    _ => 0xc0,
+                                                } | if imm[c] { 0 } else { 0x20 };
+                                                if w[c] > 31 {
+                                                    return Err(anyhow!(
+                                                        "Row {}: Tileset {} is too wide ({} bytes)",
+                                                        y,
+                                                        tile_names[c],
+                                                        w[c]
+                                                    ));
+                                                }
+                                                let dma = tileset_dma(imm[c], w[c]);
+                                                let tn = &tile_names[c];
+                                                // In a zone made of several rows, the upper rows graphics are shifted
+                                                // (the bottom row of the zone is drawn with offsets 0 to tileheight - 1)
+                                                let shift = (zone_rows - 1 - y % zone_rows) as u32
+                                                    * tileheight;
+                                                let high = if shift == 0 {
+                                                    format!("{} >> 8", tn)
+                                                } else {
+                                                    format!("({} >> 8) - {}", tn, shift)
+                                                };
+                                                if args.direct {
+                                                    // X position, in 160 pixels resolution
+                                                    let xpos = (s.0 * tilewidth / 2) as i32
+                                                        + args.offset as i32;
+                                                    let tileset_width =
+                                                        (s.1.len() as u32 * tilewidth / 2) as i32;
+                                                    if xpos > 255 || xpos <= -tileset_width {
+                                                        return Err(anyhow!(
+                                                            "Row {}: Tileset {} at x = {} has an X position ({}) that can't be represented in a header",
+                                                            y,
+                                                            tn,
+                                                            s.0,
+                                                            xpos
                                                         ));
                                                     }
-                                                    tiles_store.push((name.clone(), tn, immediate));
-                                                    tile_names.push(name);
+                                                    tilemap_str.push_str(&format!(
+                                                        "{}, 0x{:02x}, {}, ({} << 5) | ((-{}) & 0x1f), {}, ",
+                                                        tn,
+                                                        write_mode,
+                                                        high,
+                                                        ttype.palette_number,
+                                                        w[c],
+                                                        xpos & 0xff
+                                                    ));
+                                                } else {
+                                                    tilemap_str.push_str(&format!("{}, {}, {}, 0x{:02x}, {}, ({} << 5) | ((-{}) & 0x1f), {dma}, ", 
+                                                    s.0 + s.1.len() as u32 - 1, s.0, tn, write_mode, high, ttype.palette_number, w[c]));
                                                 }
+                                                c += 1;
                                             }
-                                            c += 1;
+                                            cache[row] = Some(CachedRow {
+                                                hash: row_hash,
+                                                output: output[output_start..].to_string(),
+                                                store: tiles_store[store_start..].to_vec(),
+                                                refs: row_refs,
+                                                tilemap: tilemap_str.clone(),
+                                                nsets: tilesets_ex.len(),
+                                                ram: row_ram.len(),
+                                                dma: row_dma,
+                                            });
+                                            zone_str.push_str(&tilemap_str);
+                                            zone_nsets += tilesets_ex.len();
+                                            zone_dma += row_dma;
                                         }
-                                        c = 0;
-                                        let mut tilemap_str = String::new();
-                                        for s in &tilesets_ex {
-                                            let ttype = s.1.first().unwrap();
-                                            let write_mode = match ttype.mode {
-                                                "160A" | "320A" | "320D" => 0x40,
-                                                _ => 0xc0,
-                                            } | if imm[c] { 0 } else { 0x20 };
-                                            let dma = if imm[c] {
-                                                (10 + 3 * w[c]) / 2
-                                            } else {
-                                                (10 + 3 + 9 * w[c]) / 2
-                                            };
-                                            let tn = &tile_names[c];
-                                            tilemap_str.push_str(&format!("{}, {}, {}, 0x{:02x}, {} >> 8, ({} << 5) | ((-{}) & 0x1f), {dma}, ", 
-                                                s.0 + s.1.len() as u32 - 1, s.0, tn, write_mode, tn, ttype.palette_number, w[c]));
-                                            c += 1;
+                                    }
+                                    if args.map_report.is_some() {
+                                        // Tilesets arrays defined or reused by this row
+                                        let mut arrays = Vec::<String>::new();
+                                        if let Some(c) = &cache[row] {
+                                            for r in c.store.iter().chain(&c.refs) {
+                                                if !arrays.contains(&r.0) {
+                                                    arrays.push(r.0.clone());
+                                                }
+                                            }
+                                            if c.ram != 0 {
+                                                arrays.push(format!("{prefix}_{y}_ram"));
+                                            }
                                         }
+                                        report_rows.push(serde_json::json!({
+                                            "screen": screen,
+                                            "row": y,
+                                            "zone": y / zone_rows,
+                                            "arrays": arrays,
+                                        }));
+                                    }
+
+                                    if y % zone_rows == zone_rows - 1 || y == height - 1 {
+                                        let z = y / zone_rows;
+                                        row_nsets.push(zone_nsets);
                                         let mut found = None;
                                         for c in &tilesmap_store {
-                                            if c.1 == tilemap_str {
+                                            if c.1 == zone_str {
                                                 found = Some(c.0.clone());
                                             }
                                         }
                                         if let Some(name) = found {
                                             tilesmap.push(name);
                                         } else {
-                                            let tilemap_name = format!("{}_{}_data", varname, y);
-                                            if let Some(b) = tiles_sheet.bank {
+                                            let tilemap_name = format!("{}_{}_data", prefix, z);
+                                            if let Some(b) = bank {
                                                 output.push_str(&format!("bank{} ", b));
                                             }
+                                            output.push_str(&attributes(&t.attributes.rows));
                                             output.push_str(&format!(
-                                                "const char {}[] = {{{}96, 0xff}};\n",
-                                                &tilemap_name, tilemap_str
+                                                "const char {}[] = {{{}{}}};\n",
+                                                &tilemap_name,
+                                                zone_str,
+                                                if args.direct { "0, 0" } else { "96, 0xff" }
                                             ));
                                             tilesmap_store
-                                                .push((tilemap_name.clone(), tilemap_str.clone()));
+                                                .push((tilemap_name.clone(), zone_str.clone()));
                                             tilesmap.push(tilemap_name);
                                         }
+                                        // Headers list of the zone
+                                        for r in report_rows
+                                            .iter_mut()
+                                            .filter(|r| r["screen"] == screen && r["zone"] == z)
+                                        {
+                                            r["data"] = tilesmap.last().unwrap().as_str().into();
+                                        }
+                                        zone_str = String::new();
+                                        zone_nsets = 0;
+                                        zone_dma = 0;
                                     }
                                 }
 
+                                if let Some(c) = &args.cache {
+                                    fs::write(c, serde_yaml::to_string(&cache)?)?;
+                                }
+
+                                if let Some(r) = &args.map_report {
+                                    let report = serde_json::json!({
+                                        "tiles": report_tiles,
+                                        "rows": report_rows,
+                                    });
+                                    fs::write(r, serde_json::to_string_pretty(&report)?)?;
+                                }
+
+                                if args.auto_immediate {
+                                    eprintln!(
+                                        "Automatic mode selection: {} immediate tilesets, {} indirect tilesets, {} bytes generated",
+                                        auto_report.0, auto_report.1, auto_report.2
+                                    );
+                                }
+
                                 // Output sequences
-                                if let Some(sequences) = &tiles_sheet.sequences {
-                                    for (i, sequence) in sequences.iter().enumerate() {
-                                        let name = if let Some(n) = &sequence.name {
-                                            format!("{}_{}", varname, n.clone())
-                                        } else {
-                                            format!("{}_sequence_{}", varname, i)
-                                        };
-                                        if sequences_used.contains(&name) {
-                                            print!("{}", sequences_code.get(&name).unwrap());
-                                        }
+                                for (i, (sequence, _)) in sequences.iter().enumerate() {
+                                    let name = if let Some(n) = &sequence.name {
+                                        format!("{}_{}", varname, n.clone())
+                                    } else {
+                                        format!("{}_sequence_{}", varname, i)
+                                    };
+                                    if sequences_used.contains(&name) {
+                                        print!("{}", sequences_code.get(&name).unwrap());
                                     }
                                 }
                                 // Output tilemap
@@ -1476,30 +2961,278 @@ fn main() -> Result<()> {
                                 print!("{output}");
 
                                 println!();
-                                if let Some(b) = tiles_sheet.bank {
-                                    print!("bank{b} ");
+                                let zones = tilesmap.len() / nscreens;
+                                for (s, prefix) in prefixes.iter().enumerate() {
+                                    let tilesmap = &tilesmap[s * zones..(s + 1) * zones];
+                                    let row_nsets = &row_nsets[s * zones..(s + 1) * zones];
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    print!("const char {prefix}_data_ptrs_high[{}] = {{", zones);
+                                    for t in &tilesmap[..zones - 1] {
+                                        print!("{} >> 8, ", t);
+                                    }
+                                    println!("{} >> 8}};\n", &tilesmap[zones - 1]);
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    print!("const char {prefix}_data_ptrs_low[{}] = {{", zones);
+                                    for t in &tilesmap[..zones - 1] {
+                                        print!("{} & 0xff, ", t);
+                                    }
+                                    println!("{} & 0xff}};\n", &tilesmap[zones - 1]);
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    println!("const char *{prefix}_data_ptrs[2] = {{{prefix}_data_ptrs_high, {prefix}_data_ptrs_low}};\n");
+
+                                    // Output rows sizes (7 bytes per tileset, or 5 in direct mode, terminator excluded) and number of tilesets
+                                    // Tables whose values don't fit in a byte are output as unsigned short arrays
+                                    let ctype = |max: Option<usize>| {
+                                        if max.unwrap_or(0) > 255 {
+                                            "unsigned short"
+                                        } else {
+                                            "char"
+                                        }
+                                    };
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    println!(
+                                        "const {} {prefix}_row_sizes[{}] = {{{}}};\n",
+                                        ctype(row_nsets.iter().max().map(|n| n * entry_size)),
+                                        zones,
+                                        row_nsets
+                                            .iter()
+                                            .map(|n| (n * entry_size).to_string())
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    );
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    println!(
+                                        "const {} {prefix}_row_nsets[{}] = {{{}}};\n",
+                                        ctype(row_nsets.iter().max().copied()),
+                                        zones,
+                                        row_nsets
+                                            .iter()
+                                            .map(|n| n.to_string())
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    );
+                                }
+
+                                // Output the RAM copies tables (by row) and initialization function
+                                if args.ram {
+                                    for (s, prefix) in prefixes.iter().enumerate() {
+                                        let ram_sizes = &ram_sizes[s * height..(s + 1) * height];
+                                        if let Some(r) = ram_sizes.iter().position(|s| *s > 255) {
+                                            return Err(anyhow!(
+                                                "Row {}: RAM copy too large ({} bytes)",
+                                                r,
+                                                ram_sizes[r]
+                                            ));
+                                        }
+                                        let names = |suffix: &str| {
+                                            ram_sizes
+                                                .iter()
+                                                .enumerate()
+                                                .map(|(y, s)| {
+                                                    if *s == 0 {
+                                                        "0".to_string()
+                                                    } else {
+                                                        format!("{prefix}_{y}_{suffix}")
+                                                    }
+                                                })
+                                                .collect::<Vec<_>>()
+                                                .join(", ")
+                                        };
+                                        for (table, values) in [
+                                            (
+                                                "char ",
+                                                "ram_sizes",
+                                                ram_sizes
+                                                    .iter()
+                                                    .map(|s| s.to_string())
+                                                    .collect::<Vec<_>>()
+                                                    .join(", "),
+                                            ),
+                                            ("char *", "rams", names("ram")),
+                                            ("char *", "ram_inits", names("ram_init")),
+                                        ]
+                                        .iter()
+                                        .map(|(ctype, name, values)| {
+                                            (
+                                                format!("const {ctype}{prefix}_{name}[{height}]"),
+                                                values.clone(),
+                                            )
+                                        }) {
+                                            if let Some(b) = bank {
+                                                print!("bank{b} ");
+                                            }
+                                            print!("{}", attributes(&t.attributes.tables));
+                                            println!("{table} = {{{values}}};\n");
+                                        }
+                                        println!("void {prefix}_ram_init()\n{{\n\tchar i, y;\n\tconst char *src;\n\tchar *dst;");
+                                        println!("\tfor (y = 0; y != {height}; y++) {{\n\t\tsrc = {prefix}_ram_inits[y];\n\t\tdst = {prefix}_rams[y];");
+                                        println!("\t\tfor (i = 0; i != {prefix}_ram_sizes[y]; i++) {{\n\t\t\tdst[i] = src[i];\n\t\t}}\n\t}}\n}}\n");
+                                    }
                                 }
-                                print!("const char {varname}_data_ptrs_high[{}] = {{", height);
-                                for y in 0..height - 1 {
-                                    print!("{} >> 8, ", &tilesmap[y]);
+
+                                // Output the screens table (data_ptrs high and low tables of each screen)
+                                if args.screen_width.is_some() {
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    println!(
+                                        "const char *{varname}_screens[{}] = {{{}}};\n",
+                                        nscreens * 2,
+                                        prefixes
+                                            .iter()
+                                            .map(|p| format!(
+                                                "{p}_data_ptrs_high, {p}_data_ptrs_low"
+                                            ))
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    );
                                 }
-                                println!("{} >> 8}};\n", &tilesmap[height - 1]);
-                                if let Some(b) = tiles_sheet.bank {
-                                    print!("bank{b} ");
+
+                                // Output palette cycling tables
+                                let mut cycles = Vec::<(u8, u8, &Cycle)>::new();
+                                for (s, _, _, _) in &sheets {
+                                    for sprite in &t.sprite_sheets[*s].sprites {
+                                        if let Some(cycle) = &sprite.cycle {
+                                            let palette = cycle.palette.unwrap_or_else(|| {
+                                                refs.get(&sprite.name)
+                                                    .and_then(|ix| tiles.get(ix))
+                                                    .map_or(0, |tile| tile.palette_number)
+                                            });
+                                            if palette > 7 {
+                                                return Err(anyhow!(
+                                                    "Tile {}: Bad cycle palette number {}",
+                                                    sprite.name,
+                                                    palette
+                                                ));
+                                            }
+                                            if cycle.color < 1 || cycle.color > 3 {
+                                                return Err(anyhow!(
+                                                    "Tile {}: Bad cycle color index {} (should be 1 to 3)",
+                                                    sprite.name,
+                                                    cycle.color
+                                                ));
+                                            }
+                                            if cycle.colors.is_empty() || cycle.period == 0 {
+                                                return Err(anyhow!(
+                                                    "Tile {}: Empty color cycle",
+                                                    sprite.name
+                                                ));
+                                            }
+                                            if let Some(c) = cycles
+                                                .iter()
+                                                .find(|c| c.0 == palette && c.1 == cycle.color)
+                                            {
+                                                if c.2.period != cycle.period
+                                                    || c.2.colors != cycle.colors
+                                                {
+                                                    return Err(anyhow!(
+                                                        "Tile {}: Conflicting color cycle for P{}C{}",
+                                                        sprite.name,
+                                                        palette,
+                                                        cycle.color
+                                                    ));
+                                                }
+                                            } else {
+                                                cycles.push((palette, cycle.color, cycle));
+                                            }
+                                        }
+                                    }
                                 }
-                                print!("const char {varname}_data_ptrs_low[{}] = {{", height);
-                                for y in 0..height - 1 {
-                                    print!("{} & 0xff, ", &tilesmap[y]);
+                                if !cycles.is_empty() {
+                                    let n = cycles.len();
+                                    println!("#define {}_CYCLES {}", varname.to_uppercase(), n);
+                                    for (i, c) in cycles.iter().enumerate() {
+                                        if let Some(b) = bank {
+                                            print!("bank{b} ");
+                                        }
+                                        print!("{}", attributes(&t.attributes.tables));
+                                        println!(
+                                            "const char {varname}_cycle_{i}[{}] = {{{}}};",
+                                            c.2.colors.len(),
+                                            c.2.colors
+                                                .iter()
+                                                .map(|c| format!("0x{:02x}", c))
+                                                .collect::<Vec<_>>()
+                                                .join(", ")
+                                        );
+                                    }
+                                    // Palette registers are P0C1 = 0x21, P0C2 = 0x22, P0C3 = 0x23, P1C1 = 0x25...
+                                    let tables = [
+                                        (
+                                            "registers",
+                                            cycles
+                                                .iter()
+                                                .map(|c| format!("0x{:02x}", 0x20 + c.0 * 4 + c.1))
+                                                .collect::<Vec<_>>(),
+                                        ),
+                                        (
+                                            "periods",
+                                            cycles.iter().map(|c| c.2.period.to_string()).collect(),
+                                        ),
+                                        (
+                                            "lengths",
+                                            cycles
+                                                .iter()
+                                                .map(|c| c.2.colors.len().to_string())
+                                                .collect(),
+                                        ),
+                                    ];
+                                    for (name, values) in &tables {
+                                        if let Some(b) = bank {
+                                            print!("bank{b} ");
+                                        }
+                                        print!("{}", attributes(&t.attributes.tables));
+                                        println!(
+                                            "const char {varname}_cycle_{name}[{n}] = {{{}}};",
+                                            values.join(", ")
+                                        );
+                                    }
+                                    if let Some(b) = bank {
+                                        print!("bank{b} ");
+                                    }
+                                    print!("{}", attributes(&t.attributes.tables));
+                                    println!(
+                                        "const char *{varname}_cycle_colors[{n}] = {{{}}};\n",
+                                        (0..n)
+                                            .map(|i| format!("{varname}_cycle_{i}"))
+                                            .collect::<Vec<_>>()
+                                            .join(", ")
+                                    );
                                 }
-                                println!("{} & 0xff}};\n", &tilesmap[height - 1]);
-                                if let Some(b) = tiles_sheet.bank {
-                                    print!("bank{b} ");
+                                if !args.direct {
+                                    println!("/*\n#define TILING_HEIGHT {}", zones);
+                                    println!("#define TILING_WIDTH {}", screen_width);
+                                    println!("#include \"sparse_tiling.h\"\n*/\n");
                                 }
-                                println!("const char *{varname}_data_ptrs[2] = {{{varname}_data_ptrs_high, {varname}_data_ptrs_low}};\n");
-                                println!("/*\n#define TILING_HEIGHT {}", height);
-                                println!("#define TILING_WIDTH {}", width);
-                                println!("#include \"sparse_tiling.h\"\n*/\n");
                             } else {
+                                let array = if let Some(f) = &args.fill {
+                                    // No tile names without the YAML file. Use the Tiled tile number
+                                    let ix = f.parse::<u32>().map_err(|_| {
+                                        anyhow!("Fill tile {} must be a tile number", f)
+                                    })?;
+                                    array
+                                        .iter()
+                                        .map(|c| if *c == 0 { ix } else { *c })
+                                        .collect::<Vec<_>>()
+                                } else {
+                                    array
+                                };
                                 print!(
                                     "const char {varname}[{}] = {{",
                                     if args.boundaries {
@@ -1538,10 +3271,15 @@ fn main() -> Result<()> {
                             return Ok(());
                         }
                         return Err(anyhow!("Bad data format. Unexpected table size."));
+                    } else {
+                        return Err(anyhow!("Bad data format. Only CSV layers are supported."));
                     }
                 }
             }
         }
     }
+    if let Some(l) = args.layer {
+        return Err(anyhow!("Layer {} not found", l));
+    }
     Err(anyhow!("Unexpected data provided."))
 }